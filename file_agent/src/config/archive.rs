@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Limits on extracting archives, so a hostile one cannot fill the disk
+///
+/// An archive is refused if it holds more than `max_entries` entries or
+/// would unpack to more than `max_total_size` bytes. Entries over 1 MiB
+/// may be at most `max_ratio` times larger than their compressed size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub max_total_size: u64,
+    pub max_entries: usize,
+    pub max_ratio: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            max_total_size: 8 * 1024 * 1024 * 1024,
+            max_entries: 100_000,
+            max_ratio: 200,
+        }
+    }
+}
+
+impl ArchiveConfig {
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        for (field, value) in [
+            ("max_total_size", self.max_total_size),
+            ("max_entries", self.max_entries as u64),
+            ("max_ratio", self.max_ratio),
+        ] {
+            if value == 0 {
+                issues.push(format!("archive.{}: must be greater than zero", field));
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Placeholders a custom endpoint template may reference
+pub const TEMPLATE_PLACEHOLDERS: &[&str] = &["{agent_type}", "{tenant_id}", "{uuid}"];
+
+/// WebSocket path scheme used to reach the relay
+///
+/// ```toml
+/// [endpoint]
+/// kind = "custom"
+/// template = "/relay/{agent_type}/{tenant_id}/{uuid}"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Endpoint {
+    /// `/ws/rev/{agent_type}/{tenant_id}/{uuid}`, served by current relays
+    #[default]
+    ReverseRelay,
+    /// `/websocket/{tenant_id}/{uuid}`, served by older relay deployments
+    Legacy,
+    /// Any path built from [`TEMPLATE_PLACEHOLDERS`]
+    Custom { template: String },
+}
+
+impl Endpoint {
+    /// Build the request path for an agent
+    pub fn path(&self, agent_type: &str, tenant_id: &str, uuid: &str) -> String {
+        match self {
+            Endpoint::ReverseRelay => format!("/ws/rev/{}/{}/{}", agent_type, tenant_id, uuid),
+            Endpoint::Legacy => format!("/websocket/{}/{}", tenant_id, uuid),
+            Endpoint::Custom { template } => template
+                .replace("{agent_type}", agent_type)
+                .replace("{tenant_id}", tenant_id)
+                .replace("{uuid}", uuid),
+        }
+    }
+
+    /// Problems with a custom template, if any
+    pub fn validate(&self) -> Option<String> {
+        let Endpoint::Custom { template } = self else {
+            return None;
+        };
+
+        if !template.starts_with('/') {
+            return Some(format!("template '{}' must start with '/'", template));
+        }
+        if !template.contains("{uuid}") {
+            return Some(format!("template '{}' must contain {{uuid}}", template));
+        }
+
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let tail = &rest[start..];
+            let end = tail.find('}').map(|i| i + 1).unwrap_or(tail.len());
+            let placeholder = &tail[..end];
+            if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+                return Some(format!(
+                    "unknown placeholder '{}' (expected one of {})",
+                    placeholder,
+                    TEMPLATE_PLACEHOLDERS.join(", ")
+                ));
+            }
+            rest = &tail[end..];
+        }
+        None
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    /// Accepts `reverse_relay`, `legacy`, or a custom template starting with `/`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "reverse_relay" | "reverse" | "rev" => Ok(Endpoint::ReverseRelay),
+            "legacy" => Ok(Endpoint::Legacy),
+            template if template.starts_with('/') => Ok(Endpoint::Custom {
+                template: template.to_string(),
+            }),
+            other => Err(format!(
+                "'{}' is not an endpoint (expected reverse_relay, legacy or a /path template)",
+                other
+            )),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+/// Optional protocol features the agent offers in its `hello_result`
+///
+/// A disabled feature is left out of the handshake and its requests are
+/// refused with `unsupported`, whatever the client asks for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeaturesConfig {
+    /// `cancel` requests
+    pub cancel: bool,
+    /// `job_progress` updates for requests that ask for them
+    pub progress: bool,
+    /// Chunked `download_start` and `upload_begin` transfers
+    pub transfers: bool,
+    /// File contents in binary payload frames instead of base64
+    pub binary_payload: bool,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        Self {
+            cancel: true,
+            progress: true,
+            transfers: true,
+            binary_payload: true,
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Client-initiated WebSocket pings used to detect half-open connections
+///
+/// A ping is sent every `interval_secs`; if its pong has not arrived within
+/// `timeout_secs` the connection is treated as dead and re-established.
+/// Setting `interval_secs` to zero disables the heartbeat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 15,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn enabled(&self) -> bool {
+        self.interval_secs > 0
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        if self.enabled() && self.timeout_secs == 0 {
+            issues.push("heartbeat.timeout_secs: must be greater than zero".to_string());
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use super::{Config, ProxyKind, TlsConfig};
+use crate::error::{Error, Result};
+
+/// Number of positional tokens in the legacy `config.txt` format
+pub const LEGACY_TOKEN_COUNT: usize = 11;
+
+/// Parse the legacy single-line, space separated config format:
+///
+/// `server port tenant proxy_host proxy_port proxy_user proxy_pass proxy|socks5|none uuid proxy_auth isNoAuth`
+///
+/// Empty slots are written as consecutive spaces. When the line does not
+/// split into exactly eleven slots on single spaces (e.g. it was edited by
+/// hand and picked up stray whitespace), it is re-split on whitespace runs.
+pub fn parse(content: &str) -> Result<Config> {
+    let line = content.trim();
+    let mut tokens: Vec<&str> = line.split(' ').map(str::trim).collect();
+    if tokens.len() != LEGACY_TOKEN_COUNT {
+        let collapsed: Vec<&str> = line.split_whitespace().collect();
+        if collapsed.len() == LEGACY_TOKEN_COUNT {
+            tokens = collapsed;
+        }
+    }
+
+    if tokens.len() < LEGACY_TOKEN_COUNT {
+        return Err(Error::Config(format!(
+            "legacy config doesn't have enough tokens (expected {}, got {})",
+            LEGACY_TOKEN_COUNT,
+            tokens.len()
+        )));
+    }
+
+    Ok(Config {
+        server_address: tokens[0].to_string(),
+        server_port: tokens[1].to_string(),
+        tenant_id: tokens[2].to_string(),
+        proxy_url: slot(tokens[3]),
+        proxy_port: slot(tokens[4]),
+        proxy_username: slot(tokens[5]),
+        proxy_password: slot(tokens[6]),
+        use_proxy: tokens[7] == "proxy" || tokens[7] == "socks5",
+        proxy_type: if tokens[7] == "socks5" {
+            ProxyKind::Socks5
+        } else {
+            ProxyKind::Http
+        },
+        uuid: tokens[8].to_string(),
+        proxy_auth: slot(tokens[9]),
+        no_auth: tokens[10] == "isNoAuth",
+        // Only a wss:// or https:// server prefix turns TLS on in this format
+        use_ssl: false,
+        // config.txt has no slot for a CA or pin; those, or opting out of
+        // verification, come from the --tls-* flags and C1RMM_TLS_* variables
+        tls: TlsConfig::default(),
+        ..Config::default()
+    })
+}
+
+/// Empty slots and the `none` placeholder both mean "not set"
+fn slot(token: &str) -> Option<String> {
+    if token.is_empty() || token.eq_ignore_ascii_case("none") {
+        None
+    } else {
+        Some(token.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_installer_line() {
+        let config =
+            parse("relay.example.com 8443 acme proxy.lan 3128 none none proxy 4f1c dXNlcjpwYXNz isNoAuth\n")
+                .unwrap();
+        assert_eq!(config.server_address, "relay.example.com");
+        assert_eq!(config.server_port, "8443");
+        assert_eq!(config.tenant_id, "acme");
+        assert_eq!(config.uuid, "4f1c");
+        assert_eq!(config.proxy_url.as_deref(), Some("proxy.lan"));
+        assert_eq!(config.proxy_port.as_deref(), Some("3128"));
+        assert_eq!(config.proxy_username, None);
+        assert_eq!(config.proxy_password, None);
+        assert!(config.use_proxy);
+        assert_eq!(config.proxy_type, ProxyKind::Http);
+        assert_eq!(config.proxy_auth.as_deref(), Some("dXNlcjpwYXNz"));
+        assert!(config.no_auth);
+        assert!(!config.use_ssl);
+    }
+
+    #[test]
+    fn empty_slots_are_consecutive_spaces() {
+        // No proxy: host, port, user and password left empty
+        let config = parse("relay.example.com 443 acme     none 4f1c  false").unwrap();
+        assert_eq!(config.proxy_url, None);
+        assert_eq!(config.proxy_port, None);
+        assert!(!config.use_proxy);
+        assert_eq!(config.uuid, "4f1c");
+        assert_eq!(config.proxy_auth, None);
+        assert!(!config.no_auth);
+    }
+
+    #[test]
+    fn falls_back_to_whitespace_runs() {
+        let config =
+            parse("relay.example.com  443\tacme proxy.lan 1080 bob secret  socks5 4f1c none false")
+                .unwrap();
+        assert_eq!(config.tenant_id, "acme");
+        assert_eq!(config.proxy_type, ProxyKind::Socks5);
+        assert_eq!(config.proxy_username.as_deref(), Some("bob"));
+        assert_eq!(config.proxy_password.as_deref(), Some("secret"));
+        assert_eq!(config.uuid, "4f1c");
+    }
+
+    #[test]
+    fn rejects_short_line() {
+        let error = parse("relay.example.com 443 acme").unwrap_err();
+        assert!(matches!(error, Error::Config(_)), "{}", error);
+    }
+}
@@ -1,75 +1,393 @@
+mod archive;
+mod endpoint;
+mod features;
+mod heartbeat;
+mod legacy;
+mod operations;
+mod overrides;
+mod proxy;
+mod reconnect;
+mod sandbox;
+mod tls;
+mod transfer;
+
+pub use archive::ArchiveConfig;
+pub use endpoint::{Endpoint, TEMPLATE_PLACEHOLDERS};
+pub use features::FeaturesConfig;
+pub use heartbeat::HeartbeatConfig;
+pub use operations::OperationsConfig;
+pub use overrides::{CliArgs, ConfigOverrides, DEFAULT_CONFIG_PATH, ENV_PREFIX};
+pub use proxy::{EnvProxy, ProxyKind, ProxySettings};
+pub use reconnect::ReconnectConfig;
+pub use sandbox::SandboxConfig;
+pub use tls::{parse_fingerprint, TlsConfig};
+pub use transfer::TransferConfig;
+
 use crate::error::{Error, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
+use std::path::Path;
+
+/// On-disk formats understood by [`Config::load_from_file`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    /// Single-line, space separated `config.txt` written by the installer
+    Legacy,
+}
+
+impl ConfigFormat {
+    /// Pick a format from the file extension, falling back to sniffing the content
+    pub fn detect(path: &Path, content: &str) -> Self {
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("toml") => return ConfigFormat::Toml,
+            Some("json") => return ConfigFormat::Json,
+            _ => {}
+        }
+
+        let trimmed = content.trim_start();
+        if trimmed.starts_with('{') {
+            ConfigFormat::Json
+        } else if toml::from_str::<toml::Table>(content).is_ok() {
+            ConfigFormat::Toml
+        } else {
+            ConfigFormat::Legacy
+        }
+    }
+}
 
 /// Application configuration
-#[derive(Debug, Clone)]
+///
+/// Keyed files (TOML or JSON) use the field names below; every field is
+/// optional and falls back to [`Config::default`]. The server address may
+/// carry a `ws://`, `wss://`, `http://` or `https://` prefix, which then
+/// decides `use_ssl`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub server_address: String,
+    #[serde(deserialize_with = "string_or_number")]
     pub server_port: String,
     pub tenant_id: String,
     pub uuid: String,
+    pub proxy_type: ProxyKind,
     pub proxy_url: Option<String>,
+    #[serde(deserialize_with = "optional_string_or_number")]
     pub proxy_port: Option<String>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    /// Pre-encoded credentials (base64 of `user:password`), sent as HTTP
+    /// `Basic` auth or decoded for SOCKS5
     pub proxy_auth: Option<String>,
     pub use_proxy: bool,
+    /// Fall back to `HTTPS_PROXY`/`HTTP_PROXY`/`NO_PROXY` when `use_proxy` is off
+    pub proxy_from_env: bool,
     pub use_ssl: bool,
     pub no_auth: bool,
+    pub endpoint: Endpoint,
+    pub tls: TlsConfig,
+    pub reconnect: ReconnectConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub operations: OperationsConfig,
+    pub transfer: TransferConfig,
+    pub features: FeaturesConfig,
+    pub sandbox: SandboxConfig,
+    pub archive: ArchiveConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server_address: String::new(),
+            server_port: String::new(),
+            tenant_id: String::new(),
+            uuid: String::new(),
+            proxy_type: ProxyKind::default(),
+            proxy_url: None,
+            proxy_port: None,
+            proxy_username: None,
+            proxy_password: None,
+            proxy_auth: None,
+            use_proxy: false,
+            proxy_from_env: true,
+            use_ssl: true,
+            no_auth: false,
+            endpoint: Endpoint::default(),
+            tls: TlsConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            operations: OperationsConfig::default(),
+            transfer: TransferConfig::default(),
+            features: FeaturesConfig::default(),
+            sandbox: SandboxConfig::default(),
+            archive: ArchiveConfig::default(),
+        }
+    }
 }
 
 impl Config {
-    /// Load configuration from file
+    /// Load configuration from file, detecting TOML, JSON or the legacy format
     pub fn load_from_file(path: &str) -> Result<Self> {
+        let mut config = Self::read_unvalidated(path)?;
+        config.normalize();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse configuration text in the given format, then normalize and validate it
+    pub fn parse(content: &str, format: ConfigFormat) -> Result<Self> {
+        let mut config = Self::parse_unvalidated(content, format)?;
+        config.normalize();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn parse_unvalidated(content: &str, format: ConfigFormat) -> Result<Self> {
+        match format {
+            ConfigFormat::Toml => toml::from_str(content)
+                .map_err(|e| Error::Config(format!("Invalid TOML config: {}", e))),
+            ConfigFormat::Json => serde_json::from_str(content)
+                .map_err(|e| Error::Config(format!("Invalid JSON config: {}", e))),
+            ConfigFormat::Legacy => legacy::parse(content),
+        }
+    }
+
+    /// Load configuration in layers: command line flags override `C1RMM_*`
+    /// environment variables, which override the config file.
+    ///
+    /// The file comes from `--config`, then `C1RMM_CONFIG`, then
+    /// [`DEFAULT_CONFIG_PATH`]. A missing default file is not an error as long
+    /// as the remaining layers supply every required field.
+    pub fn load_layered(args: &CliArgs) -> Result<Self> {
+        let env = ConfigOverrides::from_env()?;
+        let env_path = std::env::var(format!("{}CONFIG", ENV_PREFIX)).ok();
+        Self::load_layers(
+            args.config_path.as_deref().or(env_path.as_deref()),
+            &env,
+            &args.overrides,
+        )
+    }
+
+    /// Layer `env` and `cli` overrides on top of the file at `path`
+    pub fn load_layers(
+        path: Option<&str>,
+        env: &ConfigOverrides,
+        cli: &ConfigOverrides,
+    ) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::read_unvalidated(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::read_unvalidated(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+
+        config.normalize();
+        env.apply(&mut config);
+        cli.apply(&mut config);
+        config.normalize();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read_unvalidated(path: &str) -> Result<Self> {
         let content = fs::read_to_string(path)
             .map_err(|e| Error::Config(format!("Failed to read config file '{}': {}", path, e)))?;
-
-        let tokens: Vec<&str> = content.trim().split(' ').collect();
-
-        if tokens.len() < 11 {
-            return Err(Error::Config(format!(
-                "Config file '{}' doesn't have enough tokens (expected 11, got {})",
-                path,
-                tokens.len()
-            )));
-        }
-
-        let server_address = tokens[0].to_string();
-        let use_ssl =
-            server_address.starts_with("wss://") || server_address.starts_with("https://");
-
-        // Extract hostname/IP from server address
-        let clean_server = server_address
-            .replace("wss://", "")
-            .replace("ws://", "")
-            .replace("https://", "")
-            .replace("http://", "");
-
-        Ok(Config {
-            server_address: clean_server,
-            server_port: tokens[1].to_string(),
-            tenant_id: tokens[2].to_string(),
-            proxy_url: if tokens[3].is_empty() {
-                None
-            } else {
-                Some(tokens[3].to_string())
-            },
-            proxy_port: if tokens[4].is_empty() {
-                None
-            } else {
-                Some(tokens[4].to_string())
-            },
-            //Check if proxy_url and proxy_port are present AND not empty
-            use_proxy: tokens[7] == "proxy",
-            uuid: tokens[8].to_string(),
-            proxy_auth: if tokens[9].is_empty() {
-                None
-            } else {
-                Some(tokens[9].to_string())
-            },
-            no_auth: tokens[10] == "isNoAuth",
-            use_ssl,
+        let format = ConfigFormat::detect(Path::new(path), &content);
+        Self::parse_unvalidated(&content, format).map_err(|e| match e {
+            Error::Config(msg) => Error::Config(format!("{} ({})", msg, path)),
+            other => other,
         })
     }
 
+    /// Username and password decoded from `proxy_auth`
+    pub fn proxy_credentials(&self) -> Option<(String, String)> {
+        proxy::decode_credentials(self.proxy_auth.as_deref()?)
+    }
+
+    /// The explicitly configured proxy, if `use_proxy` is set
+    pub fn configured_proxy(&self) -> Result<Option<ProxySettings>> {
+        if !self.use_proxy {
+            return Ok(None);
+        }
+        let host = self
+            .proxy_url
+            .clone()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| Error::Config("Proxy host not configured".to_string()))?;
+        let port = self
+            .proxy_port
+            .as_deref()
+            .and_then(parse_port)
+            .ok_or_else(|| Error::Config("Proxy port not configured".to_string()))?;
+        Ok(Some(ProxySettings {
+            kind: self.proxy_type,
+            host,
+            port,
+            auth: self.proxy_auth.clone(),
+            no_auth: self.no_auth,
+        }))
+    }
+
+    /// Proxy for reaching the relay: the configured one, otherwise the
+    /// `HTTPS_PROXY`/`HTTP_PROXY` environment unless `NO_PROXY` exempts it
+    pub fn relay_proxy(&self, env: &EnvProxy) -> Result<Option<ProxySettings>> {
+        if self.use_proxy {
+            return self.configured_proxy();
+        }
+        if !self.proxy_from_env {
+            return Ok(None);
+        }
+        env.proxy_for(&self.server_address, self.use_ssl)
+            .map_err(Error::Config)
+    }
+
+    /// Copy of the configuration with credentials replaced, safe to print or log
+    pub fn redacted(&self) -> Self {
+        const REDACTED: &str = "<redacted>";
+        let mut config = self.clone();
+        for secret in [
+            &mut config.proxy_password,
+            &mut config.proxy_auth,
+            &mut config.tls.client_pkcs12_password,
+        ] {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_string());
+            }
+        }
+        config
+    }
+
+    /// Render the configuration as TOML with secrets redacted
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        toml::to_string_pretty(&self.redacted())
+            .map_err(|e| Error::Config(format!("Failed to serialize config: {}", e)))
+    }
+
+    /// Strip the URL scheme from the server address and derive `use_ssl` from it
+    fn normalize(&mut self) {
+        let address = self.server_address.trim();
+        let (use_ssl, host) = if let Some(rest) = address
+            .strip_prefix("wss://")
+            .or_else(|| address.strip_prefix("https://"))
+        {
+            (Some(true), rest)
+        } else if let Some(rest) = address
+            .strip_prefix("ws://")
+            .or_else(|| address.strip_prefix("http://"))
+        {
+            (Some(false), rest)
+        } else {
+            (None, address)
+        };
+
+        if let Some(use_ssl) = use_ssl {
+            self.use_ssl = use_ssl;
+        }
+        self.server_address = host.trim_end_matches('/').to_string();
+
+        if self.proxy_auth.is_none() {
+            if let (Some(user), Some(pass)) = (&self.proxy_username, &self.proxy_password) {
+                self.proxy_auth =
+                    Some(general_purpose::STANDARD.encode(format!("{}:{}", user, pass)));
+            }
+        }
+    }
+
+    /// Check every field and report all problems at once
+    pub fn validate(&self) -> Result<()> {
+        let mut issues = Vec::new();
+
+        if self.server_address.is_empty() {
+            issues.push("server_address: must not be empty".to_string());
+        } else if self
+            .server_address
+            .contains(|c: char| c.is_whitespace() || c == '/')
+        {
+            issues.push(format!(
+                "server_address: '{}' is not a valid host",
+                self.server_address
+            ));
+        }
+
+        if !self.server_port.is_empty() && parse_port(&self.server_port).is_none() {
+            issues.push(format!(
+                "server_port: '{}' is not a valid port",
+                self.server_port
+            ));
+        }
+
+        for (field, value) in [("tenant_id", &self.tenant_id), ("uuid", &self.uuid)] {
+            if value.is_empty() {
+                issues.push(format!("{}: must not be empty", field));
+            } else if value.contains(|c: char| c.is_whitespace() || c == '/') {
+                issues.push(format!("{}: must not contain '/' or whitespace", field));
+            }
+        }
+
+        if self.use_proxy {
+            if self.proxy_url.as_deref().unwrap_or("").is_empty() {
+                issues.push("proxy_url: required when use_proxy is set".to_string());
+            }
+            match self.proxy_port.as_deref() {
+                None | Some("") => {
+                    issues.push("proxy_port: required when use_proxy is set".to_string())
+                }
+                Some(port) if parse_port(port).is_none() => {
+                    issues.push(format!("proxy_port: '{}' is not a valid port", port))
+                }
+                _ => {}
+            }
+            if !self.no_auth && self.proxy_auth.is_none() {
+                issues.push("proxy_auth: credentials required unless no_auth is set".to_string());
+            }
+        }
+
+        if let Some(auth) = &self.proxy_auth {
+            if general_purpose::STANDARD.decode(auth).is_err() {
+                issues.push("proxy_auth: must be base64 encoded".to_string());
+            } else if self.proxy_type == ProxyKind::Socks5 {
+                match self.proxy_credentials() {
+                    Some((user, pass)) if user.len() > 255 || pass.len() > 255 => issues.push(
+                        "proxy_auth: SOCKS5 username and password are limited to 255 bytes"
+                            .to_string(),
+                    ),
+                    Some(_) => {}
+                    None => issues.push(
+                        "proxy_auth: SOCKS5 needs credentials of the form user:password"
+                            .to_string(),
+                    ),
+                }
+            }
+        }
+
+        if let Some(issue) = self.endpoint.validate() {
+            issues.push(format!("endpoint: {}", issue));
+        }
+        if self.use_ssl {
+            self.tls.validate(&mut issues);
+        }
+        self.reconnect.validate(&mut issues);
+        self.heartbeat.validate(&mut issues);
+        self.operations.validate(&mut issues);
+        self.transfer.validate(&mut issues);
+        self.sandbox.validate(&mut issues);
+        self.archive.validate(&mut issues);
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(issues))
+        }
+    }
+
     /// Build a WebSocket URL for the given agent type
     pub fn get_websocket_url_for(&self, agent_type: &str) -> String {
         let protocol = if self.use_ssl { "wss" } else { "ws" };
@@ -84,8 +402,11 @@ impl Config {
         };
 
         format!(
-            "{}://{}:{}/ws/rev/{}/{}/{}",
-            protocol, self.server_address, port, agent_type, self.tenant_id, self.uuid
+            "{}://{}:{}{}",
+            protocol,
+            self.server_address,
+            port,
+            self.endpoint.path(agent_type, &self.tenant_id, &self.uuid)
         )
     }
 
@@ -94,3 +415,122 @@ impl Config {
         self.get_websocket_url_for("file_agent")
     }
 }
+
+fn parse_port(value: &str) -> Option<u16> {
+    value.parse::<u16>().ok().filter(|port| *port != 0)
+}
+
+/// Ports are strings internally but may be written as numbers in keyed files
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrNumber {
+    String(String),
+    Number(u64),
+}
+
+impl From<StringOrNumber> for String {
+    fn from(value: StringOrNumber) -> Self {
+        match value {
+            StringOrNumber::String(s) => s,
+            StringOrNumber::Number(n) => n.to_string(),
+        }
+    }
+}
+
+fn string_or_number<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    StringOrNumber::deserialize(deserializer).map(String::from)
+}
+
+fn optional_string_or_number<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<StringOrNumber>::deserialize(deserializer)?.map(String::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_format_by_extension() {
+        let content = "server_address = 'relay'";
+        for (path, format) in [
+            ("agent.toml", ConfigFormat::Toml),
+            ("agent.JSON", ConfigFormat::Json),
+            ("config.txt", ConfigFormat::Toml),
+        ] {
+            assert_eq!(ConfigFormat::detect(Path::new(path), content), format);
+        }
+        // The extension wins over what the content looks like
+        assert_eq!(
+            ConfigFormat::detect(Path::new("agent.toml"), "{}"),
+            ConfigFormat::Toml
+        );
+    }
+
+    #[test]
+    fn detects_format_by_content() {
+        let path = Path::new("config.txt");
+        let cases = [
+            (r#"  {"server_address": "relay"}"#, ConfigFormat::Json),
+            (
+                "server_address = \"relay\"\n[sandbox]\nread_only = true",
+                ConfigFormat::Toml,
+            ),
+            ("relay 443 acme     none 4f1c  false", ConfigFormat::Legacy),
+        ];
+        for (content, format) in cases {
+            assert_eq!(ConfigFormat::detect(path, content), format, "{}", content);
+        }
+    }
+
+    #[test]
+    fn parses_keyed_formats_alike() {
+        let toml = "server_address = 'wss://relay.example.com/'\nserver_port = 8443\ntenant_id = 'acme'\nuuid = '4f1c'";
+        let json = r#"{"server_address": "wss://relay.example.com/", "server_port": "8443",
+            "tenant_id": "acme", "uuid": "4f1c"}"#;
+        for (content, format) in [(toml, ConfigFormat::Toml), (json, ConfigFormat::Json)] {
+            let config = Config::parse(content, format).unwrap();
+            assert_eq!(config.server_address, "relay.example.com");
+            assert_eq!(config.server_port, "8443");
+            assert!(config.use_ssl);
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let config = Config {
+            server_address: "relay example".to_string(),
+            server_port: "99999".to_string(),
+            tenant_id: String::new(),
+            uuid: "a/b".to_string(),
+            use_proxy: true,
+            ..Config::default()
+        };
+        let Err(Error::InvalidConfig(issues)) = config.validate() else {
+            panic!("expected InvalidConfig");
+        };
+        for field in [
+            "server_address",
+            "server_port",
+            "tenant_id",
+            "uuid",
+            "proxy_url",
+            "proxy_port",
+            "proxy_auth",
+        ] {
+            assert!(
+                issues.iter().any(|issue| issue.starts_with(field)),
+                "no issue for {} in {:?}",
+                field,
+                issues
+            );
+        }
+    }
+}
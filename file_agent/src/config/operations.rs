@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Limits for file agent requests executed in the background
+///
+/// At most `max_concurrent` requests run at once; up to `max_queued` more
+/// wait for a slot, and anything beyond that is rejected as `busy`.
+/// A running request is cancelled once it exceeds its timeout: the entry in
+/// `timeouts` for its type (e.g. `zip_file = 7200`), else `timeout_secs`.
+/// Zero disables the timeout.
+/// Requests that ask for progress get a `job_progress` update at most every
+/// `progress_interval_ms`; zero disables progress updates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OperationsConfig {
+    pub max_concurrent: usize,
+    pub max_queued: usize,
+    pub timeout_secs: u64,
+    pub timeouts: BTreeMap<String, u64>,
+    pub progress_interval_ms: u64,
+}
+
+impl Default for OperationsConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 4,
+            max_queued: 32,
+            timeout_secs: 3_600,
+            timeouts: BTreeMap::new(),
+            progress_interval_ms: 500,
+        }
+    }
+}
+
+impl OperationsConfig {
+    /// Time limit for a request of type `kind`, if any
+    pub fn timeout_for(&self, kind: &str) -> Option<Duration> {
+        let secs = self
+            .timeouts
+            .get(kind)
+            .copied()
+            .unwrap_or(self.timeout_secs);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    pub fn progress_interval(&self) -> Option<Duration> {
+        (self.progress_interval_ms > 0).then(|| Duration::from_millis(self.progress_interval_ms))
+    }
+
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        if self.max_concurrent == 0 {
+            issues.push("operations.max_concurrent: must be greater than zero".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_per_request_type_overrides_the_default() {
+        let config = OperationsConfig {
+            timeout_secs: 60,
+            timeouts: BTreeMap::from([
+                ("zip_file".to_string(), 7_200),
+                ("search_files".to_string(), 0),
+            ]),
+            ..OperationsConfig::default()
+        };
+        assert_eq!(
+            config.timeout_for("paste_file"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            config.timeout_for("zip_file"),
+            Some(Duration::from_secs(7_200))
+        );
+        assert_eq!(config.timeout_for("search_files"), None);
+
+        let unlimited = OperationsConfig {
+            timeout_secs: 0,
+            ..OperationsConfig::default()
+        };
+        assert_eq!(unlimited.timeout_for("paste_file"), None);
+    }
+}
@@ -0,0 +1,380 @@
+use super::{Config, Endpoint, ProxyKind};
+use crate::error::{Error, Result};
+
+/// Prefix shared by every environment variable the agents read
+pub const ENV_PREFIX: &str = "C1RMM_";
+
+/// Config file used when neither `--config` nor `C1RMM_CONFIG` is given
+pub const DEFAULT_CONFIG_PATH: &str = "config.txt";
+
+const USAGE: &str = "\
+Options:
+  --config <path>           Config file (TOML, JSON or legacy config.txt) [env: C1RMM_CONFIG]
+  --print-config            Print the effective configuration with secrets redacted and exit
+  --server <address>        Relay address, optionally with ws://, wss://, http(s):// [env: C1RMM_SERVER]
+  --port <port>             Relay port [env: C1RMM_PORT]
+  --tenant <id>             Tenant id [env: C1RMM_TENANT]
+  --uuid <id>               Agent uuid [env: C1RMM_UUID]
+  --proxy-type <type>       Proxy protocol: http (CONNECT) or socks5 [env: C1RMM_PROXY_TYPE]
+  --proxy-host <host>       Proxy host [env: C1RMM_PROXY_HOST]
+  --proxy-port <port>       Proxy port [env: C1RMM_PROXY_PORT]
+  --proxy-user <user>       Proxy username [env: C1RMM_PROXY_USER]
+  --proxy-password <pass>   Proxy password [env: C1RMM_PROXY_PASSWORD]
+  --proxy-auth <base64>     Pre-encoded user:password proxy credentials [env: C1RMM_PROXY_AUTH]
+  --use-proxy <bool>        Connect through the configured proxy [env: C1RMM_USE_PROXY]
+  --proxy-from-env <bool>   Otherwise honour HTTPS_PROXY/HTTP_PROXY/NO_PROXY [env: C1RMM_PROXY_FROM_ENV]
+  --use-ssl <bool>          Use wss:// for the relay connection [env: C1RMM_USE_SSL]
+  --no-auth <bool>          Proxy does not require authentication [env: C1RMM_NO_AUTH]
+  --endpoint <endpoint>     reverse_relay, legacy or a custom path template such as
+                            /relay/{agent_type}/{tenant_id}/{uuid} [env: C1RMM_ENDPOINT]
+  --tls-ca <path>           PEM CA bundle for the relay certificate [env: C1RMM_TLS_CA]
+  --tls-pin <sha256>        Pin the relay certificate by SHA-256 fingerprint [env: C1RMM_TLS_PIN]
+  --tls-insecure <bool>     Skip certificate verification [env: C1RMM_TLS_INSECURE]
+  --tls-client-cert <path>  PEM client certificate for mutual TLS [env: C1RMM_TLS_CLIENT_CERT]
+  --tls-client-key <path>   PEM PKCS#8 client key [env: C1RMM_TLS_CLIENT_KEY]
+  --tls-client-pkcs12 <path>
+                            PKCS#12 client identity [env: C1RMM_TLS_CLIENT_PKCS12]
+  --tls-client-pkcs12-password <pass>
+                            PKCS#12 password [env: C1RMM_TLS_CLIENT_PKCS12_PASSWORD]
+  --generate-identity       Create a client keypair and certificate, print its fingerprint and exit
+  --rotate-identity         Like --generate-identity, backing up and replacing an existing pair
+  -h, --help                Show this help";
+
+/// A sparse set of config values layered on top of the config file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub server_address: Option<String>,
+    pub server_port: Option<String>,
+    pub tenant_id: Option<String>,
+    pub uuid: Option<String>,
+    pub proxy_type: Option<ProxyKind>,
+    pub proxy_url: Option<String>,
+    pub proxy_port: Option<String>,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    pub proxy_auth: Option<String>,
+    pub use_proxy: Option<bool>,
+    pub proxy_from_env: Option<bool>,
+    pub use_ssl: Option<bool>,
+    pub no_auth: Option<bool>,
+    pub endpoint: Option<Endpoint>,
+    pub tls_ca_bundle: Option<String>,
+    pub tls_pin_sha256: Option<String>,
+    pub tls_insecure: Option<bool>,
+    pub tls_client_cert: Option<String>,
+    pub tls_client_key: Option<String>,
+    pub tls_client_pkcs12: Option<String>,
+    pub tls_client_pkcs12_password: Option<String>,
+}
+
+impl ConfigOverrides {
+    /// Read overrides from the process environment
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Read overrides from `C1RMM_*` variables in the given key/value pairs
+    pub fn from_vars<I>(vars: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut overrides = Self::default();
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let option = match name {
+                "SERVER" => "server",
+                "PORT" => "port",
+                "TENANT" => "tenant",
+                "UUID" => "uuid",
+                "PROXY_TYPE" => "proxy-type",
+                "PROXY_HOST" => "proxy-host",
+                "PROXY_PORT" => "proxy-port",
+                "PROXY_USER" => "proxy-user",
+                "PROXY_PASSWORD" => "proxy-password",
+                "PROXY_AUTH" => "proxy-auth",
+                "USE_PROXY" => "use-proxy",
+                "PROXY_FROM_ENV" => "proxy-from-env",
+                "USE_SSL" => "use-ssl",
+                "NO_AUTH" => "no-auth",
+                "ENDPOINT" => "endpoint",
+                "TLS_CA" => "tls-ca",
+                "TLS_PIN" => "tls-pin",
+                "TLS_INSECURE" => "tls-insecure",
+                "TLS_CLIENT_CERT" => "tls-client-cert",
+                "TLS_CLIENT_KEY" => "tls-client-key",
+                "TLS_CLIENT_PKCS12" => "tls-client-pkcs12",
+                "TLS_CLIENT_PKCS12_PASSWORD" => "tls-client-pkcs12-password",
+                _ => continue,
+            };
+            overrides
+                .set(option, value)
+                .map_err(|e| Error::Config(format!("{}: {}", key, e)))?;
+        }
+        Ok(overrides)
+    }
+
+    /// Set a single value by its command line option name (without `--`).
+    /// Returns `Ok(false)` when the option is not a config override.
+    fn set(&mut self, option: &str, value: String) -> std::result::Result<bool, String> {
+        match option {
+            "server" => self.server_address = Some(value),
+            "port" => self.server_port = Some(value),
+            "tenant" => self.tenant_id = Some(value),
+            "uuid" => self.uuid = Some(value),
+            "proxy-type" => self.proxy_type = Some(value.parse()?),
+            "proxy-host" => self.proxy_url = Some(value),
+            "proxy-port" => self.proxy_port = Some(value),
+            "proxy-user" => self.proxy_username = Some(value),
+            "proxy-password" => self.proxy_password = Some(value),
+            "proxy-auth" => self.proxy_auth = Some(value),
+            "use-proxy" => self.use_proxy = Some(parse_bool(&value)?),
+            "proxy-from-env" => self.proxy_from_env = Some(parse_bool(&value)?),
+            "use-ssl" => self.use_ssl = Some(parse_bool(&value)?),
+            "no-auth" => self.no_auth = Some(parse_bool(&value)?),
+            "endpoint" => self.endpoint = Some(value.parse()?),
+            "tls-ca" => self.tls_ca_bundle = Some(value),
+            "tls-pin" => self.tls_pin_sha256 = Some(value),
+            "tls-insecure" => self.tls_insecure = Some(parse_bool(&value)?),
+            "tls-client-cert" => self.tls_client_cert = Some(value),
+            "tls-client-key" => self.tls_client_key = Some(value),
+            "tls-client-pkcs12" => self.tls_client_pkcs12 = Some(value),
+            "tls-client-pkcs12-password" => self.tls_client_pkcs12_password = Some(value),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Apply every value that is set on top of `config`
+    pub fn apply(&self, config: &mut Config) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        fn set_opt<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+
+        set(&mut config.server_address, &self.server_address);
+        set(&mut config.server_port, &self.server_port);
+        set(&mut config.tenant_id, &self.tenant_id);
+        set(&mut config.uuid, &self.uuid);
+        set(&mut config.proxy_type, &self.proxy_type);
+        set_opt(&mut config.proxy_url, &self.proxy_url);
+        set_opt(&mut config.proxy_port, &self.proxy_port);
+
+        // New credentials invalidate a pre-encoded header from a lower layer
+        if self.proxy_username.is_some() || self.proxy_password.is_some() {
+            config.proxy_auth = None;
+        }
+        set_opt(&mut config.proxy_username, &self.proxy_username);
+        set_opt(&mut config.proxy_password, &self.proxy_password);
+        set_opt(&mut config.proxy_auth, &self.proxy_auth);
+
+        set(&mut config.use_proxy, &self.use_proxy);
+        set(&mut config.proxy_from_env, &self.proxy_from_env);
+        set(&mut config.use_ssl, &self.use_ssl);
+        set(&mut config.no_auth, &self.no_auth);
+        set(&mut config.endpoint, &self.endpoint);
+
+        // Supplying a CA or pin is an explicit request for verification
+        if self.tls_ca_bundle.is_some() || self.tls_pin_sha256.is_some() {
+            config.tls.insecure = false;
+        }
+        set_opt(&mut config.tls.ca_bundle, &self.tls_ca_bundle);
+        set_opt(&mut config.tls.pin_sha256, &self.tls_pin_sha256);
+        set(&mut config.tls.insecure, &self.tls_insecure);
+        set_opt(&mut config.tls.client_cert, &self.tls_client_cert);
+        set_opt(&mut config.tls.client_key, &self.tls_client_key);
+        set_opt(&mut config.tls.client_pkcs12, &self.tls_client_pkcs12);
+        set_opt(
+            &mut config.tls.client_pkcs12_password,
+            &self.tls_client_pkcs12_password,
+        );
+    }
+}
+
+/// Command line arguments shared by the agent binaries
+#[derive(Debug, Clone, Default)]
+pub struct CliArgs {
+    pub config_path: Option<String>,
+    pub print_config: bool,
+    pub help: bool,
+    /// Generate a client identity instead of connecting
+    pub generate_identity: bool,
+    /// Generate a client identity, replacing an existing one
+    pub rotate_identity: bool,
+    pub overrides: ConfigOverrides,
+}
+
+impl CliArgs {
+    /// Parse arguments, excluding the program name
+    pub fn parse<I>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+
+            match name.as_str() {
+                "-h" | "--help" => {
+                    parsed.help = true;
+                    continue;
+                }
+                "--print-config" => {
+                    parsed.print_config = true;
+                    continue;
+                }
+                "--generate-identity" => {
+                    parsed.generate_identity = true;
+                    continue;
+                }
+                "--rotate-identity" => {
+                    parsed.rotate_identity = true;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(option) = name.strip_prefix("--") else {
+                return Err(Error::Config(format!("Unexpected argument '{}'", arg)));
+            };
+
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(Error::Config(format!("{} requires a value", name))),
+            };
+
+            if option == "config" {
+                parsed.config_path = Some(value);
+                continue;
+            }
+
+            match parsed.overrides.set(option, value) {
+                Ok(true) => {}
+                Ok(false) => return Err(Error::Config(format!("Unknown option '{}'", name))),
+                Err(e) => return Err(Error::Config(format!("{}: {}", name, e))),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Parse the current process arguments
+    pub fn from_env_args() -> Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    /// Help text listing every option
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+}
+
+fn parse_bool(value: &str) -> std::result::Result<bool, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        other => Err(format!("'{}' is not a boolean", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+
+    fn args(line: &str) -> Result<CliArgs> {
+        CliArgs::parse(line.split_whitespace().map(str::to_string))
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn layers_file_then_env_then_cli() {
+        let dir = TestDir::new("overrides-layers");
+        let file = dir.write(
+            "agent.toml",
+            "server_address = 'relay.example.com'\nserver_port = 443\n\
+             tenant_id = 'from-file'\nuuid = 'from-file'\nuse_ssl = false",
+        );
+        let env = ConfigOverrides::from_vars(vars(&[
+            ("C1RMM_PORT", "8443"),
+            ("C1RMM_TENANT", "from-env"),
+            ("C1RMM_UNRELATED", "ignored"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        let cli = args("--port=9443 --use-ssl yes").unwrap().overrides;
+
+        let config = Config::load_layers(Some(&file.display().to_string()), &env, &cli).unwrap();
+        assert_eq!(config.server_address, "relay.example.com");
+        assert_eq!(config.uuid, "from-file");
+        assert_eq!(config.tenant_id, "from-env");
+        assert_eq!(config.server_port, "9443");
+        assert!(config.use_ssl);
+    }
+
+    #[test]
+    fn new_credentials_replace_encoded_ones_from_a_lower_layer() {
+        let mut config = Config {
+            proxy_auth: Some("b2xkOm9sZA==".to_string()),
+            ..Config::default()
+        };
+        let cli = args("--proxy-user bob --proxy-password secret")
+            .unwrap()
+            .overrides;
+        cli.apply(&mut config);
+        assert_eq!(config.proxy_auth, None);
+        config.normalize();
+        assert_eq!(
+            config.proxy_credentials(),
+            Some(("bob".into(), "secret".into()))
+        );
+    }
+
+    #[test]
+    fn parses_flags_and_config_path() {
+        let parsed =
+            args("--config agent.toml --print-config --tenant acme --proxy-type socks5").unwrap();
+        assert_eq!(parsed.config_path.as_deref(), Some("agent.toml"));
+        assert!(parsed.print_config);
+        assert_eq!(parsed.overrides.tenant_id.as_deref(), Some("acme"));
+        assert_eq!(parsed.overrides.proxy_type, Some(ProxyKind::Socks5));
+        assert_eq!(parsed.overrides.server_port, None);
+    }
+
+    #[test]
+    fn rejects_unknown_flags_and_missing_values() {
+        for (line, expected) in [
+            ("--frobnicate 1", "Unknown option '--frobnicate'"),
+            ("--tenant acme --port", "--port requires a value"),
+            ("--use-proxy maybe", "'maybe' is not a boolean"),
+            ("--proxy-type gopher", "unknown proxy type"),
+            ("relay.example.com", "Unexpected argument"),
+        ] {
+            let error = args(line).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", line, error);
+        }
+
+        let error = ConfigOverrides::from_vars(vars(&[("C1RMM_USE_SSL", "sometimes")]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("C1RMM_USE_SSL"), "{}", error);
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Reconnect timing shared by both agents
+///
+/// The n-th consecutive retry waits a random time between zero and
+/// `min(max_delay_ms, initial_delay_ms * multiplier^(n-1))` ("full jitter"),
+/// so a relay restart does not see every agent come back in lockstep.
+/// A connection that stayed up for `stable_after_secs` resets the sequence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    pub stable_after_secs: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+            multiplier: 2.0,
+            stable_after_secs: 60,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+
+    pub fn stable_after(&self) -> Duration {
+        Duration::from_secs(self.stable_after_secs)
+    }
+
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        if self.initial_delay_ms == 0 {
+            issues.push("reconnect.initial_delay_ms: must be greater than zero".to_string());
+        }
+        if self.max_delay_ms < self.initial_delay_ms {
+            issues.push("reconnect.max_delay_ms: must not be below initial_delay_ms".to_string());
+        }
+        if !(self.multiplier >= 1.0 && self.multiplier.is_finite()) {
+            issues.push(format!(
+                "reconnect.multiplier: {} must be a finite number >= 1",
+                self.multiplier
+            ));
+        }
+    }
+}
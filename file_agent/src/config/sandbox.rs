@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Which paths file operations may touch
+///
+/// With `allowed_roots` empty every path is allowed apart from
+/// `denied_paths`. Both lists take absolute paths and cover everything
+/// beneath them. `read_only` refuses every operation that would modify
+/// the file system.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    pub allowed_roots: Vec<String>,
+    pub denied_paths: Vec<String>,
+    pub read_only: bool,
+}
+
+impl SandboxConfig {
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        for (field, paths) in [
+            ("allowed_roots", &self.allowed_roots),
+            ("denied_paths", &self.denied_paths),
+        ] {
+            for path in paths {
+                if !Path::new(path).is_absolute() {
+                    issues.push(format!(
+                        "sandbox.{}: '{}' is not an absolute path",
+                        field, path
+                    ));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Certificate verification settings for the relay connection
+///
+/// By default the relay certificate is checked against the system trust
+/// store. `ca_bundle` adds private CAs, `pin_sha256` accepts exactly one
+/// leaf certificate regardless of issuer, and `insecure` turns checks off.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file with one or more CA certificates to trust
+    pub ca_bundle: Option<String>,
+    /// SHA-256 fingerprint of the relay certificate (hex, `:` separators allowed)
+    pub pin_sha256: Option<String>,
+    /// Accept any certificate and hostname; only for lab setups
+    pub insecure: bool,
+    /// PEM client certificate presented to the relay (with `client_key`)
+    pub client_cert: Option<String>,
+    /// PEM PKCS#8 private key matching `client_cert`
+    pub client_key: Option<String>,
+    /// PKCS#12 bundle holding the client certificate and key
+    pub client_pkcs12: Option<String>,
+    pub client_pkcs12_password: Option<String>,
+}
+
+impl TlsConfig {
+    /// Decode `pin_sha256` into raw bytes
+    pub fn pin(&self) -> Option<std::result::Result<[u8; 32], String>> {
+        self.pin_sha256.as_deref().map(parse_fingerprint)
+    }
+
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        if let Some(path) = &self.ca_bundle {
+            if !Path::new(path).is_file() {
+                issues.push(format!("tls.ca_bundle: '{}' is not a readable file", path));
+            }
+        }
+        if let Some(Err(e)) = self.pin() {
+            issues.push(format!("tls.pin_sha256: {}", e));
+        }
+
+        // The files themselves may not exist yet when generating an identity,
+        // so only their combination is checked here
+        match (&self.client_cert, &self.client_key) {
+            (Some(_), None) => {
+                issues.push("tls.client_key: required with tls.client_cert".to_string())
+            }
+            (None, Some(_)) => {
+                issues.push("tls.client_cert: required with tls.client_key".to_string())
+            }
+            _ => {}
+        }
+        if self.client_pkcs12.is_some() && self.client_cert.is_some() {
+            issues.push(
+                "tls.client_pkcs12: cannot be combined with tls.client_cert/client_key".to_string(),
+            );
+        }
+    }
+}
+
+/// Parse a hex SHA-256 fingerprint such as `AB:CD:...` or `abcd...`
+pub fn parse_fingerprint(value: &str) -> std::result::Result<[u8; 32], String> {
+    let hex: String = value
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+    if !hex.is_ascii() || hex.len() != 64 {
+        return Err(format!(
+            "expected 64 hex digits, got {} in '{}'",
+            hex.len(),
+            value
+        ));
+    }
+
+    let mut pin = [0u8; 32];
+    for (i, byte) in pin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("'{}' is not a hex fingerprint", value))?;
+    }
+    Ok(pin)
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Chunked file transfers over the relay connection
+///
+/// Data is sent in `chunk_size` pieces (a download may ask for anything up to
+/// `max_chunk_size`). At most `window_chunks` chunks are in flight before the
+/// client has to acknowledge them, and a transfer whose client stops
+/// acknowledging for `idle_timeout_secs` is abandoned.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    pub chunk_size: u32,
+    pub max_chunk_size: u32,
+    pub window_chunks: u32,
+    pub max_transfers: usize,
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 256 * 1024,
+            max_chunk_size: 4 * 1024 * 1024,
+            window_chunks: 8,
+            max_transfers: 4,
+            idle_timeout_secs: 120,
+        }
+    }
+}
+
+impl TransferConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// The chunk size to use when a client asks for `requested`
+    pub fn chunk_size_for(&self, requested: Option<u32>) -> u32 {
+        requested
+            .unwrap_or(self.chunk_size)
+            .clamp(1024, self.max_chunk_size.max(1024))
+    }
+
+    pub(crate) fn validate(&self, issues: &mut Vec<String>) {
+        if self.chunk_size == 0 {
+            issues.push("transfer.chunk_size: must be greater than zero".to_string());
+        }
+        if self.max_chunk_size < self.chunk_size {
+            issues.push("transfer.max_chunk_size: must not be below chunk_size".to_string());
+        }
+        if self.window_chunks == 0 {
+            issues.push("transfer.window_chunks: must be greater than zero".to_string());
+        }
+        if self.idle_timeout_secs == 0 {
+            issues.push("transfer.idle_timeout_secs: must be greater than zero".to_string());
+        }
+    }
+}
@@ -6,12 +6,44 @@ pub enum Error {
     #[error("Configuration error: {0}")]
     Config(String),
 
+    #[error("Invalid configuration: {}", .0.join("; "))]
+    InvalidConfig(Vec<String>),
+
     #[error("Network error: {0}")]
     Network(String),
 
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("Proxy error: {0}")]
+    Proxy(#[from] crate::network::proxy::ProxyError),
+
     #[error("File system error: {0}")]
     FileSystem(String),
 
+    #[error("{context}: {source}")]
+    FileIo {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A long-running operation stopped early because it was cancelled
+    /// or ran out of time
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    /// An archive that is unsafe to extract or exceeds the configured limits
+    #[error("Archive rejected: {0}")]
+    Archive(String),
+
+    /// Refused by the configured sandbox policy
+    #[error("Access denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
     #[error("System error: {0}")]
     System(String),
 
@@ -22,7 +54,7 @@ pub enum Error {
     Io(#[from] std::io::Error),
 
     #[error("WebSocket error: {0}")]
-    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocket(#[from] Box<tokio_tungstenite::tungstenite::Error>),
 
     #[error("URL parsing error: {0}")]
     Url(#[from] url::ParseError),
@@ -34,9 +66,26 @@ pub enum Error {
     Zip(#[from] zip::result::ZipError),
 }
 
+impl Error {
+    /// An I/O failure during a file operation, keeping the `io::ErrorKind`
+    /// so it can be reported with a specific error code
+    pub fn file_io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Error::FileIo {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
 /// Application result type
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
 /// Convert string errors to our error type
 impl From<String> for Error {
     fn from(s: String) -> Self {
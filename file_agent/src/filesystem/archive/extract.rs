@@ -0,0 +1,457 @@
+use crate::config::ArchiveConfig;
+use crate::error::{Error, Result};
+use crate::filesystem::progress::{Job, ProgressReader};
+use crate::filesystem::sandbox;
+use log::debug;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// What an archive records about an entry besides its contents, applied
+/// where the OS and the agent's privileges allow
+#[derive(Debug, Clone, Default)]
+pub(super) struct Attributes {
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+    /// Unix user and group id
+    pub owner: Option<(u32, u32)>,
+}
+
+/// Writes archive entries below a root, whatever the archive format
+///
+/// Entry names must be relative and free of `..`; links must point inside
+/// the root. Entries are counted and their contents measured against the
+/// limits as they are written, whatever the archive declared. Links are
+/// only created by [`Extractor::finish`], after every file and folder, so
+/// nothing is ever written through a link from the archive.
+pub(super) struct Extractor<'a> {
+    root: PathBuf,
+    limits: &'a ArchiveConfig,
+    entries: usize,
+    written: u64,
+    hard_links: Vec<(PathBuf, PathBuf)>,
+    symlinks: Vec<(PathBuf, PathBuf, Attributes)>,
+    dirs: Vec<(PathBuf, Attributes)>,
+    selection: Option<Selection>,
+}
+
+impl<'a> Extractor<'a> {
+    pub fn new(root: &Path, limits: &'a ArchiveConfig) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| Error::file_io("Cannot resolve the extraction folder", e))?;
+        Ok(Self {
+            root,
+            limits,
+            entries: 0,
+            written: 0,
+            hard_links: Vec::new(),
+            symlinks: Vec::new(),
+            dirs: Vec::new(),
+            selection: None,
+        })
+    }
+
+    /// Only extract the entries `selection` picks, placed as it says
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = Some(selection);
+        self
+    }
+
+    /// Whether the entry called `name` is extracted at all
+    pub fn selects(&self, name: &Path) -> bool {
+        match &self.selection {
+            Some(selection) => enclosed(name).is_some_and(|name| selection.locate(&name).is_some()),
+            None => true,
+        }
+    }
+
+    /// Bytes of file contents written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Count one more entry called `name`, returning its path below the
+    /// root, or `None` if the selection leaves it out
+    pub fn entry(&mut self, name: &Path) -> Result<Option<PathBuf>> {
+        let relative = match &mut self.selection {
+            Some(selection) => match enclosed(name).and_then(|name| selection.place(&name)) {
+                Some(relative) => relative,
+                None => return Ok(None),
+            },
+            None => enclosed(name).ok_or_else(|| {
+                Error::Archive(format!(
+                    "entry '{}' points outside the target",
+                    name.display()
+                ))
+            })?,
+        };
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(Error::Archive(format!(
+                "more than the limit of {} entries",
+                self.limits.max_entries
+            )));
+        }
+        Ok(Some(relative))
+    }
+
+    pub fn dir(&mut self, relative: &Path, attributes: Attributes) -> Result<()> {
+        if relative.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let path = self.root.join(relative);
+        fs::create_dir_all(&path).map_err(|e| Error::file_io("Failed to create directory", e))?;
+        self.dirs.push((path, attributes));
+        Ok(())
+    }
+
+    /// Write a file of `size` bytes, as declared by the archive, from
+    /// `contents`
+    pub fn file(
+        &mut self,
+        relative: &Path,
+        contents: impl Read,
+        size: u64,
+        attributes: Attributes,
+        job: &mut Job,
+    ) -> Result<()> {
+        let path = self.root.join(relative);
+        self.create_parent(&path)?;
+        job.start_file(&path);
+        let mut file =
+            File::create(&path).map_err(|e| Error::file_io("Failed to create output file", e))?;
+        let allowance = size.min(self.limits.max_total_size - self.written);
+        let copied = io::copy(
+            &mut ProgressReader::new(contents.take(allowance + 1), job),
+            &mut file,
+        )
+        .map_err(|e| archive_error(e, "Failed to extract file"))?;
+        if copied > allowance {
+            return Err(Error::Archive(format!(
+                "entry '{}' is larger than declared or exceeds the limit of {} bytes",
+                relative.display(),
+                self.limits.max_total_size
+            )));
+        }
+        self.written += copied;
+        if let Some(modified) = attributes.modified {
+            if let Err(e) = file.set_modified(modified) {
+                debug!("Cannot set the time of {}: {}", path.display(), e);
+            }
+        }
+        drop(file);
+        apply(&path, &attributes);
+        job.finish_file();
+        Ok(())
+    }
+
+    pub fn symlink(
+        &mut self,
+        relative: &Path,
+        target: &Path,
+        attributes: Attributes,
+    ) -> Result<()> {
+        if escapes(relative, target) {
+            return Err(Error::Archive(format!(
+                "symlink '{}' points outside the target",
+                relative.display()
+            )));
+        }
+        let path = self.root.join(relative);
+        self.create_parent(&path)?;
+        self.symlinks.push((path, target.to_path_buf(), attributes));
+        Ok(())
+    }
+
+    /// A hard link to `target`, named like an entry, i.e. from the root
+    pub fn hard_link(&mut self, relative: &Path, target: &Path) -> Result<()> {
+        let target = enclosed(target).ok_or_else(|| {
+            Error::Archive(format!(
+                "hard link '{}' points outside the target",
+                relative.display()
+            ))
+        })?;
+        let target = match &self.selection {
+            Some(selection) => selection.locate(&target).ok_or_else(|| {
+                Error::Archive(format!(
+                    "hard link '{}' points to '{}', which is not being extracted",
+                    relative.display(),
+                    target.display()
+                ))
+            })?,
+            None => target,
+        };
+        let path = self.root.join(relative);
+        self.create_parent(&path)?;
+        self.hard_links.push((path, self.root.join(target)));
+        Ok(())
+    }
+
+    /// Create the links and apply folder attributes, now that every file
+    /// is in place
+    pub fn finish(self) -> Result<()> {
+        if let Some(missing) = self.selection.as_ref().and_then(Selection::missing) {
+            return Err(super::not_in_archive(missing));
+        }
+
+        // Hard links go first, while no symlink can redirect their target
+        for (path, target) in &self.hard_links {
+            if !fs::symlink_metadata(target).is_ok_and(|meta| meta.is_file()) {
+                return Err(Error::Archive(format!(
+                    "hard link to '{}' has no file to point to",
+                    target.display()
+                )));
+            }
+            fs::hard_link(target, path)
+                .map_err(|e| Error::file_io("Failed to create hard link", e))?;
+        }
+
+        for (path, target, attributes) in &self.symlinks {
+            create_symlink(target, path)?;
+            apply_owner(path, attributes);
+        }
+        // A link into a folder that is itself a link can still lead out
+        for (path, target, _) in &self.symlinks {
+            let base = path.parent().unwrap_or(&self.root);
+            let inside = sandbox::resolve(&base.join(target))
+                .is_ok_and(|resolved| sandbox::within(&resolved, &self.root));
+            if !inside {
+                return Err(Error::Archive(format!(
+                    "symlink '{}' points outside the target",
+                    path.strip_prefix(&self.root).unwrap_or(path).display()
+                )));
+            }
+        }
+
+        // Children first, so a read-only folder is locked last
+        for (path, attributes) in self.dirs.iter().rev() {
+            if let Some(modified) = attributes.modified {
+                let set = File::open(path).and_then(|dir| dir.set_modified(modified));
+                if let Err(e) = set {
+                    debug!("Cannot set the time of {}: {}", path.display(), e);
+                }
+            }
+            apply(path, attributes);
+        }
+        Ok(())
+    }
+
+    /// Create the folders above `path`, which must not be links themselves
+    fn create_parent(&self, path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) => fs::create_dir_all(parent)
+                .map_err(|e| Error::file_io("Failed to create parent directory", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The entries `extract_entries` picks by name
+///
+/// A picked folder brings everything below it. Each pick lands in the root
+/// under its own name, the way `cp` places its sources: `logs/app.log`
+/// becomes `app.log` and `logs/old/` becomes `old/`.
+pub(super) struct Selection {
+    picks: Vec<PathBuf>,
+    found: Vec<bool>,
+}
+
+impl Selection {
+    pub fn new(names: &[String]) -> Result<Self> {
+        let invalid = |name: &str, reason: &str| {
+            Error::file_io(
+                format!("Invalid entry name '{}'", name),
+                io::Error::new(io::ErrorKind::InvalidInput, reason.to_string()),
+            )
+        };
+        if names.is_empty() {
+            return Err(Error::file_io(
+                "No entries selected",
+                io::Error::from(io::ErrorKind::InvalidInput),
+            ));
+        }
+
+        let mut picks: Vec<PathBuf> = Vec::with_capacity(names.len());
+        for name in names {
+            let pick = enclosed(Path::new(name))
+                .filter(|pick| pick.file_name().is_some())
+                .ok_or_else(|| invalid(name, "not a path inside the archive"))?;
+            picks.push(pick);
+        }
+        // A pick inside another one is already covered by it
+        let covered = |pick: &PathBuf| {
+            picks
+                .iter()
+                .any(|other| other != pick && pick.starts_with(other))
+        };
+        let mut kept: Vec<PathBuf> = picks
+            .iter()
+            .filter(|pick| !covered(pick))
+            .cloned()
+            .collect();
+        kept.sort();
+        kept.dedup();
+
+        for (i, pick) in kept.iter().enumerate() {
+            if let Some(other) = kept[..i]
+                .iter()
+                .find(|other| other.file_name() == pick.file_name())
+            {
+                return Err(invalid(
+                    &pick.display().to_string(),
+                    &format!("lands on the same name as '{}'", other.display()),
+                ));
+            }
+        }
+
+        let found = vec![false; kept.len()];
+        Ok(Self { picks: kept, found })
+    }
+
+    /// Where the entry at `name` lands below the root, if it is picked
+    fn locate(&self, name: &Path) -> Option<PathBuf> {
+        self.pick_of(name).map(|i| self.placed(i, name))
+    }
+
+    /// [`Selection::locate`], noting that the pick was found
+    fn place(&mut self, name: &Path) -> Option<PathBuf> {
+        let i = self.pick_of(name)?;
+        self.found[i] = true;
+        Some(self.placed(i, name))
+    }
+
+    /// The first pick that matched no entry
+    fn missing(&self) -> Option<&Path> {
+        self.picks
+            .iter()
+            .zip(&self.found)
+            .find(|(_, found)| !**found)
+            .map(|(pick, _)| pick.as_path())
+    }
+
+    fn pick_of(&self, name: &Path) -> Option<usize> {
+        self.picks.iter().position(|pick| name.starts_with(pick))
+    }
+
+    fn placed(&self, i: usize, name: &Path) -> PathBuf {
+        let parent = self.picks[i].parent().unwrap_or(Path::new(""));
+        name.strip_prefix(parent).unwrap_or(name).to_path_buf()
+    }
+}
+
+/// `name` as a path below the root: only plain names, ignoring `.`
+pub(super) fn enclosed(name: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Whether a link at `link` (relative to the extraction root) pointing to
+/// `target` would lead outside the root
+fn escapes(link: &Path, target: &Path) -> bool {
+    let mut depth = link.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return true,
+            },
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// An error from reading an entry: the archive's own rejection if a
+/// limit was hit mid-stream, else an I/O failure
+pub(super) fn archive_error(e: io::Error, context: &str) -> Error {
+    if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        return *e
+            .into_inner()
+            .and_then(|inner| inner.downcast().ok())
+            .expect("checked to hold an Error");
+    }
+    Error::file_io(context, e)
+}
+
+/// Permissions and owner of a file or folder; failures are expected
+/// without privileges and only logged
+fn apply(path: &Path, attributes: &Attributes) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(mode) = attributes.mode {
+            // Never hand out setuid or setgid bits from an archive
+            let permissions = fs::Permissions::from_mode(mode & 0o1777);
+            if let Err(e) = fs::set_permissions(path, permissions) {
+                debug!("Cannot set the permissions of {}: {}", path.display(), e);
+            }
+        }
+    }
+    apply_owner(path, attributes);
+}
+
+#[cfg(unix)]
+fn apply_owner(path: &Path, attributes: &Attributes) {
+    if let Some((uid, gid)) = attributes.owner {
+        if let Err(e) = std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+            debug!("Cannot set the owner of {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_owner(_path: &Path, _attributes: &Attributes) {}
+
+#[cfg(unix)]
+pub(crate) fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)
+        .map_err(|e| Error::file_io("Failed to create symlink", e))
+}
+
+/// Creating symlinks needs extra privileges on Windows; leave them out
+#[cfg(not(unix))]
+pub(crate) fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    log::warn!(
+        "Skipping symlink {} -> {}",
+        path.display(),
+        target.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enclosed_keeps_only_plain_names() {
+        assert_eq!(
+            enclosed(Path::new("./logs/app.log")),
+            Some(PathBuf::from("logs/app.log"))
+        );
+        assert_eq!(enclosed(Path::new("../x")), None);
+        assert_eq!(enclosed(Path::new("logs/../../x")), None);
+        assert_eq!(enclosed(Path::new("/etc/x")), None);
+    }
+
+    #[test]
+    fn escapes_counts_the_link_own_folder() {
+        assert!(!escapes(Path::new("a/link"), Path::new("../b")));
+        assert!(!escapes(Path::new("a/link"), Path::new("./c/../d")));
+        assert!(escapes(Path::new("a/link"), Path::new("../../b")));
+        assert!(escapes(Path::new("link"), Path::new("..")));
+        assert!(escapes(Path::new("link"), Path::new("/etc/passwd")));
+    }
+}
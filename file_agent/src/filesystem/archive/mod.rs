@@ -0,0 +1,717 @@
+//! Creating, listing and extracting zip and tar archives
+//!
+//! Archives are written to a staging file and extracted into a staging
+//! folder next to their destination, so only complete results ever appear
+//! there. Extraction keeps every entry below its destination and within
+//! the configured [`ArchiveConfig`] limits, whether it unpacks the whole
+//! archive, a few selected entries or a single file read into memory.
+
+mod extract;
+mod tar_format;
+mod zip_format;
+
+use super::glob::{self, Glob};
+use super::progress::{Job, ProgressReader};
+use crate::config::ArchiveConfig;
+use crate::error::{Error, Result};
+use crate::utils::format_bytes;
+pub(crate) use extract::create_symlink;
+use extract::{Extractor, Selection};
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use zip::CompressionMethod;
+
+/// Unpacked sizes up to this are exempt from the compression ratio limit;
+/// small text files routinely compress far better than any sane limit
+const RATIO_EXEMPT_SIZE: u64 = 1024 * 1024;
+
+/// Largest entry `extract_entries` returns in its reply; bigger ones are
+/// extracted into a folder and downloaded from there
+const MAX_INLINE_ENTRY_SIZE: u64 = 32 * 1024 * 1024;
+
+/// How many numbered names [`Conflict::Rename`] tries
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
+
+/// Entries listed per page unless the client asks for fewer
+const DEFAULT_PAGE_SIZE: usize = 1000;
+/// Most entries listed in one page
+const MAX_PAGE_SIZE: usize = 10_000;
+
+/// Compression levels accepted by gzip and xz
+const GZ_XZ_LEVELS: RangeInclusive<i32> = 0..=9;
+/// Compression levels accepted by zstd
+const ZSTD_LEVELS: RangeInclusive<i32> = -7..=22;
+
+/// The archive formats that can be created and extracted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+/// File name endings of each format, longest first
+const EXTENSIONS: [(&str, ArchiveFormat); 8] = [
+    (".tar.gz", ArchiveFormat::TarGz),
+    (".tar.xz", ArchiveFormat::TarXz),
+    (".tar.zst", ArchiveFormat::TarZst),
+    (".tgz", ArchiveFormat::TarGz),
+    (".txz", ArchiveFormat::TarXz),
+    (".tzst", ArchiveFormat::TarZst),
+    (".tar", ArchiveFormat::Tar),
+    (".zip", ArchiveFormat::Zip),
+];
+
+impl ArchiveFormat {
+    /// The format named by `path`'s extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| name.ends_with(extension))
+            .map(|&(_, format)| format)
+    }
+
+    /// The format of the archive at `path`, by its magic bytes or else its
+    /// extension
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut head = Vec::with_capacity(262);
+        File::open(path)
+            .and_then(|file| file.take(262).read_to_end(&mut head))
+            .map_err(|e| Error::file_io(format!("Cannot read {}", path.display()), e))?;
+        Self::sniff(&head)
+            .or_else(|| Self::from_path(path))
+            .ok_or_else(|| Error::Archive(format!("{} is not a supported archive", path.display())))
+    }
+
+    fn sniff(head: &[u8]) -> Option<Self> {
+        const MAGIC: [(&[u8], ArchiveFormat); 5] = [
+            (b"PK\x03\x04", ArchiveFormat::Zip),
+            (b"PK\x05\x06", ArchiveFormat::Zip),
+            (b"\x1f\x8b", ArchiveFormat::TarGz),
+            (b"\xfd7zXZ\x00", ArchiveFormat::TarXz),
+            (b"\x28\xb5\x2f\xfd", ArchiveFormat::TarZst),
+        ];
+        if let Some(&(_, format)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+            return Some(format);
+        }
+        // ustar and GNU tar headers carry their magic at offset 257
+        head.get(257..262)
+            .filter(|magic| *magic == b"ustar")
+            .map(|_| ArchiveFormat::Tar)
+    }
+
+    /// `name` without this format's extension
+    pub(crate) fn stem(self, name: &str) -> &str {
+        let lower = name.to_lowercase();
+        EXTENSIONS
+            .iter()
+            .filter(|&&(_, format)| format == self)
+            .find(|(extension, _)| lower.ends_with(extension) && lower.len() > extension.len())
+            .and_then(|(extension, _)| name.get(..name.len() - extension.len()))
+            .unwrap_or(name)
+    }
+
+    /// Compression levels this format accepts with `compression`
+    fn levels(self, compression: Compression) -> Option<RangeInclusive<i32>> {
+        match self {
+            ArchiveFormat::Zip => compression.levels(),
+            ArchiveFormat::Tar => None,
+            ArchiveFormat::TarGz | ArchiveFormat::TarXz => Some(GZ_XZ_LEVELS),
+            ArchiveFormat::TarZst => Some(ZSTD_LEVELS),
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarXz => "tar.xz",
+            ArchiveFormat::TarZst => "tar.zst",
+        })
+    }
+}
+
+/// How the entries of a new zip are compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Stored,
+    #[default]
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    fn method(self) -> CompressionMethod {
+        match self {
+            Compression::Stored => CompressionMethod::Stored,
+            Compression::Deflate => CompressionMethod::Deflated,
+            Compression::Zstd => CompressionMethod::Zstd,
+        }
+    }
+
+    fn levels(self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Compression::Stored => None,
+            Compression::Deflate => Some(GZ_XZ_LEVELS),
+            Compression::Zstd => Some(ZSTD_LEVELS),
+        }
+    }
+}
+
+/// What to do when an archive's destination already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Conflict {
+    #[default]
+    Fail,
+    /// Write `name (1).zip`, `name (2).zip`, ... instead
+    Rename,
+    Overwrite,
+}
+
+/// How `zip_file` and `archive_create` build an archive
+///
+/// Patterns are [`Glob`]s matched against each entry's path inside the
+/// archive. With `include` patterns only matching files are added; entries
+/// matching an `exclude` pattern are left out, directories with everything
+/// below them. `compression` only applies to zip; tar formats compress the
+/// whole stream as their name says.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ArchiveOptions {
+    pub compression: Compression,
+    /// Algorithm specific; the default level if absent
+    pub level: Option<i32>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub on_conflict: Conflict,
+}
+
+impl ArchiveOptions {
+    /// Check the level and patterns before anything is written
+    pub(crate) fn validate(&self, format: ArchiveFormat) -> Result<()> {
+        if let Some(level) = self.level {
+            let supported = format.levels(self.compression);
+            if !supported
+                .as_ref()
+                .is_some_and(|range| range.contains(&level))
+            {
+                let reason = match supported {
+                    Some(range) => {
+                        format!("{} takes {} to {}", format, range.start(), range.end())
+                    }
+                    None => format!("{} has no levels", format),
+                };
+                return Err(Error::file_io(
+                    format!("Invalid compression level {}", level),
+                    io::Error::new(io::ErrorKind::InvalidInput, reason),
+                ));
+            }
+        }
+        glob::compile(&self.include)?;
+        glob::compile(&self.exclude)?;
+        Ok(())
+    }
+}
+
+/// What kind of thing an archive entry unpacks to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    HardLink,
+    /// Devices, fifos and the like, which are never extracted
+    Other,
+}
+
+/// One entry of an archive, as `list_archive` reports it
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntry {
+    /// Path inside the archive, as stored
+    pub path: String,
+    pub kind: EntryKind,
+    /// Unpacked size in bytes
+    pub size: u64,
+    /// Stored size of a zip entry; tars compress the stream as a whole
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compressed_size: Option<u64>,
+    /// Local modification time as `dd/mm/YYYY, HH:MM:SS`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// Compression method of a zip entry, such as `deflated`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    pub encrypted: bool,
+}
+
+/// A page of an archive's entries
+#[derive(Debug, Clone)]
+pub struct ArchiveListing {
+    pub entries: Vec<ArchiveEntry>,
+    /// Entries in the whole archive
+    pub total: usize,
+}
+
+/// A hidden directory or file next to an operation's destination, removed
+/// again unless it is moved into place
+pub(crate) struct Staging {
+    path: PathBuf,
+    finished: bool,
+}
+
+impl Staging {
+    /// A new staging directory in `parent`
+    pub(crate) fn new(parent: &Path, name: &str) -> Result<Self> {
+        let path = staging_path(parent, name);
+        fs::create_dir(&path).map_err(|e| Error::file_io("Failed to create staging folder", e))?;
+        Ok(Self {
+            path,
+            finished: false,
+        })
+    }
+
+    /// A new staging file in `parent`, opened for writing
+    pub(crate) fn file(parent: &Path, name: &str) -> Result<(Self, File)> {
+        let path = staging_path(parent, name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| Error::file_io("Failed to create staging file", e))?;
+        let staging = Self {
+            path,
+            finished: false,
+        };
+        Ok((staging, file))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the staging path to `destination`, which must not exist
+    pub(crate) fn finish(self, destination: &Path) -> Result<()> {
+        if fs::symlink_metadata(destination).is_ok() {
+            return Err(already_exists(destination));
+        }
+        self.replace(destination)
+    }
+
+    /// Rename the staging path to `destination`, replacing a file there
+    pub(crate) fn replace(mut self, destination: &Path) -> Result<()> {
+        fs::rename(&self.path, destination)
+            .map_err(|e| Error::file_io("Failed to move the result into place", e))?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let removed = if self.path.is_dir() {
+            fs::remove_dir_all(&self.path)
+        } else {
+            fs::remove_file(&self.path)
+        };
+        if let Err(e) = removed {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+fn staging_path(parent: &Path, name: &str) -> PathBuf {
+    let tag: u32 = rand::thread_rng().gen();
+    parent.join(format!(".{}.{:08x}.partial", name, tag))
+}
+
+pub(crate) fn already_exists(path: &Path) -> Error {
+    Error::file_io(
+        format!("Cannot write {}", path.display()),
+        io::Error::from(io::ErrorKind::AlreadyExists),
+    )
+}
+
+/// Where an archive for `destination` goes under `conflict`, or an error
+/// if it must not be written
+pub(crate) fn claim_destination(destination: &Path, conflict: Conflict) -> Result<PathBuf> {
+    if fs::symlink_metadata(destination).is_err() {
+        return Ok(destination.to_path_buf());
+    }
+    match conflict {
+        Conflict::Fail => Err(already_exists(destination)),
+        Conflict::Overwrite if destination.is_dir() => Err(already_exists(destination)),
+        Conflict::Overwrite => Ok(destination.to_path_buf()),
+        Conflict::Rename => {
+            let name = destination
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            // `logs.tar.gz` becomes `logs (1).tar.gz`
+            let stem = ArchiveFormat::from_path(destination)
+                .map(|format| format.stem(&name))
+                .unwrap_or_else(|| {
+                    Path::new(name.as_ref())
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .unwrap_or(&name)
+                });
+            let extension = &name[stem.len()..];
+            (1..=MAX_RENAME_ATTEMPTS)
+                .map(|n| destination.with_file_name(format!("{} ({}){}", stem, n, extension)))
+                .find(|candidate| fs::symlink_metadata(candidate).is_err())
+                .ok_or_else(|| already_exists(destination))
+        }
+    }
+}
+
+/// Write `paths` as a `format` archive to `file`, leaving out `skip` (the
+/// archive itself, should it land inside one of the folders)
+pub(crate) fn write_archive(
+    file: File,
+    format: ArchiveFormat,
+    paths: &[String],
+    options: &ArchiveOptions,
+    skip: &Path,
+    job: &mut Job,
+) -> Result<()> {
+    let include = glob::compile(&options.include)?;
+    let exclude = glob::compile(&options.exclude)?;
+
+    let mut entries = Vec::new();
+    for path in paths {
+        job.check_cancelled()?;
+        collect(Path::new(path), &include, &exclude, skip, &mut entries)?;
+    }
+    for entry in &entries {
+        if let Source::File(size) = entry.source {
+            job.plan(1, size);
+        }
+    }
+
+    match format {
+        ArchiveFormat::Zip => zip_format::write(file, &entries, options, job),
+        _ => tar_format::write(file, format, &entries, options.level, job),
+    }
+}
+
+/// Extract the `format` archive at `source` into `destination`
+pub(crate) fn extract_archive(
+    source: &Path,
+    format: ArchiveFormat,
+    destination: &Path,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<()> {
+    let extractor = Extractor::new(destination, limits)?;
+    run_extractor(source, format, extractor, limits, job)
+}
+
+/// Extract the entries named `names` from the `format` archive at `source`
+/// into `destination`, each under its own name; a folder brings everything
+/// below it
+pub(crate) fn extract_selected(
+    source: &Path,
+    format: ArchiveFormat,
+    names: &[String],
+    destination: &Path,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<()> {
+    let selection = Selection::new(names)?;
+    let extractor = Extractor::new(destination, limits)?.with_selection(selection);
+    run_extractor(source, format, extractor, limits, job)
+}
+
+fn run_extractor(
+    source: &Path,
+    format: ArchiveFormat,
+    mut extractor: Extractor,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<()> {
+    let file = open_archive(source)?;
+    match format {
+        ArchiveFormat::Zip => zip_format::extract(file, &mut extractor, limits, job)?,
+        _ => tar_format::extract(file, format, &mut extractor, limits, job)?,
+    }
+    extractor.finish()
+}
+
+/// The contents of the file entry called `name` in the `format` archive at
+/// `source`, without writing anything to disk
+pub(crate) fn read_entry(
+    source: &Path,
+    format: ArchiveFormat,
+    name: &str,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<Vec<u8>> {
+    let name = extract::enclosed(Path::new(name))
+        .filter(|name| name.file_name().is_some())
+        .ok_or_else(|| not_in_archive(Path::new(name)))?;
+    let file = open_archive(source)?;
+    match format {
+        ArchiveFormat::Zip => zip_format::read(file, &name, limits, job),
+        _ => tar_format::read(file, format, &name, limits, job),
+    }
+}
+
+/// The entries of the `format` archive at `source` from `offset` on, at
+/// most `limit` of them
+pub(crate) fn list_archive(
+    source: &Path,
+    format: ArchiveFormat,
+    offset: usize,
+    limit: Option<usize>,
+    job: &Job,
+) -> Result<ArchiveListing> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let file = open_archive(source)?;
+    match format {
+        ArchiveFormat::Zip => zip_format::list(file, offset, limit, job),
+        _ => tar_format::list(file, format, offset, limit, job),
+    }
+}
+
+fn open_archive(source: &Path) -> Result<File> {
+    File::open(source).map_err(|e| Error::file_io("Failed to open archive", e))
+}
+
+/// Read an entry of `size` bytes, as declared by the archive, into memory
+fn read_limited(
+    contents: impl Read,
+    size: u64,
+    name: &Path,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<Vec<u8>> {
+    if size > MAX_INLINE_ENTRY_SIZE {
+        return Err(Error::file_io(
+            format!("Cannot return '{}' in the reply", name.display()),
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the entry is {}, over the {} limit; extract it into a target folder and download it from there",
+                    format_bytes(size),
+                    format_bytes(MAX_INLINE_ENTRY_SIZE)
+                ),
+            ),
+        ));
+    }
+    let too_large = || {
+        Error::Archive(format!(
+            "entry '{}' is larger than declared or exceeds the limit of {} bytes",
+            name.display(),
+            limits.max_total_size
+        ))
+    };
+    if size > limits.max_total_size {
+        return Err(too_large());
+    }
+
+    job.plan(1, size);
+    job.start_file(name);
+    // The declared size is only trusted as far as the limit allows
+    let mut buffer = Vec::with_capacity(size.min(RATIO_EXEMPT_SIZE) as usize);
+    ProgressReader::new(contents.take(size + 1), job)
+        .read_to_end(&mut buffer)
+        .map_err(|e| extract::archive_error(e, "Failed to read entry"))?;
+    if buffer.len() as u64 > size {
+        return Err(too_large());
+    }
+    job.finish_file();
+    Ok(buffer)
+}
+
+fn not_in_archive(name: &Path) -> Error {
+    Error::file_io(
+        format!("Cannot extract '{}'", name.display()),
+        io::Error::new(io::ErrorKind::NotFound, "no such entry in the archive"),
+    )
+}
+
+fn not_a_file(name: &Path) -> Error {
+    Error::file_io(
+        format!("Cannot read '{}'", name.display()),
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only file entries can be returned directly; extract others to a folder",
+        ),
+    )
+}
+
+/// One thing to add to a new archive
+enum Source {
+    Dir,
+    File(u64),
+    Symlink(PathBuf),
+}
+
+struct Entry {
+    path: PathBuf,
+    /// `/`-separated path inside the archive
+    name: String,
+    source: Source,
+    metadata: Metadata,
+}
+
+/// Gather the entries for `root` and, for a folder, everything below it
+fn collect(
+    root: &Path,
+    include: &[Glob],
+    exclude: &[Glob],
+    skip: &Path,
+    entries: &mut Vec<Entry>,
+) -> Result<()> {
+    let root_name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::FileSystem(format!("Cannot archive {}", root.display())))?;
+    let name_of = |path: &Path| {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        relative
+            .components()
+            .fold(root_name.clone(), |mut name, component| {
+                name.push('/');
+                name.push_str(&component.as_os_str().to_string_lossy());
+                name
+            })
+    };
+
+    let walk = WalkDir::new(root).into_iter().filter_entry(|entry| {
+        entry.path() != skip && !glob::any_match(exclude, &name_of(entry.path()))
+    });
+    for walked in walk {
+        let walked =
+            walked.map_err(|e| Error::FileSystem(format!("Walk directory error: {}", e)))?;
+        let path = walked.path();
+        let name = name_of(path);
+        let metadata = walked
+            .metadata()
+            .map_err(|e| Error::FileSystem(format!("Cannot read {}: {}", path.display(), e)))?;
+        let source = if metadata.is_dir() {
+            if !include.is_empty() {
+                continue;
+            }
+            Source::Dir
+        } else if !include.is_empty() && !glob::any_match(include, &name) {
+            continue;
+        } else if metadata.file_type().is_symlink() {
+            let target = fs::read_link(path)
+                .map_err(|e| Error::file_io(format!("Cannot read link {}", path.display()), e))?;
+            Source::Symlink(target)
+        } else {
+            Source::File(metadata.len())
+        };
+        entries.push(Entry {
+            path: path.to_path_buf(),
+            name,
+            source,
+            metadata,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+
+    #[test]
+    fn formats_come_from_extensions_and_magic_bytes() {
+        let cases = [
+            ("logs.tar.gz", Some(ArchiveFormat::TarGz)),
+            ("LOGS.TZST", Some(ArchiveFormat::TarZst)),
+            ("logs.tar", Some(ArchiveFormat::Tar)),
+            ("logs.gz", None),
+        ];
+        for (name, format) in cases {
+            assert_eq!(
+                ArchiveFormat::from_path(Path::new(name)),
+                format,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(ArchiveFormat::TarGz.stem("logs.tar.gz"), "logs");
+        assert_eq!(ArchiveFormat::TarGz.stem(".tar.gz"), ".tar.gz");
+
+        let dir = TestDir::new("archive-sniff");
+        let misnamed = dir.write("download.bin", b"\x28\xb5\x2f\xfd rest of the frame");
+        assert_eq!(
+            ArchiveFormat::detect(&misnamed).unwrap(),
+            ArchiveFormat::TarZst
+        );
+        let unknown = dir.write("notes.txt", "just text");
+        assert!(ArchiveFormat::detect(&unknown).is_err());
+    }
+
+    #[test]
+    fn renamed_destination_keeps_the_whole_extension() {
+        let dir = TestDir::new("archive-rename");
+        let destination = dir.write("logs.tar.gz", "old");
+        assert_eq!(
+            claim_destination(&destination, Conflict::Rename).unwrap(),
+            dir.join("logs (1).tar.gz")
+        );
+        // Even overwriting never replaces a folder
+        std::fs::create_dir(dir.join("backup.tar")).unwrap();
+        assert!(claim_destination(&dir.join("backup.tar"), Conflict::Overwrite).is_err());
+    }
+
+    #[test]
+    fn levels_are_checked_against_the_format() {
+        let level = |level| ArchiveOptions {
+            level: Some(level),
+            ..ArchiveOptions::default()
+        };
+        assert!(level(9).validate(ArchiveFormat::TarGz).is_ok());
+        assert!(level(10).validate(ArchiveFormat::TarXz).is_err());
+        assert!(level(19).validate(ArchiveFormat::TarZst).is_ok());
+        assert!(level(1).validate(ArchiveFormat::Tar).is_err());
+    }
+
+    #[test]
+    fn refuses_to_read_large_entry_into_the_reply() {
+        let dir = TestDir::new("inline-entry");
+        // Only the header: the declared size is refused before any data is read
+        let mut header = tar::Header::new_gnu();
+        header.set_path("big.log").unwrap();
+        header.set_size(MAX_INLINE_ENTRY_SIZE + 1);
+        header.set_cksum();
+        let source = dir.write("logs.tar", header.as_bytes());
+
+        let error = read_entry(
+            &source,
+            ArchiveFormat::Tar,
+            "big.log",
+            &ArchiveConfig::default(),
+            &mut Job::detached(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("target folder"), "{}", error);
+    }
+}
@@ -0,0 +1,475 @@
+use super::extract::{archive_error, enclosed, Attributes, Extractor};
+use super::{
+    not_a_file, not_in_archive, read_limited, ArchiveEntry, ArchiveFormat, ArchiveListing, Entry,
+    EntryKind, Source, RATIO_EXEMPT_SIZE,
+};
+use crate::config::ArchiveConfig;
+use crate::error::{Error, Result};
+use crate::filesystem::progress::{Job, ProgressReader};
+use crate::filesystem::utils::format_local_time;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::debug;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+use tar::{Builder, EntryType, Header, HeaderMode};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+/// Default xz preset, as the `xz` tool uses
+const XZ_LEVEL: u32 = 6;
+
+/// Write `entries` as a `format` tar to `file`, compressed at `level`
+pub(super) fn write(
+    file: File,
+    format: ArchiveFormat,
+    entries: &[Entry],
+    level: Option<i32>,
+    job: &mut Job,
+) -> Result<()> {
+    let finished = match format {
+        ArchiveFormat::TarGz => {
+            let level = level.map_or(flate2::Compression::default(), |level| {
+                flate2::Compression::new(level as u32)
+            });
+            write_tar(GzEncoder::new(file, level), entries, job)?.finish()
+        }
+        ArchiveFormat::TarXz => {
+            let level = level.map_or(XZ_LEVEL, |level| level as u32);
+            write_tar(XzEncoder::new(file, level), entries, job)?.finish()
+        }
+        ArchiveFormat::TarZst => {
+            let encoder = zstd::Encoder::new(file, level.unwrap_or(0))
+                .map_err(|e| Error::file_io("Failed to start compressing", e))?;
+            write_tar(encoder, entries, job)?.finish()
+        }
+        _ => Ok(write_tar(file, entries, job)?),
+    };
+    finished
+        .map(drop)
+        .map_err(|e| Error::file_io("Failed to finish compressing", e))
+}
+
+fn write_tar<W: Write>(out: W, entries: &[Entry], job: &mut Job) -> Result<W> {
+    let mut tar = Builder::new(out);
+    for entry in entries {
+        job.check_cancelled()?;
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&entry.metadata, HeaderMode::Complete);
+        let written = match &entry.source {
+            Source::Dir => tar.append_data(&mut header, format!("{}/", entry.name), io::empty()),
+            Source::Symlink(target) => tar.append_link(&mut header, &entry.name, target),
+            Source::File(size) => {
+                job.start_file(&entry.path);
+                let source = File::open(&entry.path).map_err(|e| {
+                    Error::file_io(format!("Failed to read file {}", entry.path.display()), e)
+                })?;
+                // A file that grew since it was listed is cut at its listed size
+                let written = tar.append_data(
+                    &mut header,
+                    &entry.name,
+                    ProgressReader::new(source.take(*size), job),
+                );
+                job.finish_file();
+                written
+            }
+        };
+        written.map_err(|e| Error::file_io("Failed to write to archive", e))?;
+    }
+    tar.into_inner()
+        .map_err(|e| Error::file_io("Failed to finish archive", e))
+}
+
+/// One page of the entries of the `format` tar in `file`. A tar has no
+/// index, so the whole stream is read to count them.
+pub(super) fn list(
+    file: File,
+    format: ArchiveFormat,
+    offset: usize,
+    limit: usize,
+    job: &Job,
+) -> Result<ArchiveListing> {
+    let stream = open(file, format, &Rc::default())?;
+    let mut archive = tar::Archive::new(stream);
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for entry in archive
+        .entries()
+        .map_err(|e| Error::file_io("Failed to read archive", e))?
+    {
+        job.check_cancelled()?;
+        let entry = entry.map_err(|e| Error::file_io("Failed to read archive", e))?;
+        total += 1;
+        if total <= offset || entries.len() >= limit {
+            continue;
+        }
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            EntryType::Directory => EntryKind::Dir,
+            EntryType::Regular | EntryType::Continuous => EntryKind::File,
+            EntryType::Symlink => EntryKind::Symlink,
+            EntryType::Link => EntryKind::HardLink,
+            _ => EntryKind::Other,
+        };
+        entries.push(ArchiveEntry {
+            path: String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+            kind,
+            size: entry.size(),
+            compressed_size: None,
+            date: attributes(header).modified.map(format_local_time),
+            method: None,
+            encrypted: false,
+        });
+    }
+    Ok(ArchiveListing { entries, total })
+}
+
+/// Extract the entries of the `format` tar in `file` that `extractor`
+/// selects.
+///
+/// A tar lists its entries as it goes, so the limits are enforced while
+/// reading: the [`Extractor`] counts entries and bytes, and the ratio of
+/// bytes unpacked to bytes read from the file is checked on every read.
+pub(super) fn extract(
+    file: File,
+    format: ArchiveFormat,
+    extractor: &mut Extractor,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<()> {
+    let compressed = Rc::new(Cell::new(0));
+    let stream = open(file, format, &compressed)?;
+    let mut archive = tar::Archive::new(stream);
+    let entries = archive
+        .entries()
+        .map_err(|e| Error::file_io("Failed to read archive", e))?;
+    for entry in entries {
+        job.check_cancelled()?;
+        let mut entry = entry.map_err(|e| archive_error(e, "Failed to read archive"))?;
+        let header = entry.header();
+        let kind = header.entry_type();
+        let attributes = attributes(header);
+        let name = entry
+            .path()
+            .map_err(|e| Error::file_io("Invalid entry name", e))?
+            .into_owned();
+        let Some(relative) = extractor.entry(&name)? else {
+            continue;
+        };
+
+        match kind {
+            EntryType::Directory => extractor.dir(&relative, attributes)?,
+            EntryType::Regular | EntryType::Continuous => {
+                let size = entry.size();
+                job.plan(1, size);
+                let guarded = RatioGuard {
+                    inner: &mut entry,
+                    compressed: &compressed,
+                    unpacked: extractor.written(),
+                    max_ratio: limits.max_ratio,
+                };
+                extractor.file(&relative, guarded, size, attributes, job)?;
+            }
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry
+                    .link_name()
+                    .map_err(|e| Error::file_io("Invalid link target", e))?
+                    .ok_or_else(|| {
+                        Error::Archive(format!("link '{}' has no target", name.display()))
+                    })?
+                    .into_owned();
+                if kind == EntryType::Symlink {
+                    extractor.symlink(&relative, &target, attributes)?;
+                } else {
+                    extractor.hard_link(&relative, &target)?;
+                }
+            }
+            other => debug!("Skipping {:?} entry {}", other, name.display()),
+        }
+    }
+    Ok(())
+}
+
+/// The contents of the file entry called `name` in the `format` tar in
+/// `file`, held to the limits
+pub(super) fn read(
+    file: File,
+    format: ArchiveFormat,
+    name: &Path,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<Vec<u8>> {
+    let compressed = Rc::new(Cell::new(0));
+    let stream = open(file, format, &compressed)?;
+    let mut archive = tar::Archive::new(stream);
+    for entry in archive
+        .entries()
+        .map_err(|e| Error::file_io("Failed to read archive", e))?
+    {
+        job.check_cancelled()?;
+        let mut entry = entry.map_err(|e| archive_error(e, "Failed to read archive"))?;
+        let path = entry
+            .path()
+            .map_err(|e| Error::file_io("Invalid entry name", e))?;
+        if enclosed(&path).as_deref() != Some(name) {
+            continue;
+        }
+        if !matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::Continuous
+        ) {
+            return Err(not_a_file(name));
+        }
+
+        let size = entry.size();
+        let guarded = RatioGuard {
+            inner: &mut entry,
+            compressed: &compressed,
+            unpacked: 0,
+            max_ratio: limits.max_ratio,
+        };
+        return read_limited(guarded, size, name, limits, job);
+    }
+    Err(not_in_archive(name))
+}
+
+/// The tar stream of the `format` archive in `file`, adding the bytes read
+/// from the file itself to `compressed`
+fn open(file: File, format: ArchiveFormat, compressed: &Rc<Cell<u64>>) -> Result<Box<dyn Read>> {
+    let counted = Counted {
+        inner: BufReader::new(file),
+        count: Rc::clone(compressed),
+    };
+    Ok(match format {
+        ArchiveFormat::TarGz => Box::new(GzDecoder::new(counted)),
+        ArchiveFormat::TarXz => Box::new(XzDecoder::new(counted)),
+        ArchiveFormat::TarZst => Box::new(
+            zstd::Decoder::new(counted)
+                .map_err(|e| Error::file_io("Failed to start decompressing", e))?,
+        ),
+        _ => Box::new(counted),
+    })
+}
+
+fn attributes(header: &Header) -> Attributes {
+    let owner = match (header.uid(), header.gid()) {
+        (Ok(uid), Ok(gid)) => u32::try_from(uid).ok().zip(u32::try_from(gid).ok()),
+        _ => None,
+    };
+    Attributes {
+        mode: header.mode().ok().map(|mode| mode & 0o7777),
+        modified: header
+            .mtime()
+            .ok()
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        owner,
+    }
+}
+
+/// Counts the bytes read from the archive file
+struct Counted<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Fails an entry's read once everything unpacked so far exceeds the
+/// ratio limit against the archive bytes read
+struct RatioGuard<'a, R> {
+    inner: R,
+    compressed: &'a Cell<u64>,
+    unpacked: u64,
+    max_ratio: u64,
+}
+
+impl<R: Read> Read for RatioGuard<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.unpacked += n as u64;
+        let compressed = self.compressed.get().max(1);
+        if self.unpacked > RATIO_EXEMPT_SIZE && self.unpacked / compressed > self.max_ratio {
+            return Err(io::Error::other(Error::Archive(format!(
+                "unpacks {} bytes from {}, beyond the ratio limit of {}",
+                self.unpacked, compressed, self.max_ratio
+            ))));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::operations::{handle_create_archive, handle_extract_archive};
+    use crate::filesystem::ArchiveOptions;
+    use crate::utils::test_dir::TestDir;
+
+    /// A tar entry, named byte for byte as given: the tar crate refuses to
+    /// write the names a hostile archive uses
+    fn entry(tar: &mut Builder<Vec<u8>>, name: &str, kind: EntryType, data: &[u8], link: &str) {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        if !link.is_empty() {
+            header.set_link_name(link).unwrap();
+        }
+        header.set_cksum();
+        tar.append(&header, data).unwrap();
+    }
+
+    fn tar(add: impl FnOnce(&mut Builder<Vec<u8>>)) -> Vec<u8> {
+        let mut tar = Builder::new(Vec::new());
+        add(&mut tar);
+        tar.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Extract `archive`, named `name`, with `limits`, returning the error
+    /// and checking that nothing was left in the target folder
+    fn refused(name: &str, archive: Vec<u8>, limits: &ArchiveConfig) -> Error {
+        let dir = TestDir::new("tar-refused");
+        let source = dir.write(&format!("in/{}", name), archive);
+        let target = dir.join("out");
+        let result = handle_extract_archive(
+            &source.display().to_string(),
+            &target.display().to_string(),
+            None,
+            limits,
+            &mut Job::detached(),
+        );
+        let leftovers = std::fs::read_dir(&target).map_or(0, |entries| entries.count());
+        assert_eq!(leftovers, 0, "extraction left files behind");
+        assert!(!dir.join("outside").exists());
+        result.unwrap_err()
+    }
+
+    #[test]
+    fn created_archives_extract_to_the_same_tree() {
+        let dir = TestDir::new("tar-round-trip");
+        dir.write("logs/app.log", "app");
+        dir.write("logs/2024/old.log", "old");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("app.log", dir.join("logs/latest")).unwrap();
+        let sources = [dir.join("logs").display().to_string()];
+
+        for (name, format) in [
+            ("bundle.tar", ArchiveFormat::Tar),
+            ("bundle.tgz", ArchiveFormat::TarGz),
+            ("bundle.tar.xz", ArchiveFormat::TarXz),
+            ("bundle.tar.zst", ArchiveFormat::TarZst),
+        ] {
+            let (archive, written_as) = handle_create_archive(
+                &sources,
+                &dir.join(name),
+                None,
+                &ArchiveOptions::default(),
+                &mut Job::detached(),
+            )
+            .unwrap();
+            assert_eq!(written_as, format, "{}", name);
+
+            let target = dir.join(format!("out-{}", format));
+            let (folder, detected) = handle_extract_archive(
+                &archive.display().to_string(),
+                &target.display().to_string(),
+                None,
+                &ArchiveConfig::default(),
+                &mut Job::detached(),
+            )
+            .unwrap();
+            assert_eq!(detected, format, "{}", name);
+            assert_eq!(folder, target.join("bundle"));
+            let read = |path: &str| std::fs::read_to_string(folder.join(path)).unwrap();
+            assert_eq!(read("logs/app.log"), "app");
+            assert_eq!(read("logs/2024/old.log"), "old");
+            #[cfg(unix)]
+            assert_eq!(
+                std::fs::read_link(folder.join("logs/latest")).unwrap(),
+                Path::new("app.log")
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_entries_outside_the_target() {
+        let limits = ArchiveConfig::default();
+        for name in ["../outside", "/etc/outside", "logs/../../outside"] {
+            let archive = tar(|t| {
+                entry(t, "fine.txt", EntryType::Regular, b"fine", "");
+                entry(t, name, EntryType::Regular, b"escaped", "");
+            });
+            let error = refused("bad.tar", archive, &limits);
+            assert!(matches!(error, Error::Archive(_)), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn refuses_links_pointing_outside_the_target() {
+        let limits = ArchiveConfig::default();
+        let archive = tar(|t| entry(t, "link", EntryType::Symlink, b"", "../../outside"));
+        let error = refused("bad.tar", archive, &limits);
+        assert!(error.to_string().contains("points outside"), "{}", error);
+
+        // A link inside that leads through another link out of the target
+        #[cfg(unix)]
+        {
+            let archive = tar(|t| {
+                entry(t, "here", EntryType::Symlink, b"", ".");
+                entry(t, "up", EntryType::Symlink, b"", "here/..");
+            });
+            let error = refused("bad.tar", archive, &limits);
+            assert!(
+                error.to_string().contains("'up' points outside"),
+                "{}",
+                error
+            );
+        }
+
+        let archive = tar(|t| entry(t, "hard", EntryType::Link, b"", "../outside"));
+        let error = refused("bad.tar", archive, &limits);
+        assert!(error.to_string().contains("points outside"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_more_entries_than_the_limit() {
+        let limits = ArchiveConfig {
+            max_entries: 2,
+            ..ArchiveConfig::default()
+        };
+        let archive = tar(|t| {
+            for name in ["a", "b", "c"] {
+                entry(t, name, EntryType::Regular, b"x", "");
+            }
+        });
+        let error = refused("many.tar", archive, &limits);
+        assert!(error.to_string().contains("limit of 2"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_entry_beyond_the_compression_ratio() {
+        let zeros = vec![0; 16 * 1024 * 1024];
+        let archive = gzip(&tar(|t| {
+            entry(t, "zeros.bin", EntryType::Regular, &zeros, "")
+        }));
+        let error = refused("bomb.tar.gz", archive, &ArchiveConfig::default());
+        assert!(error.to_string().contains("ratio limit"), "{}", error);
+    }
+}
@@ -0,0 +1,392 @@
+use super::extract::{enclosed, Attributes, Extractor};
+use super::{
+    not_a_file, not_in_archive, read_limited, ArchiveEntry, ArchiveListing, ArchiveOptions, Entry,
+    EntryKind, Source, RATIO_EXEMPT_SIZE,
+};
+use crate::config::ArchiveConfig;
+use crate::error::{Error, Result};
+use crate::filesystem::progress::{Job, ProgressReader};
+use crate::filesystem::utils::format_local_time;
+use chrono::{Datelike, Local, NaiveDate, TimeZone, Timelike};
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::{DateTime, ZipArchive, ZipWriter};
+
+/// Longest symlink target read from an archive
+const MAX_LINK_TARGET: u64 = 4096;
+
+pub(super) fn write(
+    file: File,
+    entries: &[Entry],
+    options: &ArchiveOptions,
+    job: &mut Job,
+) -> Result<()> {
+    let base = FileOptions::default()
+        .compression_method(options.compression.method())
+        .compression_level(options.level);
+
+    let mut zip = ZipWriter::new(file);
+    for entry in entries {
+        job.check_cancelled()?;
+        let options = entry_options(base, &entry.metadata);
+        match &entry.source {
+            Source::Dir => zip.add_directory(format!("{}/", entry.name), options)?,
+            Source::Symlink(target) => {
+                zip.add_symlink(&entry.name, target.to_string_lossy(), options)?
+            }
+            Source::File(_) => {
+                job.start_file(&entry.path);
+                let source = File::open(&entry.path).map_err(|e| {
+                    Error::file_io(format!("Failed to read file {}", entry.path.display()), e)
+                })?;
+                zip.start_file(&entry.name, options)?;
+                io::copy(&mut ProgressReader::new(source, job), &mut zip)
+                    .map_err(|e| Error::file_io("Failed to write to zip", e))?;
+                job.finish_file();
+            }
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// One page of the entries of the zip in `file`, from the central
+/// directory alone
+pub(super) fn list(file: File, offset: usize, limit: usize, job: &Job) -> Result<ArchiveListing> {
+    let mut archive = open(file)?;
+    let total = archive.len();
+    let mut entries = Vec::new();
+    for i in offset..total.min(offset.saturating_add(limit)) {
+        job.check_cancelled()?;
+        // Only the flag is checked here, before any of the entry is read
+        let encrypted = matches!(
+            archive.by_index(i),
+            Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED))
+        );
+        let entry = archive.by_index_raw(i)?;
+        let kind = if entry.is_dir() {
+            EntryKind::Dir
+        } else if is_symlink(&entry) {
+            EntryKind::Symlink
+        } else {
+            EntryKind::File
+        };
+        entries.push(ArchiveEntry {
+            path: entry.name().to_string(),
+            kind,
+            size: entry.size(),
+            compressed_size: Some(entry.compressed_size()),
+            date: system_time(entry.last_modified()).map(format_local_time),
+            method: Some(entry.compression().to_string().to_lowercase()),
+            encrypted,
+        });
+    }
+    Ok(ArchiveListing { entries, total })
+}
+
+/// Extract the entries of the zip in `file` that `extractor` selects.
+///
+/// The central directory declares every size up front, so an archive
+/// over the limits is refused before anything is written; the
+/// [`Extractor`] then holds each entry to what it declared.
+pub(super) fn extract(
+    file: File,
+    extractor: &mut Extractor,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<()> {
+    let mut archive = open(file)?;
+
+    let mut selected = 0;
+    let mut declared = 0u64;
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if !extractor.selects(Path::new(entry.name())) {
+            continue;
+        }
+        selected += 1;
+        check_ratio(&entry, limits)?;
+        declared = declared.saturating_add(entry.size());
+        if !entry.is_dir() {
+            job.plan(1, entry.size());
+        }
+    }
+    if selected > limits.max_entries {
+        return Err(Error::Archive(format!(
+            "{} entries, more than the limit of {}",
+            selected, limits.max_entries
+        )));
+    }
+    if declared > limits.max_total_size {
+        return Err(Error::Archive(format!(
+            "unpacks to {} bytes, more than the limit of {}",
+            declared, limits.max_total_size
+        )));
+    }
+
+    for i in 0..archive.len() {
+        job.check_cancelled()?;
+        let mut entry = archive.by_index(i)?;
+        let Some(relative) = extractor.entry(Path::new(entry.name()))? else {
+            continue;
+        };
+        let attributes = Attributes {
+            mode: entry.unix_mode().map(|mode| mode & 0o7777),
+            modified: system_time(entry.last_modified()),
+            owner: None,
+        };
+
+        if entry.is_dir() {
+            extractor.dir(&relative, attributes)?;
+        } else if is_symlink(&entry) {
+            let mut target = String::new();
+            (&mut entry)
+                .take(MAX_LINK_TARGET)
+                .read_to_string(&mut target)
+                .map_err(|e| Error::file_io("Failed to read symlink entry", e))?;
+            extractor.symlink(&relative, Path::new(&target), attributes)?;
+        } else {
+            let size = entry.size();
+            extractor.file(&relative, &mut entry, size, attributes, job)?;
+        }
+    }
+    Ok(())
+}
+
+/// The contents of the file entry called `name` in the zip in `file`,
+/// held to the limits
+pub(super) fn read(
+    file: File,
+    name: &Path,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<Vec<u8>> {
+    let mut archive = open(file)?;
+    let index = (0..archive.len())
+        .find(|&i| {
+            archive.by_index_raw(i).is_ok_and(|entry| {
+                enclosed(Path::new(entry.name())).is_some_and(|entry| entry == name)
+            })
+        })
+        .ok_or_else(|| not_in_archive(name))?;
+
+    let entry = archive.by_index_raw(index)?;
+    if entry.is_dir() || is_symlink(&entry) {
+        return Err(not_a_file(name));
+    }
+    check_ratio(&entry, limits)?;
+    let size = entry.size();
+    drop(entry);
+
+    let entry = archive.by_index(index)?;
+    read_limited(entry, size, name, limits, job)
+}
+
+fn open(file: File) -> Result<ZipArchive<File>> {
+    ZipArchive::new(file)
+        .map_err(|e| Error::FileSystem(format!("Failed to read zip archive: {}", e)))
+}
+
+/// `base` with the modification time and permissions of `metadata`
+fn entry_options(base: FileOptions, metadata: &Metadata) -> FileOptions {
+    let mut options = base.large_file(metadata.len() >= u64::from(u32::MAX));
+    if let Some(modified) = metadata.modified().ok().and_then(dos_time) {
+        options = options.last_modified_time(modified);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(metadata.permissions().mode());
+    }
+    options
+}
+
+/// `time` in local time, as zip stores it; `None` before 1980
+fn dos_time(time: SystemTime) -> Option<DateTime> {
+    let local = chrono::DateTime::<Local>::from(time);
+    DateTime::from_date_and_time(
+        u16::try_from(local.year()).ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    )
+    .ok()
+}
+
+/// The instant a zip's local `time` stands for
+fn system_time(time: DateTime) -> Option<SystemTime> {
+    let local =
+        NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?
+            .and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )?;
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(SystemTime::from)
+}
+
+fn check_ratio(entry: &ZipFile, limits: &ArchiveConfig) -> Result<()> {
+    let size = entry.size();
+    if size > RATIO_EXEMPT_SIZE && size / entry.compressed_size().max(1) > limits.max_ratio {
+        return Err(Error::Archive(format!(
+            "entry '{}' compresses {} bytes into {}, beyond the ratio limit of {}",
+            entry.name(),
+            size,
+            entry.compressed_size(),
+            limits.max_ratio
+        )));
+    }
+    Ok(())
+}
+
+fn is_symlink(entry: &ZipFile) -> bool {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFLNK: u32 = 0o120000;
+    entry
+        .unix_mode()
+        .is_some_and(|mode| mode & S_IFMT == S_IFLNK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::operations::{handle_extract_archive, handle_zip_files};
+    use crate::filesystem::{ArchiveFormat, Compression};
+    use crate::utils::test_dir::TestDir;
+    use std::io::{Cursor, Write};
+
+    /// A zip built in memory by `add`
+    fn zip(add: impl FnOnce(&mut ZipWriter<Cursor<Vec<u8>>>)) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        add(&mut zip);
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn file(zip: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, contents: &[u8]) {
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(contents).unwrap();
+    }
+
+    /// Extract `archive` with `limits`, returning the error and checking
+    /// that nothing was left in the target folder
+    fn refused(archive: Vec<u8>, limits: &ArchiveConfig) -> Error {
+        let dir = TestDir::new("zip-refused");
+        let source = dir.write("in/bad.zip", archive);
+        let target = dir.join("out");
+        let result = handle_extract_archive(
+            &source.display().to_string(),
+            &target.display().to_string(),
+            None,
+            limits,
+            &mut Job::detached(),
+        );
+        let leftovers = std::fs::read_dir(&target).map_or(0, |entries| entries.count());
+        assert_eq!(leftovers, 0, "extraction left files behind");
+        assert!(!dir.join("outside").exists());
+        result.unwrap_err()
+    }
+
+    #[test]
+    fn creates_zip_of_the_entries_the_patterns_select() {
+        let dir = TestDir::new("zip-create");
+        dir.write("logs/app.log", "app");
+        dir.write("logs/notes.txt", "notes");
+        dir.write("logs/cache/old.log", "old");
+        let destination = dir.join("logs.zip");
+        let options = ArchiveOptions {
+            compression: Compression::Stored,
+            include: vec!["*.log".to_string()],
+            exclude: vec!["logs/cache".to_string()],
+            ..ArchiveOptions::default()
+        };
+
+        let written = handle_zip_files(
+            &[dir.join("logs").display().to_string()],
+            &destination,
+            &options,
+            &mut Job::detached(),
+        )
+        .unwrap();
+        assert_eq!(written, destination);
+
+        let mut archive = ZipArchive::new(File::open(&destination).unwrap()).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names, ["logs/app.log"]);
+        let mut entry = archive.by_name("logs/app.log").unwrap();
+        assert_eq!(entry.compression(), zip::CompressionMethod::Stored);
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "app");
+    }
+
+    #[test]
+    fn extracts_plain_archive() {
+        let dir = TestDir::new("zip-plain");
+        let source = dir.write("logs.zip", zip(|z| file(z, "logs/app.log", b"ok")));
+        let (folder, format) = handle_extract_archive(
+            &source.display().to_string(),
+            &dir.join("out").display().to_string(),
+            None,
+            &ArchiveConfig::default(),
+            &mut Job::detached(),
+        )
+        .unwrap();
+        assert_eq!(format, ArchiveFormat::Zip);
+        assert_eq!(std::fs::read(folder.join("logs/app.log")).unwrap(), b"ok");
+    }
+
+    #[test]
+    fn refuses_entries_outside_the_target() {
+        let limits = ArchiveConfig::default();
+        for name in ["../outside", "/etc/outside", "logs/../../outside"] {
+            let archive = zip(|z| {
+                file(z, "fine.txt", b"fine");
+                file(z, name, b"escaped");
+            });
+            let error = refused(archive, &limits);
+            assert!(matches!(error, Error::Archive(_)), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn refuses_symlink_pointing_outside_the_target() {
+        let archive = zip(|z| {
+            z.add_symlink("link", "../../outside", FileOptions::default())
+                .unwrap();
+        });
+        let error = refused(archive, &ArchiveConfig::default());
+        assert!(error.to_string().contains("points outside"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_more_entries_than_the_limit() {
+        let limits = ArchiveConfig {
+            max_entries: 2,
+            ..ArchiveConfig::default()
+        };
+        let archive = zip(|z| {
+            for name in ["a", "b", "c"] {
+                file(z, name, b"x");
+            }
+        });
+        let error = refused(archive, &limits);
+        assert!(error.to_string().contains("limit of 2"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_entry_beyond_the_compression_ratio() {
+        let archive = zip(|z| file(z, "zeros.bin", &vec![0; 4 * 1024 * 1024]));
+        let error = refused(archive, &ArchiveConfig::default());
+        assert!(error.to_string().contains("ratio limit"), "{}", error);
+    }
+}
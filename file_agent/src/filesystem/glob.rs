@@ -0,0 +1,213 @@
+use crate::error::{Error, Result};
+use std::io;
+
+/// A shell-style wildcard pattern over `/`-separated relative paths
+///
+/// `*` matches any run of characters within one path segment, `**` any
+/// run including `/`, `?` a single character and `[a-z]` or `[!a-z]` a
+/// character class. A pattern without a `/` is matched against the last
+/// segment only, so `*.log` finds log files at any depth. Matching is
+/// case-insensitive on Windows.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+    name_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    Globstar,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim_start_matches("./");
+        if pattern.is_empty() {
+            return Err(invalid(pattern, "pattern is empty"));
+        }
+        let mut tokens = Vec::new();
+        let folded = fold(pattern);
+        let mut chars = folded.chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // `a/**/b` also matches `a/b`
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        tokens.push(Token::Globstar);
+                        Token::Char('/')
+                    } else {
+                        Token::Globstar
+                    }
+                }
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '[' => {
+                    let negated = matches!(chars.peek(), Some('!' | '^'));
+                    if negated {
+                        chars.next();
+                    }
+                    let mut ranges = Vec::new();
+                    loop {
+                        match chars.next() {
+                            Some(']') if !ranges.is_empty() => break,
+                            Some(start) => {
+                                let mut lookahead = chars.clone();
+                                match (lookahead.next(), lookahead.next()) {
+                                    (Some('-'), Some(end)) if end != ']' => {
+                                        chars.next();
+                                        chars.next();
+                                        ranges.push((start, end));
+                                    }
+                                    _ => ranges.push((start, start)),
+                                }
+                            }
+                            None => return Err(invalid(pattern, "unclosed '['")),
+                        }
+                    }
+                    Token::Class { negated, ranges }
+                }
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Self {
+            name_only: !pattern.contains('/'),
+            tokens,
+        })
+    }
+
+    /// Whether `path`, relative and `/`-separated, matches
+    pub fn matches(&self, path: &str) -> bool {
+        let path = fold(path);
+        let path = if self.name_only {
+            path.rsplit('/').next().unwrap_or_default()
+        } else {
+            path.as_str()
+        };
+        let text: Vec<char> = path.chars().collect();
+        Matcher::new(&self.tokens, &text).at(0, 0)
+    }
+}
+
+/// Backtracking over `*` and `**`, with each combination of pattern and
+/// text position worked out at most once, so a pattern such as
+/// `*a*a*a*a*b` stays polynomial in the length of the name
+struct Matcher<'a> {
+    tokens: &'a [Token],
+    text: &'a [char],
+    memo: Vec<Option<bool>>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(tokens: &'a [Token], text: &'a [char]) -> Self {
+        Self {
+            tokens,
+            text,
+            memo: vec![None; (tokens.len() + 1) * (text.len() + 1)],
+        }
+    }
+
+    /// Whether `text[j..]` matches `tokens[i..]`
+    fn at(&mut self, i: usize, j: usize) -> bool {
+        let slot = i * (self.text.len() + 1) + j;
+        if let Some(known) = self.memo[slot] {
+            return known;
+        }
+        let matched = self.step(i, j);
+        self.memo[slot] = Some(matched);
+        matched
+    }
+
+    fn step(&mut self, i: usize, j: usize) -> bool {
+        let (tokens, text) = (self.tokens, self.text);
+        let Some(token) = tokens.get(i) else {
+            return j == text.len();
+        };
+        match token {
+            Token::Star => (j..=text.len())
+                .take_while(|&k| k == j || text[k - 1] != '/')
+                .any(|k| self.at(i + 1, k)),
+            Token::Globstar => {
+                // A `**/` may also match nothing at all, slash included
+                if tokens.get(i + 1) == Some(&Token::Char('/')) && self.at(i + 2, j) {
+                    return true;
+                }
+                (j..=text.len()).any(|k| self.at(i + 1, k))
+            }
+            Token::Any => text.get(j).is_some_and(|&c| c != '/') && self.at(i + 1, j + 1),
+            Token::Char(c) => text.get(j) == Some(c) && self.at(i + 1, j + 1),
+            Token::Class { negated, ranges } => match text.get(j) {
+                Some(&c) if c != '/' => {
+                    let hit = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                    hit != *negated && self.at(i + 1, j + 1)
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Whether `path` matches any of `globs`
+pub fn any_match(globs: &[Glob], path: &str) -> bool {
+    globs.iter().any(|glob| glob.matches(path))
+}
+
+/// Compile every pattern, failing on the first invalid one
+pub fn compile(patterns: &[String]) -> Result<Vec<Glob>> {
+    patterns.iter().map(|pattern| Glob::new(pattern)).collect()
+}
+
+fn fold(text: &str) -> String {
+    if cfg!(windows) {
+        text.replace('\\', "/").to_lowercase()
+    } else {
+        text.to_string()
+    }
+}
+
+fn invalid(pattern: &str, reason: &str) -> Error {
+    Error::file_io(
+        format!("Invalid pattern '{}'", pattern),
+        io::Error::new(io::ErrorKind::InvalidInput, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_segments_and_globstars() {
+        let cases = [
+            ("*.log", "logs/app.log", true),
+            ("a/*", "a/b/c", false),
+            ("a/**/b", "a/b", true),
+            ("a/**/b", "a/x/y/b", true),
+            ("[!x]?", "yz", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(
+                Glob::new(pattern).unwrap().matches(path),
+                expected,
+                "{pattern} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn wildcard_heavy_patterns_do_not_backtrack_exponentially() {
+        let name = "a".repeat(500);
+        assert!(!Glob::new("*a*a*a*a*a*a*a*a*a*a*b").unwrap().matches(&name));
+        let deep = "a/".repeat(200) + "y";
+        assert!(!Glob::new("**/**/**/**/**/**/x").unwrap().matches(&deep));
+    }
+}
@@ -0,0 +1,460 @@
+//! Paginated, sorted directory listings
+//!
+//! Only the name and sort key of every entry are held while a directory is
+//! sorted, and the details are read for the requested page alone, so a
+//! folder of 200k files is neither described nor sent in full.
+
+use super::operations::get_drives;
+use super::progress::Job;
+use super::utils::{format_iso_time, format_local_time, guess_mime};
+use crate::error::{Error, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Most entries listed in one page
+const MAX_PAGE_SIZE: usize = 10_000;
+
+/// What a listing is ordered by; ties are broken by name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+    /// The file extension
+    Type,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How `list_remote` pages, sorts and filters a directory
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RawListOptions")]
+pub struct ListOptions {
+    pub offset: usize,
+    /// Entries per page, at most 10000; the whole directory if absent
+    pub limit: Option<usize>,
+    pub sort: SortKey,
+    pub order: SortOrder,
+    /// Folders before files, whatever the sort
+    pub dirs_first: bool,
+    pub show_hidden: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: None,
+            sort: SortKey::default(),
+            order: SortOrder::default(),
+            dirs_first: false,
+            show_hidden: true,
+        }
+    }
+}
+
+/// [`ListOptions`] as sent. The relay forwards fields the caller left out
+/// as `null`, which means the same as leaving them out.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RawListOptions {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    sort: Option<SortKey>,
+    order: Option<SortOrder>,
+    dirs_first: Option<bool>,
+    show_hidden: Option<bool>,
+}
+
+impl From<RawListOptions> for ListOptions {
+    fn from(raw: RawListOptions) -> Self {
+        let default = Self::default();
+        Self {
+            offset: raw.offset.unwrap_or(default.offset),
+            limit: raw.limit,
+            sort: raw.sort.unwrap_or(default.sort),
+            order: raw.order.unwrap_or(default.order),
+            dirs_first: raw.dirs_first.unwrap_or(default.dirs_first),
+            show_hidden: raw.show_hidden.unwrap_or(default.show_hidden),
+        }
+    }
+}
+
+/// One row of a directory listing
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DirEntry {
+    pub name: String,
+    /// A folder, or a link to one
+    pub is_dir: bool,
+    pub size: u64,
+    /// Local modification time as `dd/mm/YYYY, HH:MM:SS`, or a label such as `Drive`
+    pub date: String,
+    /// ISO-8601 times in UTC, where the file system records them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessed: Option<String>,
+    /// A dot file on Unix, or one with the hidden attribute on Windows
+    pub hidden: bool,
+    /// The Windows system attribute
+    pub system: bool,
+    /// Unix permission bits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    pub is_symlink: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    /// A symlink whose target does not exist
+    pub broken_link: bool,
+    /// Guessed from the extension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<&'static str>,
+    /// Why the entry's details could not be read; only its name is reliable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A page of a directory's entries
+#[derive(Debug, Clone)]
+pub struct Listing {
+    pub entries: Vec<DirEntry>,
+    /// Entries in the whole directory, after filtering
+    pub total: usize,
+}
+
+/// List a page of the directory at `path`, or of the drives if it is empty
+pub fn list_directory(path: &str, options: &ListOptions, job: &Job) -> Result<Listing> {
+    let limit = options
+        .limit
+        .map_or(usize::MAX, |limit| limit.min(MAX_PAGE_SIZE));
+    if path.is_empty() {
+        let drives = get_drives();
+        let total = drives.len();
+        let entries = drives
+            .into_iter()
+            .skip(options.offset)
+            .take(limit)
+            .map(|name| DirEntry {
+                name,
+                is_dir: true,
+                date: "Drive".to_string(),
+                ..DirEntry::default()
+            })
+            .collect();
+        return Ok(Listing { entries, total });
+    }
+
+    let dir = Path::new(path);
+    let mut items = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| Error::file_io("Failed to list directory", e))? {
+        job.check_cancelled()?;
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Skipping unreadable entry in {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        if !options.show_hidden && is_hidden(&entry) {
+            continue;
+        }
+        items.push(Item::new(&entry, options.sort));
+    }
+
+    items.sort_by(|a, b| a.cmp(b, options));
+    let total = items.len();
+    let entries = items
+        .iter()
+        .skip(options.offset)
+        .take(limit)
+        .map(|item| describe(dir, &item.name))
+        .collect();
+    Ok(Listing { entries, total })
+}
+
+/// What sorting needs to know about an entry
+struct Item {
+    name: OsString,
+    /// Lowercased, so case does not split the order
+    folded: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Item {
+    /// Only stat the entry if the sort needs it
+    fn new(entry: &fs::DirEntry, sort: SortKey) -> Self {
+        let name = entry.file_name();
+        let is_dir = match entry.file_type() {
+            Ok(kind) if kind.is_symlink() => entry.path().is_dir(),
+            Ok(kind) => kind.is_dir(),
+            Err(_) => false,
+        };
+        let metadata = match sort {
+            SortKey::Size | SortKey::Modified => fs::metadata(entry.path()).ok(),
+            SortKey::Name | SortKey::Type => None,
+        };
+        Self {
+            folded: name.to_string_lossy().to_lowercase(),
+            name,
+            is_dir,
+            size: metadata.as_ref().map_or(0, Metadata::len),
+            modified: metadata.and_then(|metadata| metadata.modified().ok()),
+        }
+    }
+
+    fn cmp(&self, other: &Self, options: &ListOptions) -> Ordering {
+        if options.dirs_first && self.is_dir != other.is_dir {
+            return other.is_dir.cmp(&self.is_dir);
+        }
+        let by_key = match options.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => self.size.cmp(&other.size),
+            SortKey::Modified => self.modified.cmp(&other.modified),
+            SortKey::Type => extension(&self.folded).cmp(extension(&other.folded)),
+        };
+        let ordering = by_key
+            .then_with(|| self.folded.cmp(&other.folded))
+            .then_with(|| self.name.cmp(&other.name));
+        match options.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// The extension of a lowercased name, empty if it has none
+fn extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => &name[dot + 1..],
+        _ => "",
+    }
+}
+
+/// Everything there is to report about `name` in `dir`, following a
+/// symlink for its size and times
+fn describe(dir: &Path, name: &OsStr) -> DirEntry {
+    let path = dir.join(name);
+    let name = name.to_string_lossy().into_owned();
+    let link = match fs::symlink_metadata(&path) {
+        Ok(link) => link,
+        Err(e) => return unreadable(name, &e),
+    };
+
+    let mut entry = DirEntry {
+        is_symlink: link.file_type().is_symlink(),
+        ..DirEntry::default()
+    };
+    (entry.hidden, entry.system) = attributes(&name, &link);
+    let metadata = if entry.is_symlink {
+        entry.link_target = fs::read_link(&path)
+            .ok()
+            .map(|target| target.to_string_lossy().into_owned());
+        match fs::metadata(&path) {
+            Ok(target) => target,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                entry.broken_link = true;
+                link
+            }
+            Err(e) => {
+                entry.error = Some(e.to_string());
+                link
+            }
+        }
+    } else {
+        link
+    };
+
+    entry.is_dir = metadata.is_dir();
+    entry.size = metadata.len();
+    entry.date = metadata
+        .modified()
+        .map(format_local_time)
+        .unwrap_or_else(|_| "Unknown".to_string());
+    entry.modified = metadata.modified().ok().map(format_iso_time);
+    entry.created = metadata.created().ok().map(format_iso_time);
+    entry.accessed = metadata.accessed().ok().map(format_iso_time);
+    if !entry.is_dir {
+        entry.mime = guess_mime(&name);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        entry.mode = Some(metadata.mode() & 0o7777);
+        entry.uid = Some(metadata.uid());
+        entry.gid = Some(metadata.gid());
+    }
+    entry.name = name;
+    entry
+}
+
+/// An entry whose metadata cannot be read, reported rather than dropped
+fn unreadable(name: String, e: &io::Error) -> DirEntry {
+    DirEntry {
+        hidden: name.starts_with('.'),
+        name,
+        date: "Unknown".to_string(),
+        error: Some(e.to_string()),
+        ..DirEntry::default()
+    }
+}
+
+#[cfg(windows)]
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    // Cheap on Windows, where the directory listing carries the attributes
+    let name = entry.file_name().to_string_lossy().into_owned();
+    entry
+        .metadata()
+        .map(|metadata| attributes(&name, &metadata).0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+/// Whether an entry is hidden and whether it is a system file
+#[cfg(windows)]
+fn attributes(_name: &str, metadata: &Metadata) -> (bool, bool) {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    let attributes = metadata.file_attributes();
+    (
+        attributes & FILE_ATTRIBUTE_HIDDEN != 0,
+        attributes & FILE_ATTRIBUTE_SYSTEM != 0,
+    )
+}
+
+/// Whether an entry is hidden and whether it is a system file
+#[cfg(not(windows))]
+fn attributes(name: &str, _metadata: &Metadata) -> (bool, bool) {
+    (name.starts_with('.'), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+
+    fn list(dir: &TestDir, options: ListOptions) -> (Vec<String>, usize) {
+        let path = dir.path().to_str().unwrap();
+        let listing = list_directory(path, &options, &Job::detached()).unwrap();
+        let names = listing
+            .entries
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        (names, listing.total)
+    }
+
+    #[test]
+    fn null_options_mean_the_defaults() {
+        let text = r#"{"offset":null,"limit":null,"sort":null,"order":null,
+            "dirs_first":null,"show_hidden":null}"#;
+        let options: ListOptions = serde_json::from_str(text).unwrap();
+        assert_eq!(options.offset, 0);
+        assert_eq!(options.limit, None);
+        assert_eq!(options.sort, SortKey::Name);
+        assert_eq!(options.order, SortOrder::Asc);
+        assert!(!options.dirs_first);
+        assert!(options.show_hidden);
+    }
+
+    #[test]
+    fn pages_through_the_sorted_directory() {
+        let dir = TestDir::new("listing-pages");
+        for name in ["e.txt", "B.txt", "a.txt", "d.txt", "c.txt"] {
+            dir.write(name, name);
+        }
+        let page = |offset, limit| ListOptions {
+            offset,
+            limit,
+            ..ListOptions::default()
+        };
+
+        assert_eq!(
+            list(&dir, page(1, Some(2))),
+            (vec!["B.txt".to_string(), "c.txt".to_string()], 5)
+        );
+        assert_eq!(list(&dir, page(4, None)), (vec!["e.txt".to_string()], 5));
+        assert_eq!(list(&dir, page(9, Some(2))), (vec![], 5));
+    }
+
+    #[test]
+    fn sorts_by_key_with_folders_first() {
+        let dir = TestDir::new("listing-sort");
+        dir.write("small.txt", "1");
+        dir.write("large.log", "1234567890");
+        dir.write("medium.txt", "12345");
+        fs::create_dir(dir.join("folder")).unwrap();
+
+        let options = ListOptions {
+            sort: SortKey::Size,
+            order: SortOrder::Desc,
+            dirs_first: true,
+            ..ListOptions::default()
+        };
+        let (names, _) = list(&dir, options);
+        assert_eq!(names, ["folder", "large.log", "medium.txt", "small.txt"]);
+
+        let by_type = ListOptions {
+            sort: SortKey::Type,
+            ..ListOptions::default()
+        };
+        let (names, _) = list(&dir, by_type);
+        assert_eq!(names, ["folder", "large.log", "medium.txt", "small.txt"]);
+    }
+
+    #[test]
+    fn hidden_entries_are_left_out_of_the_total_too() {
+        let dir = TestDir::new("listing-hidden");
+        dir.write(".secret", "s");
+        dir.write("visible.txt", "v");
+        let options = ListOptions {
+            show_hidden: false,
+            ..ListOptions::default()
+        };
+        assert_eq!(list(&dir, options), (vec!["visible.txt".to_string()], 1));
+        assert_eq!(list(&dir, ListOptions::default()).1, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn describes_broken_links_instead_of_dropping_them() {
+        let dir = TestDir::new("listing-links");
+        std::os::unix::fs::symlink("missing.txt", dir.join("dangling")).unwrap();
+        let path = dir.path().to_str().unwrap();
+        let listing = list_directory(path, &ListOptions::default(), &Job::detached()).unwrap();
+
+        let [entry] = listing.entries.as_slice() else {
+            panic!("expected one entry, got {:?}", listing.entries);
+        };
+        assert!(entry.is_symlink);
+        assert!(entry.broken_link);
+        assert_eq!(entry.link_target.as_deref(), Some("missing.txt"));
+        assert_eq!(entry.error, None);
+    }
+}
@@ -1,5 +1,18 @@
+pub mod archive;
+pub mod glob;
+pub mod listing;
 pub mod operations;
+pub mod progress;
+pub mod sandbox;
+pub mod search;
 pub mod utils;
 
+pub use archive::{
+    ArchiveEntry, ArchiveFormat, ArchiveListing, ArchiveOptions, Compression, Conflict, EntryKind,
+};
+pub use listing::{DirEntry, ListOptions, SortKey, SortOrder};
 pub use operations::*;
+pub use progress::{Job, JobProgress};
+pub use sandbox::{Access, Sandbox};
+pub use search::{SearchMatch, SearchQuery, SearchSummary};
 pub use utils::*;
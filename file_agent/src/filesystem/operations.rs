@@ -1,11 +1,28 @@
+use super::archive::{
+    already_exists, claim_destination, create_symlink, extract_archive, extract_selected,
+    list_archive, read_entry, write_archive, ArchiveFormat, ArchiveListing, ArchiveOptions,
+    Conflict, Staging,
+};
+use super::progress::{Job, ProgressReader};
+use crate::config::ArchiveConfig;
 use crate::error::{Error, Result};
 use base64::{engine::general_purpose, Engine as _};
-use serde_json::{json, Value};
+use log::warn;
+use serde::Deserialize;
 use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
-use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+/// Copy or move for `paste_file`; `cut` is accepted as an alias of `move`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasteMode {
+    #[default]
+    Copy,
+    #[serde(alias = "cut")]
+    Move,
+}
 
 /// Get available drives on the system
 pub fn get_drives() -> Vec<String> {
@@ -31,15 +48,8 @@ pub fn get_drives() -> Vec<String> {
     }
 }
 
-/// Handle file/folder rename operation
-pub fn handle_rename(msg: &Value) -> Result<()> {
-    let old_path = msg["old_path"]
-        .as_str()
-        .ok_or(Error::FileSystem("Missing old_path".to_string()))?;
-    let new_name = msg["new_name"]
-        .as_str()
-        .ok_or(Error::FileSystem("Missing new_name".to_string()))?;
-
+/// Handle file/folder rename operation, returning the new path
+pub fn handle_rename(old_path: &str, new_name: &str) -> Result<PathBuf> {
     if old_path.is_empty() || new_name.is_empty() {
         return Err(Error::FileSystem("Empty path or name provided".to_string()));
     }
@@ -47,111 +57,118 @@ pub fn handle_rename(msg: &Value) -> Result<()> {
     let old_path = Path::new(old_path);
     if let Some(parent) = old_path.parent() {
         let new_path = parent.join(new_name);
-        std::fs::rename(old_path, new_path)
-            .map_err(|e| Error::FileSystem(format!("Failed to rename: {}", e)))?;
+        std::fs::rename(old_path, &new_path).map_err(|e| Error::file_io("Failed to rename", e))?;
+        Ok(new_path)
     } else {
-        return Err(Error::FileSystem(
+        Err(Error::FileSystem(
             "Cannot determine parent directory".to_string(),
-        ));
+        ))
     }
-    Ok(())
 }
 
-/// Handle file/folder deletion
-pub fn handle_delete(msg: &Value) -> Result<()> {
-    let path = msg["path"]
-        .as_str()
-        .ok_or(Error::FileSystem("Missing path".to_string()))?;
-
+/// Handle file/folder deletion. A cancelled directory delete stops between
+/// entries; whatever was already removed stays removed.
+pub fn handle_delete(path: &str, job: &mut Job) -> Result<()> {
     if path.is_empty() {
         return Err(Error::FileSystem("Empty path provided".to_string()));
     }
 
     let path = Path::new(path);
     if path.is_file() {
-        fs::remove_file(path)
-            .map_err(|e| Error::FileSystem(format!("Failed to delete file: {}", e)))?;
+        job.plan_path(path)?;
+        remove_file(path, job)?;
     } else if path.is_dir() {
-        fs::remove_dir_all(path)
-            .map_err(|e| Error::FileSystem(format!("Failed to delete directory: {}", e)))?;
+        job.plan_path(path)?;
+        remove_dir_all(path, job)?;
     } else {
-        return Err(Error::FileSystem("Path does not exist".to_string()));
+        return Err(Error::file_io(
+            "Path does not exist",
+            io::Error::from(io::ErrorKind::NotFound),
+        ));
     }
     Ok(())
 }
 
-/// Handle folder creation
-pub fn handle_folder_creation(msg: &Value) -> Result<()> {
-    // Support both formats: path + folder_name OR just path
-    let path = if let Some(folder_name) = msg["folder_name"].as_str() {
-        let base_path = msg["path"]
-            .as_str()
-            .ok_or(Error::FileSystem("Missing path".to_string()))?;
-        Path::new(base_path)
-            .join(folder_name)
-            .to_string_lossy()
-            .to_string()
-    } else {
-        msg["path"]
-            .as_str()
-            .ok_or(Error::FileSystem("Missing path".to_string()))?
-            .to_string()
-    };
+/// `fs::remove_dir_all`, checking for cancellation before each entry
+fn remove_dir_all(path: &Path, job: &mut Job) -> Result<()> {
+    for entry in WalkDir::new(path).contents_first(true) {
+        job.check_cancelled()?;
+        let entry = entry.map_err(|e| Error::FileSystem(format!("Walk directory error: {}", e)))?;
+        if entry.file_type().is_dir() {
+            fs::remove_dir(entry.path()).map_err(|e| {
+                Error::file_io(format!("Failed to delete {}", entry.path().display()), e)
+            })?;
+        } else {
+            remove_file(entry.path(), job)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_file(path: &Path, job: &mut Job) -> Result<()> {
+    job.start_file(path);
+    let size = fs::symlink_metadata(path).map(|m| m.len()).unwrap_or(0);
+    fs::remove_file(path)
+        .map_err(|e| Error::file_io(format!("Failed to delete {}", path.display()), e))?;
+    job.advance(size);
+    job.finish_file();
+    Ok(())
+}
 
+/// Handle folder creation, either `path` itself or `folder_name` inside it.
+/// Returns the created path.
+pub fn handle_folder_creation(path: &str, folder_name: Option<&str>) -> Result<PathBuf> {
     if path.is_empty() {
         return Err(Error::FileSystem("Empty path provided".to_string()));
     }
 
-    fs::create_dir_all(&path)
-        .map_err(|e| Error::FileSystem(format!("Failed to create folder: {}", e)))?;
-    Ok(())
+    let path = match folder_name {
+        Some(folder_name) => Path::new(path).join(folder_name),
+        None => PathBuf::from(path),
+    };
+
+    fs::create_dir_all(&path).map_err(|e| Error::file_io("Failed to create folder", e))?;
+    Ok(path)
 }
 
 /// Handle file editing (read file content)
-pub fn handle_edit_file(path: &str) -> Result<Value> {
+pub fn handle_edit_file(path: &str) -> Result<String> {
     if path.is_empty() {
         return Err(Error::FileSystem("Empty path provided".to_string()));
     }
 
-    let content = fs::read_to_string(path)
-        .map_err(|e| Error::FileSystem(format!("Failed to read file: {}", e)))?;
+    fs::read_to_string(path).map_err(|e| Error::file_io("Failed to read file", e))
+}
 
-    Ok(json!({
-        "type": "edit_file",
-        "path": path,
-        "content": content
-    }))
+/// Handle file download, returning the file name and base64 encoded content
+pub fn handle_download_file(path: &str) -> Result<(String, String)> {
+    let (filename, content) = read_file(path)?;
+    Ok((filename, general_purpose::STANDARD.encode(&content)))
 }
 
-/// Handle file download (encode file as base64)
-pub fn handle_download_file(path: &str) -> Result<Value> {
+/// Read a whole file for download, returning its name and raw content
+pub fn read_file(path: &str) -> Result<(String, Vec<u8>)> {
     if path.is_empty() {
         return Err(Error::FileSystem("Empty path provided".to_string()));
     }
 
-    let mut file =
-        File::open(path).map_err(|e| Error::FileSystem(format!("Failed to open file: {}", e)))?;
+    let mut file = File::open(path).map_err(|e| Error::file_io("Failed to open file", e))?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)
-        .map_err(|e| Error::FileSystem(format!("Failed to read file: {}", e)))?;
+        .map_err(|e| Error::file_io("Failed to read file", e))?;
 
-    let encoded = general_purpose::STANDARD.encode(&buffer);
     let filename = Path::new(path)
         .file_name()
         .and_then(|n| n.to_str())
         .unwrap_or("unknown")
         .to_string();
 
-    Ok(json!({
-        "type": "download_file",
-        "filename": filename,
-        "content": encoded
-    }))
+    Ok((filename, buffer))
 }
 
 /// Handle file upload
 pub fn handle_upload_file(dir_path: &str, filename: &str, base64_content: &str) -> Result<()> {
-    if dir_path.is_empty() || filename.is_empty() || base64_content.is_empty() {
+    if base64_content.is_empty() {
         return Err(Error::FileSystem("Missing required parameters".to_string()));
     }
 
@@ -159,74 +176,84 @@ pub fn handle_upload_file(dir_path: &str, filename: &str, base64_content: &str)
         .decode(base64_content)
         .map_err(|e| Error::FileSystem(format!("Failed to decode base64: {}", e)))?;
 
+    write_file(dir_path, filename, &decoded)
+}
+
+/// Write uploaded content to `filename` in `dir_path`
+pub fn write_file(dir_path: &str, filename: &str, content: &[u8]) -> Result<()> {
+    if dir_path.is_empty() || filename.is_empty() {
+        return Err(Error::FileSystem("Missing required parameters".to_string()));
+    }
+
     let file_path = Path::new(dir_path).join(filename);
-    let mut file = File::create(&file_path)
-        .map_err(|e| Error::FileSystem(format!("Failed to create file: {}", e)))?;
+    let mut file =
+        File::create(&file_path).map_err(|e| Error::file_io("Failed to create file", e))?;
 
-    file.write_all(&decoded)
-        .map_err(|e| Error::FileSystem(format!("Failed to write file: {}", e)))?;
+    file.write_all(content)
+        .map_err(|e| Error::file_io("Failed to write file", e))?;
 
     Ok(())
 }
 
-/// Handle paste operation
-pub fn handle_paste_multiple(msg: &Value) -> Result<()> {
-    // Support both old and new parameter formats for compatibility
-    let source_paths = msg["source_paths"]
-        .as_array()
-        .or_else(|| msg["from_list"].as_array())
-        .ok_or(Error::FileSystem(
-            "Missing source_paths or from_list".to_string(),
-        ))?;
-
-    let target_path = msg["target_path"]
-        .as_str()
-        .or_else(|| msg["to"].as_str())
-        .ok_or(Error::FileSystem("Missing target_path or to".to_string()))?;
-
-    let operation = msg["operation"]
-        .as_str()
-        .or_else(|| msg["mode"].as_str())
-        .unwrap_or("copy");
-
-    for source_value in source_paths {
-        let source_path = source_value
-            .as_str()
-            .ok_or(Error::FileSystem("Invalid source path".to_string()))?;
+/// Handle paste operation: copy or move every source into `target_path`
+///
+/// Cancellation is checked between files. The source being copied when the
+/// operation stops has its partial copy removed; sources already pasted are
+/// left in place. Progress covers every file under every source.
+pub fn handle_paste_multiple(
+    source_paths: &[String],
+    target_path: &str,
+    mode: PasteMode,
+    job: &mut Job,
+) -> Result<()> {
+    for source_path in source_paths {
+        job.plan_path(Path::new(source_path))?;
+    }
+
+    for source_path in source_paths {
+        job.check_cancelled()?;
         let source = Path::new(source_path);
         let filename = extract_filename(source_path);
         let target = Path::new(target_path).join(&filename);
 
         if source.is_file() {
-            if operation == "move" || operation == "cut" {
+            if mode == PasteMode::Move {
+                job.start_file(source);
+                let size = fs::metadata(source).map(|m| m.len()).unwrap_or(0);
                 fs::rename(source, &target)
-                    .map_err(|e| Error::FileSystem(format!("Failed to move file: {}", e)))?;
+                    .map_err(|e| Error::file_io("Failed to move file", e))?;
+                job.advance(size);
+                job.finish_file();
             } else {
-                fs::copy(source, &target)
-                    .map_err(|e| Error::FileSystem(format!("Failed to copy file: {}", e)))?;
+                with_rollback(|created| copy_file(source, &target, created, job))?;
             }
         } else if source.is_dir() {
-            copy_dir_all(source, &target)?;
-            if operation == "move" || operation == "cut" {
-                fs::remove_dir_all(source).map_err(|e| {
-                    Error::FileSystem(format!("Failed to remove source directory: {}", e))
-                })?;
+            with_rollback(|created| copy_dir_all(source, &target, created, job))?;
+            if mode == PasteMode::Move {
+                fs::remove_dir_all(source)
+                    .map_err(|e| Error::file_io("Failed to remove source directory", e))?;
             }
         }
     }
     Ok(())
 }
 // Add separate copy and cut handlers for compatibility
-pub fn handle_copy_files(msg: &Value) -> Result<()> {
-    let mut copy_msg = msg.clone();
-    copy_msg["operation"] = json!("copy");
-    handle_paste_multiple(&copy_msg)
+pub fn handle_copy_files(source_paths: &[String], target_path: &str) -> Result<()> {
+    handle_paste_multiple(
+        source_paths,
+        target_path,
+        PasteMode::Copy,
+        &mut Job::detached(),
+    )
 }
 
-pub fn handle_cut_files(msg: &Value) -> Result<()> {
-    let mut cut_msg = msg.clone();
-    cut_msg["operation"] = json!("cut");
-    handle_paste_multiple(&cut_msg)
+pub fn handle_cut_files(source_paths: &[String], target_path: &str) -> Result<()> {
+    handle_paste_multiple(
+        source_paths,
+        target_path,
+        PasteMode::Move,
+        &mut Job::detached(),
+    )
 }
 fn extract_filename(path: &str) -> String {
     Path::new(path)
@@ -236,175 +263,465 @@ fn extract_filename(path: &str) -> String {
         .to_string()
 }
 
-fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
-    fs::create_dir_all(dst)
-        .map_err(|e| Error::FileSystem(format!("Failed to create directory: {}", e)))?;
-    for entry in fs::read_dir(src)
-        .map_err(|e| Error::FileSystem(format!("Failed to read directory: {}", e)))?
-    {
-        let entry = entry.map_err(|e| Error::FileSystem(format!("Failed to read entry: {}", e)))?;
+fn copy_dir_all(src: &Path, dst: &Path, created: &mut Created, job: &mut Job) -> Result<()> {
+    created
+        .create_dir_all(dst)
+        .map_err(|e| Error::file_io("Failed to create directory", e))?;
+    for entry in fs::read_dir(src).map_err(|e| Error::file_io("Failed to read directory", e))? {
+        job.check_cancelled()?;
+        let entry = entry.map_err(|e| Error::file_io("Failed to read entry", e))?;
         let ty = entry
             .file_type()
-            .map_err(|e| Error::FileSystem(format!("Failed to get file type: {}", e)))?;
+            .map_err(|e| Error::file_io("Failed to get file type", e))?;
+        let target = dst.join(entry.file_name());
         if ty.is_dir() {
-            copy_dir_all(&entry.path(), &dst.join(entry.file_name()))?;
+            copy_dir_all(&entry.path(), &target, created, job)?;
+        } else if ty.is_symlink() {
+            copy_symlink(&entry.path(), &target, created)?;
         } else {
-            fs::copy(entry.path(), dst.join(entry.file_name()))
-                .map_err(|e| Error::FileSystem(format!("Failed to copy file: {}", e)))?;
+            copy_file(&entry.path(), &target, created, job)?;
         }
     }
     Ok(())
 }
 
-/// Handle zip operation
-pub fn handle_zip_files(paths: &[String], zip_name: &str) -> Result<Value> {
-    if paths.is_empty() {
-        return Err(Error::FileSystem("No input paths provided".to_string()));
+/// Recreate the link rather than copy what it points to, which may be
+/// somewhere the sandbox does not let the copy read. Unlike a file, an
+/// entry already at `dst` is not replaced.
+fn copy_symlink(src: &Path, dst: &Path, created: &mut Created) -> Result<()> {
+    let target = fs::read_link(src).map_err(|e| Error::file_io("Failed to read symlink", e))?;
+    if fs::symlink_metadata(dst).is_ok() {
+        return Err(already_exists(dst));
     }
+    created.file(dst);
+    create_symlink(&target, dst)
+}
 
-    if zip_name.is_empty() {
-        return Err(Error::FileSystem("No zip name provided".to_string()));
+/// `fs::copy` in chunks, so progress is reported within large files.
+///
+/// The copy is written to a staging file next to `dst` and renamed over it
+/// once complete, so a file being overwritten stays intact if the copy
+/// fails or is cancelled.
+fn copy_file(src: &Path, dst: &Path, created: &mut Created, job: &mut Job) -> Result<()> {
+    job.start_file(src);
+    let mut reader = File::open(src).map_err(|e| Error::file_io("Failed to copy file", e))?;
+    let permissions = reader.metadata().map(|m| m.permissions());
+
+    let parent = dst.parent().unwrap_or(Path::new("."));
+    let name = dst.file_name().unwrap_or_default().to_string_lossy();
+    let (staging, mut writer) = Staging::file(parent, &name)?;
+    io::copy(&mut ProgressReader::new(&mut reader, job), &mut writer)
+        .map_err(|e| Error::file_io("Failed to copy file", e))?;
+    drop(writer);
+    if let Ok(permissions) = permissions {
+        fs::set_permissions(staging.path(), permissions)
+            .map_err(|e| Error::file_io("Failed to copy file permissions", e))?;
     }
+    created.file(dst);
+    staging.replace(dst)?;
+    job.finish_file();
+    Ok(())
+}
 
-    let parent = Path::new(&paths[0]).parent().unwrap_or(Path::new("."));
-    let target = parent.join(zip_name);
+/// Paths an operation has created, so a failed or cancelled run can remove
+/// its partial output without touching anything that was there before
+#[derive(Default)]
+struct Created(Vec<PathBuf>);
 
-    let file = File::create(&target)
-        .map_err(|e| Error::FileSystem(format!("Cannot create zip file: {}", e)))?;
-    let mut zip = ZipWriter::new(file);
-    let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+impl Created {
+    /// Record `path` ahead of writing it, unless it already exists
+    fn file(&mut self, path: &Path) {
+        if fs::symlink_metadata(path).is_err() {
+            self.0.push(path.to_path_buf());
+        }
+    }
 
-    for path_str in paths {
-        let path = Path::new(path_str);
+    /// `fs::create_dir_all`, recording each directory it had to create
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        let missing: Vec<PathBuf> = path
+            .ancestors()
+            .take_while(|p| !p.as_os_str().is_empty() && fs::symlink_metadata(p).is_err())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(path)?;
+        self.0.extend(missing.into_iter().rev());
+        Ok(())
+    }
 
-        if path.is_file() {
-            add_file_to_zip(&mut zip, path, &options)?;
-        } else if path.is_dir() {
-            add_directory_to_zip(&mut zip, path, &options)?;
+    /// Remove everything recorded, newest first
+    fn roll_back(self) {
+        for path in self.0.into_iter().rev() {
+            let removed = if path.is_dir() {
+                fs::remove_dir(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            if let Err(e) = removed {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to clean up {}: {}", path.display(), e);
+                }
+            }
         }
     }
+}
 
-    zip.finish()
-        .map_err(|e| Error::FileSystem(format!("Failed to finalize zip: {}", e)))?;
+/// Run `f`, removing whatever it recorded as created if it fails
+fn with_rollback<T>(f: impl FnOnce(&mut Created) -> Result<T>) -> Result<T> {
+    let mut created = Created::default();
+    let result = f(&mut created);
+    if result.is_err() {
+        created.roll_back();
+    }
+    result
+}
 
-    Ok(json!({
-        "type": "zip_file_result",
-        "path": target.display().to_string()
-    }))
+/// Where `zip_file` writes its archive: `destination`, or `zip_name` inside
+/// it if it is a folder, or else `zip_name` next to the first input
+pub fn zip_destination(paths: &[String], destination: Option<&str>, zip_name: &str) -> PathBuf {
+    match destination.filter(|d| !d.is_empty()) {
+        Some(destination) if Path::new(destination).is_dir() => {
+            Path::new(destination).join(zip_name)
+        }
+        Some(destination) => PathBuf::from(destination),
+        None => {
+            let first = paths.first().map(String::as_str).unwrap_or_default();
+            let parent = Path::new(first).parent().unwrap_or(Path::new("."));
+            parent.join(zip_name)
+        }
+    }
 }
 
-fn add_file_to_zip(zip: &mut ZipWriter<File>, path: &Path, options: &FileOptions) -> Result<()> {
-    let name = path.file_name().unwrap().to_string_lossy();
-    let content = fs::read(path)
-        .map_err(|e| Error::FileSystem(format!("Failed to read file {}: {}", path.display(), e)))?;
+/// Handle zip operation, returning the path of the created archive
+pub fn handle_zip_files(
+    paths: &[String],
+    destination: &Path,
+    options: &ArchiveOptions,
+    job: &mut Job,
+) -> Result<PathBuf> {
+    handle_create_archive(paths, destination, Some(ArchiveFormat::Zip), options, job)
+        .map(|(path, _)| path)
+}
 
-    zip.start_file(name, *options)
-        .map_err(|e| Error::FileSystem(format!("Failed to start zip file entry: {}", e)))?;
-    zip.write_all(&content)
-        .map_err(|e| Error::FileSystem(format!("Failed to write to zip: {}", e)))?;
+/// Create an archive of `paths` at `destination`, in `format` or else the
+/// one its extension names, returning where it was written and as what.
+///
+/// The archive is written to a staging file and only moved to `destination`
+/// once complete, so a failed or cancelled run leaves nothing behind and
+/// an archive being overwritten stays intact until then.
+pub fn handle_create_archive(
+    paths: &[String],
+    destination: &Path,
+    format: Option<ArchiveFormat>,
+    options: &ArchiveOptions,
+    job: &mut Job,
+) -> Result<(PathBuf, ArchiveFormat)> {
+    if paths.is_empty() {
+        return Err(Error::FileSystem("No input paths provided".to_string()));
+    }
+    let format = format
+        .or_else(|| ArchiveFormat::from_path(destination))
+        .ok_or_else(|| {
+            Error::Archive(format!(
+                "no format given and none implied by {}",
+                destination.display()
+            ))
+        })?;
+    options.validate(format)?;
+
+    let name = destination
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| Error::FileSystem("No archive name provided".to_string()))?;
+    let parent = destination.parent().unwrap_or(Path::new("."));
+    let target = claim_destination(destination, options.on_conflict)?;
+
+    let (staging, file) = Staging::file(parent, &name)?;
+    write_archive(file, format, paths, options, staging.path(), job)?;
+    if options.on_conflict == Conflict::Overwrite {
+        staging.replace(&target)?;
+    } else {
+        staging.finish(&target)?;
+    }
+    Ok((target, format))
+}
 
-    Ok(())
+/// Handle unzip operation, returning the folder the archive was extracted into
+pub fn handle_unzip_file(
+    source: &str,
+    target: &str,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<PathBuf> {
+    handle_extract_archive(source, target, Some(ArchiveFormat::Zip), limits, job)
+        .map(|(path, _)| path)
 }
 
-fn add_directory_to_zip(
-    zip: &mut ZipWriter<File>,
-    path: &Path,
-    options: &FileOptions,
-) -> Result<()> {
-    let base_name = path.file_name().unwrap().to_string_lossy();
-    let mut has_entries = false;
-
-    for entry_result in WalkDir::new(path).min_depth(1) {
-        has_entries = true;
-        let entry =
-            entry_result.map_err(|e| Error::FileSystem(format!("Walk directory error: {}", e)))?;
-        let entry_path = entry.path();
-
-        let rel_path = entry_path
-            .strip_prefix(path)
-            .map_err(|e| Error::FileSystem(format!("Failed to strip prefix: {}", e)))?;
-        let zip_path = format!("{}/{}", base_name, rel_path.to_string_lossy());
-
-        if entry_path.is_file() {
-            zip.start_file(&zip_path, *options)
-                .map_err(|e| Error::FileSystem(format!("Failed to start zip file entry: {}", e)))?;
-
-            let mut f = File::open(entry_path)
-                .map_err(|e| Error::FileSystem(format!("Failed to open file: {}", e)))?;
-            let mut buffer = Vec::new();
-            f.read_to_end(&mut buffer)
-                .map_err(|e| Error::FileSystem(format!("Failed to read file: {}", e)))?;
-
-            zip.write_all(&buffer)
-                .map_err(|e| Error::FileSystem(format!("Failed to write to zip: {}", e)))?;
-        } else if entry_path.is_dir() {
-            zip.add_directory(format!("{}/", zip_path), *options)
-                .map_err(|e| Error::FileSystem(format!("Failed to add directory to zip: {}", e)))?;
-        }
+/// Extract the archive at `source` into a new folder in `target` named
+/// after it, returning that folder and the archive's format, which is
+/// detected unless given.
+///
+/// The archive is unpacked into a hidden staging folder next to the
+/// destination and only renamed into place once every entry has been
+/// extracted within `limits`; a rejected, failed or cancelled extraction
+/// leaves nothing behind.
+pub fn handle_extract_archive(
+    source: &str,
+    target: &str,
+    format: Option<ArchiveFormat>,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<(PathBuf, ArchiveFormat)> {
+    if source.is_empty() || target.is_empty() {
+        return Err(Error::FileSystem(
+            "Source or target path is empty".to_string(),
+        ));
     }
-
-    // If folder is completely empty
-    if !has_entries {
-        zip.add_directory(format!("{}/", base_name), *options)
-            .map_err(|e| {
-                Error::FileSystem(format!("Failed to add empty directory to zip: {}", e))
-            })?;
+    let source = Path::new(source);
+    let format = archive_format(source, format)?;
+
+    let name = source.file_name().unwrap_or_default().to_string_lossy();
+    let folder_name = format.stem(&name);
+    let base_folder = Path::new(target).join(folder_name);
+    if fs::symlink_metadata(&base_folder).is_ok() {
+        return Err(already_exists(&base_folder));
     }
 
-    Ok(())
+    with_rollback(|created| {
+        created
+            .create_dir_all(Path::new(target))
+            .map_err(|e| Error::file_io("Failed to create target folder", e))?;
+        let staging = Staging::new(Path::new(target), folder_name)?;
+        extract_archive(source, format, staging.path(), limits, job)?;
+        staging.finish(&base_folder)
+    })?;
+
+    Ok((base_folder, format))
+}
+
+/// List the entries of the archive at `source` from `offset` on, at most
+/// `limit` of them, with the archive's format, which is detected unless
+/// given
+pub fn handle_list_archive(
+    source: &str,
+    format: Option<ArchiveFormat>,
+    offset: usize,
+    limit: Option<usize>,
+    job: &Job,
+) -> Result<(ArchiveListing, ArchiveFormat)> {
+    if source.is_empty() {
+        return Err(Error::FileSystem("Empty path provided".to_string()));
+    }
+    let source = Path::new(source);
+    let format = archive_format(source, format)?;
+    let listing = list_archive(source, format, offset, limit, job)?;
+    Ok((listing, format))
 }
 
-/// Handle unzip operation
-pub fn handle_unzip_file(source: &str, target: &str) -> Result<Value> {
+/// Extract the entries named `entries` from the archive at `source` into
+/// `target`, each under its own name as `paste_file` would place it, and
+/// return the paths written there. A folder entry brings everything below
+/// it.
+///
+/// The entries are unpacked into a staging folder in `target` first and
+/// only moved out once all of them were extracted within `limits` and none
+/// of their names is taken.
+pub fn handle_extract_entries(
+    source: &str,
+    entries: &[String],
+    target: &str,
+    format: Option<ArchiveFormat>,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<(Vec<PathBuf>, ArchiveFormat)> {
     if source.is_empty() || target.is_empty() {
         return Err(Error::FileSystem(
             "Source or target path is empty".to_string(),
         ));
     }
+    let source = Path::new(source);
+    let target = Path::new(target);
+    let format = archive_format(source, format)?;
+    let name = source.file_name().unwrap_or_default().to_string_lossy();
+
+    let written = with_rollback(|created| {
+        created
+            .create_dir_all(target)
+            .map_err(|e| Error::file_io("Failed to create target folder", e))?;
+        let staging = Staging::new(target, format.stem(&name))?;
+        extract_selected(source, format, entries, staging.path(), limits, job)?;
+
+        let mut moves = Vec::new();
+        for extracted in fs::read_dir(staging.path())
+            .map_err(|e| Error::file_io("Failed to read staging folder", e))?
+        {
+            let extracted =
+                extracted.map_err(|e| Error::file_io("Failed to read staging folder", e))?;
+            let destination = target.join(extracted.file_name());
+            if fs::symlink_metadata(&destination).is_ok() {
+                return Err(already_exists(&destination));
+            }
+            moves.push((extracted.path(), destination));
+        }
+        for (from, to) in &moves {
+            created.file(to);
+            fs::rename(from, to)
+                .map_err(|e| Error::file_io("Failed to move the result into place", e))?;
+        }
+        Ok(moves.into_iter().map(|(_, to)| to).collect())
+    })?;
+
+    Ok((written, format))
+}
+
+/// Read the file entry called `entry` from the archive at `source` for
+/// download, returning its name and contents. The entry is held in memory;
+/// larger ones should be extracted to a folder and downloaded from there.
+pub fn handle_read_archive_entry(
+    source: &str,
+    entry: &str,
+    format: Option<ArchiveFormat>,
+    limits: &ArchiveConfig,
+    job: &mut Job,
+) -> Result<(String, Vec<u8>)> {
+    if source.is_empty() {
+        return Err(Error::FileSystem("Empty path provided".to_string()));
+    }
+    let source = Path::new(source);
+    let format = archive_format(source, format)?;
+    let content = read_entry(source, format, entry, limits, job)?;
+    Ok((extract_filename(entry), content))
+}
+
+/// `format`, or else the format of the archive at `source`
+fn archive_format(source: &Path, format: Option<ArchiveFormat>) -> Result<ArchiveFormat> {
+    match format {
+        Some(format) => Ok(format),
+        None => ArchiveFormat::detect(source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    /// A job that cancels itself once `after` files are done
+    fn cancelling_job(after: u64) -> Job {
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        Job::new(token).with_progress(Duration::ZERO, move |progress| {
+            if progress.files_done >= after {
+                cancel.cancel();
+            }
+        })
+    }
 
-    let zip_file = File::open(source)
-        .map_err(|e| Error::FileSystem(format!("Failed to open zip file: {}", e)))?;
-    let mut archive = ZipArchive::new(zip_file)
-        .map_err(|e| Error::FileSystem(format!("Failed to read zip archive: {}", e)))?;
+    /// Zip `dir/logs` as `dir/logs.zip`, which already exists, under `conflict`
+    fn zip_over_existing(dir: &TestDir, conflict: Conflict) -> Result<PathBuf> {
+        dir.write("logs/app.log", "app");
+        let options = ArchiveOptions {
+            on_conflict: conflict,
+            ..ArchiveOptions::default()
+        };
+        handle_zip_files(
+            &[dir.join("logs").display().to_string()],
+            &dir.join("logs.zip"),
+            &options,
+            &mut Job::detached(),
+        )
+    }
 
-    fs::create_dir_all(target)
-        .map_err(|e| Error::FileSystem(format!("Failed to create target directory: {}", e)))?;
+    /// Files in `dir` other than the source folder
+    fn outputs(dir: &TestDir) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "logs")
+            .collect();
+        names.sort();
+        names
+    }
 
-    let zip_name = Path::new(source)
-        .file_stem()
-        .unwrap_or_default()
-        .to_string_lossy();
-    let base_folder = Path::new(target).join(zip_name.to_string());
+    #[test]
+    fn existing_archive_is_kept_by_default() {
+        let dir = TestDir::new("zip-conflict-fail");
+        let existing = dir.write("logs.zip", "old");
+
+        let error = zip_over_existing(&dir, Conflict::Fail).unwrap_err();
+        assert!(
+            matches!(&error, Error::FileIo { source, .. } if source.kind() == io::ErrorKind::AlreadyExists),
+            "{}",
+            error
+        );
+        assert_eq!(fs::read_to_string(existing).unwrap(), "old");
+        assert_eq!(outputs(&dir), ["logs.zip"]);
+    }
 
-    fs::create_dir_all(&base_folder)
-        .map_err(|e| Error::FileSystem(format!("Failed to create base folder: {}", e)))?;
+    #[test]
+    fn rename_writes_the_next_free_numbered_name() {
+        let dir = TestDir::new("zip-conflict-rename");
+        dir.write("logs.zip", "old");
+        dir.write("logs (1).zip", "older");
 
-    for i in 0..archive.len() {
-        let mut file = archive
-            .by_index(i)
-            .map_err(|e| Error::FileSystem(format!("Failed to access zip entry {}: {}", i, e)))?;
+        let written = zip_over_existing(&dir, Conflict::Rename).unwrap();
+        assert_eq!(written, dir.join("logs (2).zip"));
+        assert_eq!(fs::read_to_string(dir.join("logs.zip")).unwrap(), "old");
+        assert_eq!(outputs(&dir), ["logs (1).zip", "logs (2).zip", "logs.zip"]);
+    }
 
-        let outpath = base_folder.join(file.mangled_name());
+    #[test]
+    fn overwrite_replaces_the_existing_archive() {
+        let dir = TestDir::new("zip-conflict-overwrite");
+        dir.write("logs.zip", "old");
 
-        if file.name().ends_with('/') {
-            fs::create_dir_all(&outpath)
-                .map_err(|e| Error::FileSystem(format!("Failed to create directory: {}", e)))?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                fs::create_dir_all(p).map_err(|e| {
-                    Error::FileSystem(format!("Failed to create parent directory: {}", e))
-                })?;
-            }
+        let written = zip_over_existing(&dir, Conflict::Overwrite).unwrap();
+        assert_eq!(written, dir.join("logs.zip"));
+        assert_eq!(ArchiveFormat::detect(&written).unwrap(), ArchiveFormat::Zip);
+        assert_eq!(outputs(&dir), ["logs.zip"]);
+    }
 
-            let mut outfile = File::create(&outpath)
-                .map_err(|e| Error::FileSystem(format!("Failed to create output file: {}", e)))?;
-            std::io::copy(&mut file, &mut outfile)
-                .map_err(|e| Error::FileSystem(format!("Failed to extract file: {}", e)))?;
+    #[test]
+    fn cancelled_paste_removes_its_partial_copy() {
+        let dir = TestDir::new("paste-cancel");
+        for name in ["logs/a.txt", "logs/b.txt", "logs/nested/c.txt"] {
+            dir.write(name, name);
         }
+        std::fs::create_dir(dir.join("out")).unwrap();
+        let existing = dir.write("out/kept.txt", "kept");
+
+        let sources = [dir.join("logs").display().to_string()];
+        let target = dir.join("out").display().to_string();
+        let error =
+            handle_paste_multiple(&sources, &target, PasteMode::Move, &mut cancelling_job(1))
+                .unwrap_err();
+
+        assert!(matches!(error, Error::Cancelled), "{}", error);
+        assert!(!dir.join("out/logs").exists());
+        assert_eq!(std::fs::read_to_string(existing).unwrap(), "kept");
+        // A cancelled move leaves its source alone
+        assert!(dir.join("logs/nested/c.txt").exists());
     }
 
-    Ok(json!({
-        "type": "unzip_file_result",
-        "path": base_folder.display().to_string()
-    }))
+    #[test]
+    fn sources_pasted_before_the_cancel_are_kept() {
+        let dir = TestDir::new("paste-partial");
+        let first = dir.write("first.txt", "one");
+        let second = dir.write("second.txt", "two");
+        std::fs::create_dir(dir.join("out")).unwrap();
+
+        let sources = [first, second].map(|path| path.display().to_string());
+        let target = dir.join("out").display().to_string();
+        let error =
+            handle_paste_multiple(&sources, &target, PasteMode::Copy, &mut cancelling_job(1))
+                .unwrap_err();
+
+        assert!(matches!(error, Error::Cancelled), "{}", error);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out/first.txt")).unwrap(),
+            "one"
+        );
+        assert!(!dir.join("out/second.txt").exists());
+    }
 }
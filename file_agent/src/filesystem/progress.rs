@@ -0,0 +1,152 @@
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use walkdir::WalkDir;
+
+/// Snapshot of a running job, as sent in `job_progress` messages
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct JobProgress {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub files_done: u64,
+    pub files_total: u64,
+    pub current_file: Option<String>,
+    /// Average throughput since the job started
+    pub bytes_per_sec: u64,
+}
+
+type ProgressSink = Box<dyn FnMut(&JobProgress) + Send>;
+
+struct Reporter {
+    interval: Duration,
+    last: Option<Instant>,
+    sink: ProgressSink,
+}
+
+/// Context a long-running file operation runs in: it is checked for
+/// cancellation between files and told about every file and byte processed
+pub struct Job {
+    cancel: CancellationToken,
+    reporter: Option<Reporter>,
+    progress: JobProgress,
+    started: Instant,
+}
+
+impl Job {
+    pub fn new(cancel: CancellationToken) -> Self {
+        Self {
+            cancel,
+            reporter: None,
+            progress: JobProgress::default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// A job that is never cancelled and reports nowhere
+    pub fn detached() -> Self {
+        Self::new(CancellationToken::new())
+    }
+
+    /// Pass a snapshot to `sink` at most once per `interval`
+    pub fn with_progress(
+        mut self,
+        interval: Duration,
+        sink: impl FnMut(&JobProgress) + Send + 'static,
+    ) -> Self {
+        self.reporter = Some(Reporter {
+            interval,
+            last: None,
+            sink: Box::new(sink),
+        });
+        self
+    }
+
+    pub fn progress(&self) -> &JobProgress {
+        &self.progress
+    }
+
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Add the files and bytes under `path` to the job's totals
+    pub(crate) fn plan_path(&mut self, path: &Path) -> Result<()> {
+        for entry in WalkDir::new(path) {
+            self.check_cancelled()?;
+            // Unreadable entries fail later, when the operation reaches them
+            let Ok(entry) = entry else { continue };
+            if !entry.file_type().is_dir() {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                self.plan(1, size);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn plan(&mut self, files: u64, bytes: u64) {
+        self.progress.files_total += files;
+        self.progress.bytes_total += bytes;
+    }
+
+    pub(crate) fn start_file(&mut self, path: &Path) {
+        self.progress.current_file = Some(path.display().to_string());
+        self.report();
+    }
+
+    pub(crate) fn advance(&mut self, bytes: u64) {
+        self.progress.bytes_done += bytes;
+        self.report();
+    }
+
+    pub(crate) fn finish_file(&mut self) {
+        self.progress.files_done += 1;
+        self.report();
+    }
+
+    fn report(&mut self) {
+        let Some(reporter) = self.reporter.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        if reporter
+            .last
+            .is_some_and(|last| now - last < reporter.interval)
+        {
+            return;
+        }
+        reporter.last = Some(now);
+
+        let elapsed = (now - self.started).as_secs_f64();
+        if elapsed > 0.0 {
+            self.progress.bytes_per_sec = (self.progress.bytes_done as f64 / elapsed) as u64;
+        }
+        (reporter.sink)(&self.progress);
+    }
+}
+
+/// Counts the bytes read through it towards a [`Job`]
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    job: &'a mut Job,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub(crate) fn new(inner: R, job: &'a mut Job) -> Self {
+        Self { inner, job }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.job.advance(read as u64);
+        Ok(read)
+    }
+}
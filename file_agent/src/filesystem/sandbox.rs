@@ -0,0 +1,309 @@
+use crate::config::SandboxConfig;
+use crate::error::{Error, Result};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// What an operation does with a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Read the path and everything below it, as a copy or archive does
+    ReadTree,
+    /// Create, change, move or remove the path itself
+    Write,
+    /// Add entries to the directory, which may be a root
+    WriteInside,
+}
+
+/// The configured path policy, with its roots resolved once at startup
+///
+/// Paths are checked after resolving symlinks and `..`, so a link inside
+/// an allowed root that points outside it is treated as the outside path.
+/// A path that does not exist yet is checked by its nearest existing
+/// ancestor plus the remaining names. Drives, filesystem roots and the
+/// allowed roots themselves are never writable, and neither is a folder
+/// holding a denied path nor can it be read as a whole.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    roots: Vec<PathBuf>,
+    denied: Vec<PathBuf>,
+    read_only: bool,
+}
+
+impl Sandbox {
+    pub fn new(config: &SandboxConfig) -> Self {
+        let resolve_all = |paths: &[String]| {
+            paths
+                .iter()
+                .map(|path| resolve(Path::new(path)).unwrap_or_else(|_| PathBuf::from(path)))
+                .collect()
+        };
+        Self {
+            roots: resolve_all(&config.allowed_roots),
+            denied: resolve_all(&config.denied_paths),
+            read_only: config.read_only,
+        }
+    }
+
+    /// Check that `path` may be used for `access`, returning the path the
+    /// operation should use: its folder resolved, and its own name kept
+    /// even if it is a symlink, so the link is what gets renamed or deleted
+    /// and copies keep its name. A symlink must be allowed both where it is
+    /// and where it points. Blocking; resolving touches the file system.
+    pub fn check(&self, path: &Path, access: Access) -> Result<PathBuf> {
+        let shown = path.display();
+        let writes = !matches!(access, Access::Read | Access::ReadTree);
+        if writes && self.read_only {
+            return Err(Error::PermissionDenied(format!(
+                "{} cannot be modified, the agent is read-only",
+                shown
+            )));
+        }
+        let resolve_error = |e| Error::file_io(format!("Cannot resolve {}", shown), e);
+        let resolved = resolve(path).map_err(resolve_error)?;
+        self.permit(path, &resolved, access)?;
+
+        let is_link = path.symlink_metadata().is_ok_and(|meta| meta.is_symlink());
+        let (Some(parent), Some(name), true) = (path.parent(), path.file_name(), is_link) else {
+            return Ok(resolved);
+        };
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        let link = resolve(parent).map_err(resolve_error)?.join(name);
+        self.permit(path, &link, access)?;
+        Ok(link)
+    }
+
+    /// Whether the resolved form of `path` may be used for `access`
+    fn permit(&self, path: &Path, resolved: &Path, access: Access) -> Result<()> {
+        let shown = path.display();
+        if !self.roots.is_empty() && !self.roots.iter().any(|root| within(resolved, root)) {
+            return Err(Error::PermissionDenied(format!(
+                "{} is outside the allowed roots",
+                shown
+            )));
+        }
+        if self.denied.iter().any(|denied| within(resolved, denied)) {
+            return Err(Error::PermissionDenied(format!(
+                "{} is a denied path",
+                shown
+            )));
+        }
+        // Deleting, moving, copying or archiving a folder takes everything below it
+        if matches!(access, Access::Write | Access::ReadTree)
+            && self.denied.iter().any(|denied| within(denied, resolved))
+        {
+            return Err(Error::PermissionDenied(format!(
+                "{} contains a denied path",
+                shown
+            )));
+        }
+        // Never delete or replace a whole drive or an allowed root
+        if access == Access::Write
+            && (resolved.parent().is_none() || self.roots.iter().any(|root| same(resolved, root)))
+        {
+            return Err(Error::PermissionDenied(format!(
+                "{} is a root and cannot be modified",
+                shown
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether `path` may be read, for operations that walk a tree. Free
+    /// when no roots or denied paths are configured.
+    pub fn allows_read(&self, path: &Path) -> bool {
+        if self.roots.is_empty() && self.denied.is_empty() {
+            return true;
+        }
+        self.check(path, Access::Read).is_ok()
+    }
+}
+
+/// Canonicalize `path`, or its nearest existing ancestor with the missing
+/// names appended. A `..` after a missing name cannot be resolved and fails
+/// like the missing name itself.
+pub(crate) fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match existing.canonicalize().map(simplify) {
+            Ok(mut resolved) => {
+                for name in missing.iter().rev() {
+                    resolved.push(name);
+                }
+                return Ok(resolved);
+            }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(e) => {
+                // A dangling symlink would be followed when written to
+                if existing.symlink_metadata().is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "symlink target does not exist",
+                    ));
+                }
+                let (Some(parent), Some(Component::Normal(name))) =
+                    (existing.parent(), existing.components().next_back())
+                else {
+                    return Err(e);
+                };
+                missing.push(name.to_os_string());
+                existing = if parent.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    parent
+                };
+            }
+        }
+    }
+}
+
+/// `C:\dir` rather than the `\\?\C:\dir` Windows canonicalizes to, as
+/// resolved paths are handed to operations and show up in replies
+#[cfg(windows)]
+fn simplify(path: PathBuf) -> PathBuf {
+    let text = path.to_string_lossy();
+    let simpler = if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+        Some(format!(r"\\{}", rest))
+    } else {
+        text.strip_prefix(r"\\?\")
+            .filter(|rest| rest.as_bytes().get(1) == Some(&b':'))
+            .map(str::to_string)
+    };
+    simpler.map_or(path, PathBuf::from)
+}
+
+#[cfg(not(windows))]
+fn simplify(path: PathBuf) -> PathBuf {
+    path
+}
+
+pub(crate) fn within(path: &Path, base: &Path) -> bool {
+    if cfg!(windows) {
+        fold_case(path).starts_with(fold_case(base))
+    } else {
+        path.starts_with(base)
+    }
+}
+
+fn same(path: &Path, other: &Path) -> bool {
+    if cfg!(windows) {
+        fold_case(path) == fold_case(other)
+    } else {
+        path == other
+    }
+}
+
+/// Windows paths compare case-insensitively
+fn fold_case(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+
+    fn sandbox(roots: &[&Path], denied: &[&Path]) -> Sandbox {
+        let strings = |paths: &[&Path]| paths.iter().map(|p| p.display().to_string()).collect();
+        Sandbox::new(&SandboxConfig {
+            allowed_roots: strings(roots),
+            denied_paths: strings(denied),
+            read_only: false,
+        })
+    }
+
+    fn denied(result: Result<PathBuf>) -> bool {
+        matches!(result, Err(Error::PermissionDenied(_)))
+    }
+
+    #[test]
+    fn denies_paths_outside_the_roots() {
+        let dir = TestDir::new("sandbox-roots");
+        let root = dir.join("root");
+        dir.write("root/inside.txt", "");
+        let outside = dir.write("outside.txt", "");
+        let sandbox = sandbox(&[&root], &[]);
+
+        assert!(sandbox
+            .check(&root.join("inside.txt"), Access::Read)
+            .is_ok());
+        assert!(denied(sandbox.check(&outside, Access::Read)));
+        assert!(denied(
+            sandbox.check(&root.join("../outside.txt"), Access::Read)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn denies_symlink_pointing_outside_the_roots() {
+        let dir = TestDir::new("sandbox-link");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let outside = dir.write("secret/key.pem", "");
+        std::os::unix::fs::symlink(&outside, root.join("key.pem")).unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("secret")).unwrap();
+        let sandbox = sandbox(&[&root], &[]);
+
+        assert!(denied(sandbox.check(&root.join("key.pem"), Access::Read)));
+        assert!(denied(sandbox.check(&root.join("key.pem"), Access::Write)));
+        assert!(denied(
+            sandbox.check(&root.join("secret/key.pem"), Access::Read)
+        ));
+    }
+
+    #[test]
+    fn folder_holding_a_denied_path_cannot_be_taken_whole() {
+        let dir = TestDir::new("sandbox-denied");
+        let secret = dir.write("projects/app/.env", "");
+        dir.write("projects/app/main.rs", "");
+        let projects = dir.join("projects");
+        let sandbox = sandbox(&[], &[&secret]);
+
+        // Deleting or moving, and copying or archiving
+        assert!(denied(sandbox.check(&projects, Access::Write)));
+        assert!(denied(sandbox.check(&projects, Access::ReadTree)));
+        // Browsing it and changing what else it holds are fine
+        assert!(sandbox.check(&projects, Access::Read).is_ok());
+        assert!(sandbox.check(&projects, Access::WriteInside).is_ok());
+        assert!(sandbox
+            .check(&projects.join("app/main.rs"), Access::Write)
+            .is_ok());
+        assert!(denied(sandbox.check(&secret, Access::Read)));
+    }
+
+    #[test]
+    fn roots_themselves_cannot_be_written() {
+        let dir = TestDir::new("sandbox-root");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let sandbox = sandbox(&[&root], &[]);
+
+        assert!(denied(sandbox.check(&root, Access::Write)));
+        assert!(sandbox.check(&root, Access::WriteInside).is_ok());
+        assert!(sandbox.check(&root.join("new.txt"), Access::Write).is_ok());
+    }
+
+    #[test]
+    fn missing_tail_resolves_through_nearest_existing_ancestor() {
+        let dir = TestDir::new("sandbox-missing");
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        let sandbox = sandbox(&[&root], &[]);
+
+        let path = root.join("sub/../new/file.txt");
+        assert!(matches!(
+            sandbox.check(&path, Access::Write),
+            Err(Error::FileIo { .. })
+        ));
+        let path = root.join("new/folder/file.txt");
+        assert_eq!(sandbox.check(&path, Access::Write).unwrap(), path);
+        assert!(denied(
+            sandbox.check(&dir.join("elsewhere/file.txt"), Access::Write)
+        ));
+    }
+}
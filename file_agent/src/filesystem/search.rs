@@ -0,0 +1,679 @@
+//! Recursive file search by name, size, modification time and content
+//!
+//! Matches are handed out in batches as the walk finds them, so a client
+//! can show the first results of a search that runs for a minute. The walk
+//! stops at the result, depth and time limits, reporting which one it hit.
+
+use super::glob::Glob;
+use super::progress::Job;
+use super::utils::format_iso_time;
+use crate::error::{Error, Result};
+use log::debug;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::{DirEntry, WalkDir};
+
+/// Results returned unless the client asks for fewer
+const DEFAULT_MAX_RESULTS: usize = 1000;
+/// Most results one search returns
+const MAX_RESULTS: usize = 10_000;
+/// Time a search runs for unless the client gives another limit
+const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(60);
+
+/// Matches handed out together while streaming
+const BATCH_SIZE: usize = 50;
+/// Longest a found match waits for its batch to fill
+const BATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Matching lines reported per file
+const MAX_LINES_PER_FILE: usize = 100;
+/// Longest line shown; longer ones are cut, and only this much is searched
+const MAX_LINE_LENGTH: usize = 4096;
+/// Leading bytes checked for a NUL to tell binary files from text
+const BINARY_CHECK_BYTES: usize = 8192;
+
+/// What `search_files` looks for below its root
+///
+/// Every filter given must match. `name` is a [`Glob`] matched against the
+/// file name, or against the path below the root if it contains a `/`;
+/// `name_regex` is matched against the file name. Times are RFC 3339, such
+/// as `2024-05-01T00:00:00Z`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SearchQuery {
+    pub name: Option<String>,
+    pub name_regex: Option<String>,
+    /// Size range in bytes, inclusive
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<String>,
+    pub modified_before: Option<String>,
+    /// Text that matching files contain; a regular expression if
+    /// `content_regex` is set
+    pub content: Option<String>,
+    pub content_regex: bool,
+    /// Applies to `name_regex` and `content`
+    pub case_sensitive: bool,
+    /// Lines shown before and after each content match
+    pub context: usize,
+    /// Report matching folders as well as files
+    pub include_dirs: bool,
+    pub show_hidden: bool,
+    pub follow_links: bool,
+    /// Levels below the root to descend; unlimited if absent
+    pub max_depth: Option<usize>,
+    /// 1000 if absent, at most 10000
+    pub max_results: Option<usize>,
+    /// 60 if absent
+    pub max_seconds: Option<u64>,
+}
+
+impl Default for SearchQuery {
+    fn default() -> Self {
+        Self {
+            name: None,
+            name_regex: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+            content: None,
+            content_regex: false,
+            case_sensitive: false,
+            context: 0,
+            include_dirs: false,
+            show_hidden: true,
+            follow_links: false,
+            max_depth: None,
+            max_results: None,
+            max_seconds: None,
+        }
+    }
+}
+
+/// A file or folder that matched a search
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchMatch {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// ISO-8601 in UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    /// The matching lines, for a content search
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lines: Vec<LineMatch>,
+}
+
+/// A line that matched a content search, with its context
+#[derive(Debug, Clone, Serialize)]
+pub struct LineMatch {
+    /// Counted from 1
+    pub line: u64,
+    pub text: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+}
+
+/// Why a search stopped before walking everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    MaxResults,
+    TimeLimit,
+}
+
+/// How a search went
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchSummary {
+    /// Matches found, whether returned in the reply or streamed
+    pub found: usize,
+    /// Files and folders looked at
+    pub scanned: u64,
+    /// Entries that could not be read
+    pub unreadable: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<StopReason>,
+}
+
+/// A [`SearchQuery`] checked and compiled
+struct Filters {
+    name: Option<Glob>,
+    name_regex: Option<Regex>,
+    content: Option<Regex>,
+    modified_after: Option<SystemTime>,
+    modified_before: Option<SystemTime>,
+}
+
+impl Filters {
+    fn new(query: &SearchQuery) -> Result<Self> {
+        let regex = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(!query.case_sensitive)
+                .build()
+                .map_err(|e| invalid(format!("Invalid regular expression '{}'", pattern), e))
+        };
+        let time = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| {
+                    chrono::DateTime::parse_from_rfc3339(value)
+                        .map(SystemTime::from)
+                        .map_err(|e| invalid(format!("Invalid time '{}'", value), e))
+                })
+                .transpose()
+        };
+        let content = match &query.content {
+            Some(text) if query.content_regex => Some(regex(text)?),
+            Some(text) => Some(regex(&regex::escape(text))?),
+            None => None,
+        };
+        Ok(Self {
+            name: query.name.as_deref().map(Glob::new).transpose()?,
+            name_regex: query.name_regex.as_deref().map(regex).transpose()?,
+            content,
+            modified_after: time(&query.modified_after)?,
+            modified_before: time(&query.modified_before)?,
+        })
+    }
+}
+
+fn invalid(context: String, e: impl ToString) -> Error {
+    Error::file_io(
+        context,
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+    )
+}
+
+/// Search below `root`, handing matches to `found` in batches and leaving
+/// out whatever `allowed` rejects, folders with everything below them
+pub fn search_files(
+    root: &Path,
+    query: &SearchQuery,
+    allowed: impl Fn(&Path) -> bool,
+    job: &Job,
+    mut found: impl FnMut(Vec<SearchMatch>) -> Result<()>,
+) -> Result<SearchSummary> {
+    let filters = Filters::new(query)?;
+    if !root.is_dir() {
+        return Err(Error::FileSystem(format!(
+            "{} is not a directory",
+            root.display()
+        )));
+    }
+    let max_results = query
+        .max_results
+        .unwrap_or(DEFAULT_MAX_RESULTS)
+        .min(MAX_RESULTS);
+    let deadline = Instant::now()
+        + query
+            .max_seconds
+            .map_or(DEFAULT_TIME_LIMIT, Duration::from_secs);
+
+    let mut walk = WalkDir::new(root).follow_links(query.follow_links);
+    if let Some(depth) = query.max_depth {
+        walk = walk.max_depth(depth);
+    }
+    let walk = walk.into_iter().filter_entry(|entry| {
+        entry.depth() == 0 || ((query.show_hidden || !is_hidden(entry)) && allowed(entry.path()))
+    });
+
+    let mut summary = SearchSummary {
+        found: 0,
+        scanned: 0,
+        unreadable: 0,
+        stopped: None,
+    };
+    let mut batch = Vec::new();
+    let mut last_flush = Instant::now();
+    for entry in walk {
+        job.check_cancelled()?;
+        if Instant::now() >= deadline {
+            summary.stopped = Some(StopReason::TimeLimit);
+            break;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Search skipping an entry: {}", e);
+                summary.unreadable += 1;
+                continue;
+            }
+        };
+        if entry.depth() == 0 {
+            continue;
+        }
+        summary.scanned += 1;
+
+        match examine(&entry, root, query, &filters, job, deadline) {
+            Ok(Some(found)) => {
+                batch.push(found);
+                summary.found += 1;
+            }
+            Ok(None) => {}
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(e) => {
+                debug!("Search skipping {}: {}", entry.path().display(), e);
+                summary.unreadable += 1;
+            }
+        }
+
+        if summary.found >= max_results {
+            summary.stopped = Some(StopReason::MaxResults);
+            break;
+        }
+        if batch.len() >= BATCH_SIZE
+            || (!batch.is_empty() && last_flush.elapsed() >= BATCH_INTERVAL)
+        {
+            found(std::mem::take(&mut batch))?;
+            last_flush = Instant::now();
+        }
+    }
+    if !batch.is_empty() {
+        found(batch)?;
+    }
+    Ok(summary)
+}
+
+/// `entry` as a match, or `None` if a filter rejects it
+fn examine(
+    entry: &DirEntry,
+    root: &Path,
+    query: &SearchQuery,
+    filters: &Filters,
+    job: &Job,
+    deadline: Instant,
+) -> Result<Option<SearchMatch>> {
+    let is_dir = entry.file_type().is_dir();
+    if is_dir && (!query.include_dirs || filters.content.is_some()) {
+        return Ok(None);
+    }
+
+    let name = entry.file_name().to_string_lossy();
+    if let Some(glob) = &filters.name {
+        let relative = entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or(entry.path())
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if !glob.matches(&relative) {
+            return Ok(None);
+        }
+    }
+    if filters
+        .name_regex
+        .as_ref()
+        .is_some_and(|regex| !regex.is_match(&name))
+    {
+        return Ok(None);
+    }
+
+    let metadata = entry
+        .metadata()
+        .map_err(|e| Error::FileSystem(e.to_string()))?;
+    let size = if is_dir { 0 } else { metadata.len() };
+    let size_filtered = query.min_size.is_some() || query.max_size.is_some();
+    if size_filtered
+        && (is_dir
+            || query.min_size.is_some_and(|min| size < min)
+            || query.max_size.is_some_and(|max| size > max))
+    {
+        return Ok(None);
+    }
+    let modified = metadata.modified().ok();
+    if filters.modified_after.is_some() || filters.modified_before.is_some() {
+        let Some(time) = modified else {
+            return Ok(None);
+        };
+        if filters.modified_after.is_some_and(|after| time < after)
+            || filters.modified_before.is_some_and(|before| time > before)
+        {
+            return Ok(None);
+        }
+    }
+
+    let lines = match &filters.content {
+        Some(pattern) => {
+            let lines = grep(entry.path(), pattern, query.context, job, deadline)?;
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            lines
+        }
+        None => Vec::new(),
+    };
+
+    Ok(Some(SearchMatch {
+        path: entry.path().display().to_string(),
+        is_dir,
+        size,
+        modified: modified.map(format_iso_time),
+        lines,
+    }))
+}
+
+/// The lines of the text file at `path` that match `pattern`, each with up
+/// to `context` lines around it. Binary files never match.
+fn grep(
+    path: &Path,
+    pattern: &Regex,
+    context: usize,
+    job: &Job,
+    deadline: Instant,
+) -> Result<Vec<LineMatch>> {
+    let file = File::open(path).map_err(|e| Error::file_io("Failed to open file", e))?;
+    let mut reader = BufReader::with_capacity(BINARY_CHECK_BYTES, file);
+    let head = reader
+        .fill_buf()
+        .map_err(|e| Error::file_io("Failed to read file", e))?;
+    if head.contains(&0) {
+        return Ok(Vec::new());
+    }
+
+    let mut matches: Vec<LineMatch> = Vec::new();
+    let mut before = VecDeque::with_capacity(context);
+    let mut owed_after = 0;
+    let mut line = Vec::new();
+    let mut number = 0u64;
+    while read_line(&mut reader, &mut line).map_err(|e| Error::file_io("Failed to read file", e))? {
+        number += 1;
+        if number.is_multiple_of(1024) {
+            job.check_cancelled()?;
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&line);
+        if pattern.is_match(&text) {
+            if matches.len() == MAX_LINES_PER_FILE {
+                break;
+            }
+            matches.push(LineMatch {
+                line: number,
+                text: text.into_owned(),
+                before: before.drain(..).collect(),
+                after: Vec::new(),
+            });
+            owed_after = context;
+        } else if owed_after > 0 {
+            if let Some(last) = matches.last_mut() {
+                last.after.push(text.into_owned());
+            }
+            owed_after -= 1;
+        } else if context > 0 {
+            if before.len() == context {
+                before.pop_front();
+            }
+            before.push_back(text.into_owned());
+        }
+    }
+    Ok(matches)
+}
+
+/// Read the next line into `line` without its line ending, keeping at most
+/// [`MAX_LINE_LENGTH`] bytes of it. `false` at the end of the file.
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> io::Result<bool> {
+    line.clear();
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', line)?;
+    if read == 0 {
+        return Ok(false);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() == MAX_LINE_LENGTH {
+        skip_line(reader)?;
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(true)
+}
+
+/// Consume the rest of an overlong line
+fn skip_line(reader: &mut impl BufRead) -> io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buffer.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn is_hidden(entry: &DirEntry) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    entry
+        .metadata()
+        .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+    use tokio_util::sync::CancellationToken;
+
+    /// Everything `query` finds below `root`, as paths relative to it
+    fn search(root: &TestDir, query: &SearchQuery) -> (Vec<String>, SearchSummary) {
+        search_allowed(root, query, |_| true)
+    }
+
+    fn search_allowed(
+        root: &TestDir,
+        query: &SearchQuery,
+        allowed: impl Fn(&Path) -> bool,
+    ) -> (Vec<String>, SearchSummary) {
+        let mut found = Vec::new();
+        let base = root.path();
+        let summary = search_files(base, query, allowed, &Job::detached(), |batch| {
+            found.extend(batch);
+            Ok(())
+        })
+        .unwrap();
+        let mut paths: Vec<String> = found
+            .iter()
+            .map(|found| {
+                let path = Path::new(&found.path).strip_prefix(base).unwrap();
+                path.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        paths.sort();
+        (paths, summary)
+    }
+
+    #[test]
+    fn filters_by_size_and_modification_time() {
+        let dir = TestDir::new("search-size");
+        dir.write("small.log", [b'x'; 10]);
+        dir.write("medium.log", [b'x'; 100]);
+        dir.write("large.log", [b'x'; 1000]);
+        let old = dir.write("old.log", [b'x'; 100]);
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_262_304_000))
+            .unwrap();
+
+        let query = SearchQuery {
+            min_size: Some(50),
+            max_size: Some(500),
+            ..SearchQuery::default()
+        };
+        assert_eq!(search(&dir, &query).0, ["medium.log", "old.log"]);
+
+        let query = SearchQuery {
+            modified_after: Some("2015-01-01T00:00:00Z".to_string()),
+            ..query
+        };
+        assert_eq!(search(&dir, &query).0, ["medium.log"]);
+
+        let query = SearchQuery {
+            modified_after: None,
+            modified_before: Some("2015-01-01T00:00:00Z".to_string()),
+            ..SearchQuery::default()
+        };
+        assert_eq!(search(&dir, &query).0, ["old.log"]);
+    }
+
+    #[test]
+    fn stops_at_max_depth_and_max_results() {
+        let dir = TestDir::new("search-limits");
+        dir.write("a.txt", "");
+        dir.write("one/b.txt", "");
+        dir.write("one/two/c.txt", "");
+
+        let query = SearchQuery {
+            max_depth: Some(2),
+            ..SearchQuery::default()
+        };
+        let (found, summary) = search(&dir, &query);
+        assert_eq!(found, ["a.txt", "one/b.txt"]);
+        assert_eq!(summary.stopped, None);
+
+        let query = SearchQuery {
+            max_results: Some(2),
+            ..SearchQuery::default()
+        };
+        let (found, summary) = search(&dir, &query);
+        assert_eq!(found.len(), 2);
+        assert_eq!(summary.found, 2);
+        assert_eq!(summary.stopped, Some(StopReason::MaxResults));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_hidden_and_denied_folders() {
+        let dir = TestDir::new("search-skip");
+        dir.write("visible.txt", "");
+        dir.write(".hidden.txt", "");
+        dir.write(".cache/inside.txt", "");
+        let secret = dir.write("secret/keys.txt", "");
+        dir.write("secret/deeper/more.txt", "");
+        let denied = secret.parent().unwrap().to_path_buf();
+        let allowed = |path: &Path| !path.starts_with(&denied);
+
+        let query = SearchQuery {
+            name: Some("*.txt".to_string()),
+            show_hidden: false,
+            ..SearchQuery::default()
+        };
+        assert_eq!(search_allowed(&dir, &query, allowed).0, ["visible.txt"]);
+
+        let query = SearchQuery {
+            show_hidden: true,
+            ..query
+        };
+        assert_eq!(
+            search_allowed(&dir, &query, allowed).0,
+            [".cache/inside.txt", ".hidden.txt", "visible.txt"]
+        );
+    }
+
+    #[test]
+    fn content_matches_carry_context_without_repeating_lines() {
+        let dir = TestDir::new("search-grep");
+        dir.write(
+            "app.log",
+            "start\nERROR one\nerror two\nok\nok again\nquiet\nERROR three\r\nend\n",
+        );
+        dir.write("binary.log", b"ERROR\0\x01\x02");
+        let query = SearchQuery {
+            content: Some("error".to_string()),
+            context: 1,
+            ..SearchQuery::default()
+        };
+        let mut found = Vec::new();
+        search_files(
+            dir.path(),
+            &query,
+            |_| true,
+            &Job::detached(),
+            |batch| {
+                found.extend(batch);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(found.len(), 1, "binary files never match");
+        let lines: Vec<(u64, &str, Vec<String>, Vec<String>)> = found[0]
+            .lines
+            .iter()
+            .map(|line| {
+                (
+                    line.line,
+                    line.text.as_str(),
+                    line.before.clone(),
+                    line.after.clone(),
+                )
+            })
+            .collect();
+        let strings = |lines: &[&str]| lines.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                (2, "ERROR one", strings(&["start"]), strings(&[])),
+                (3, "error two", strings(&[]), strings(&["ok"])),
+                (7, "ERROR three", strings(&["quiet"]), strings(&["end"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn cancellation_stops_the_walk() {
+        let dir = TestDir::new("search-cancel");
+        for i in 0..BATCH_SIZE * 3 {
+            dir.write(&format!("file{}.txt", i), "");
+        }
+        let token = CancellationToken::new();
+        let job = Job::new(token.clone());
+        let mut batches = 0;
+        let result = search_files(
+            dir.path(),
+            &SearchQuery::default(),
+            |_| true,
+            &job,
+            |_| {
+                batches += 1;
+                token.cancel();
+                Ok(())
+            },
+        );
+        assert!(
+            matches!(result, Err(Error::Cancelled)),
+            "{:?}",
+            result.map(|_| ())
+        );
+        assert_eq!(batches, 1);
+    }
+}
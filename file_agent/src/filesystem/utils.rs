@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::time::SystemTime;
 
 /// Extract filename from path
 pub fn extract_filename(path: &str) -> String {
@@ -21,3 +22,66 @@ pub fn get_file_extension(path: &str) -> Option<String> {
         .and_then(|ext| ext.to_str())
         .map(|ext| ext.to_lowercase())
 }
+
+/// `time` in local time as `dd/mm/YYYY, HH:MM:SS`, as listings show it
+pub fn format_local_time(time: SystemTime) -> String {
+    let datetime: chrono::DateTime<chrono::Local> = time.into();
+    datetime.format("%d/%m/%Y, %H:%M:%S").to_string()
+}
+
+/// `time` in UTC as ISO-8601, such as `2024-05-01T12:30:00Z`
+pub fn format_iso_time(time: SystemTime) -> String {
+    let datetime: chrono::DateTime<chrono::Utc> = time.into();
+    datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// MIME types by lowercased extension
+const MIME_TYPES: [(&str, &str); 38] = [
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("ini", "text/plain"),
+    ("cfg", "text/plain"),
+    ("conf", "text/plain"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("tar", "application/x-tar"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("svg", "image/svg+xml"),
+    ("webp", "image/webp"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("exe", "application/vnd.microsoft.portable-executable"),
+    ("dll", "application/vnd.microsoft.portable-executable"),
+    ("msi", "application/x-msi"),
+];
+
+/// Guess a file's MIME type from its extension
+pub fn guess_mime(path: &str) -> Option<&'static str> {
+    let extension = get_file_extension(path)?;
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|&(_, mime)| mime)
+}
@@ -0,0 +1,326 @@
+//! The `hello` handshake
+//!
+//! A client opens with a `hello` request naming the protocol version and
+//! the features, codecs and pixel formats it understands. The agent answers
+//! with what both sides support, and from then on refuses that client's
+//! requests that need anything outside that set. The relay drops agent
+//! messages nobody asked for, so the agent cannot announce itself
+//! unprompted; until a `hello` every enabled feature is available, which
+//! keeps clients that predate the handshake working.
+//!
+//! The relay multiplexes every browser and HTTP caller over the one agent
+//! connection and does not say who sent a request, so a client names its
+//! own `session` in the envelope. An agreement only applies to requests
+//! carrying the same session; a `hello` without one is answered but
+//! restricts nothing.
+
+use crate::{
+    config::FeaturesConfig,
+    network::protocol::{ErrorBody, ErrorCode},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use sysinfo::System;
+
+/// Version of the request/response protocol this agent speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol version still accepted
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Most sessions whose agreements are remembered; the longest unused one
+/// is forgotten first, after which its client gets every offered feature
+const MAX_SESSIONS: usize = 256;
+
+/// Names of the optional features of the file protocol
+pub mod feature {
+    pub const CANCEL: &str = "cancel";
+    pub const PROGRESS: &str = "progress";
+    pub const TRANSFERS: &str = "transfers";
+    pub const BINARY_PAYLOAD: &str = "binary_payload";
+}
+
+/// What an agent supports, as sent in `hello_result`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Capabilities {
+    pub agent: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub protocol_version: u32,
+    pub features: BTreeSet<String>,
+    /// Video codecs, most preferred first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub codecs: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pixel_formats: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new(agent: &str, version: &str) -> Self {
+        Self {
+            agent: agent.to_string(),
+            version: version.to_string(),
+            os: System::long_os_version().unwrap_or_else(|| std::env::consts::OS.to_string()),
+            arch: std::env::consts::ARCH.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            features: BTreeSet::new(),
+            codecs: Vec::new(),
+            pixel_formats: Vec::new(),
+        }
+    }
+
+    /// The file agent with the features enabled in `config`
+    pub fn file_agent(config: &FeaturesConfig) -> Self {
+        let enabled = [
+            (config.cancel, feature::CANCEL),
+            (config.progress, feature::PROGRESS),
+            (config.transfers, feature::TRANSFERS),
+            (config.binary_payload, feature::BINARY_PAYLOAD),
+        ];
+        Self::new("file_agent", env!("CARGO_PKG_VERSION")).with_features(
+            enabled
+                .into_iter()
+                .filter(|(on, _)| *on)
+                .map(|(_, name)| name),
+        )
+    }
+
+    pub fn with_features<'a>(mut self, features: impl IntoIterator<Item = &'a str>) -> Self {
+        self.features
+            .extend(features.into_iter().map(str::to_string));
+        self
+    }
+
+    pub fn with_codecs<'a>(mut self, codecs: impl IntoIterator<Item = &'a str>) -> Self {
+        self.codecs.extend(codecs.into_iter().map(str::to_string));
+        self
+    }
+
+    pub fn with_pixel_formats<'a>(mut self, formats: impl IntoIterator<Item = &'a str>) -> Self {
+        self.pixel_formats
+            .extend(formats.into_iter().map(str::to_string));
+        self
+    }
+}
+
+/// What a client sent in its `hello`. A missing list means it takes
+/// whatever the agent offers.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub features: Option<Vec<String>>,
+    pub codecs: Option<Vec<String>>,
+    pub pixel_formats: Option<Vec<String>>,
+}
+
+/// The offered capabilities and those agreed with each session, shared by
+/// everything serving one relay connection
+#[derive(Debug, Clone)]
+pub struct Negotiation {
+    offered: Arc<Capabilities>,
+    agreed: Arc<Mutex<Sessions>>,
+}
+
+/// Agreements by session, with when each was last used
+#[derive(Debug, Default)]
+struct Sessions {
+    agreed: HashMap<String, (u64, Capabilities)>,
+    clock: u64,
+}
+
+impl Negotiation {
+    pub fn new(offered: Capabilities) -> Self {
+        Self {
+            offered: Arc::new(offered),
+            agreed: Arc::default(),
+        }
+    }
+
+    pub fn offered(&self) -> &Capabilities {
+        &self.offered
+    }
+
+    /// Agree on what both sides support and remember it for later requests
+    /// in `session`
+    pub fn hello(&self, session: Option<&str>, hello: &Hello) -> Result<Capabilities, ErrorBody> {
+        if hello.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(ErrorBody::new(
+                ErrorCode::Unsupported,
+                format!(
+                    "Protocol version {} is not supported; this agent speaks {} to {}",
+                    hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            ));
+        }
+
+        let offered = &self.offered;
+        let agreed = Capabilities {
+            protocol_version: hello.protocol_version.min(PROTOCOL_VERSION),
+            features: match &hello.features {
+                Some(wanted) => wanted
+                    .iter()
+                    .filter(|name| offered.features.contains(*name))
+                    .cloned()
+                    .collect(),
+                None => offered.features.clone(),
+            },
+            codecs: common(&offered.codecs, hello.codecs.as_deref()),
+            pixel_formats: common(&offered.pixel_formats, hello.pixel_formats.as_deref()),
+            ..Capabilities::clone(offered)
+        };
+        if let Some(session) = session {
+            self.agreed
+                .lock()
+                .unwrap()
+                .insert(session.to_string(), agreed.clone());
+        }
+        Ok(agreed)
+    }
+
+    /// Whether requests in `session` may use `feature`: it must be offered,
+    /// and agreed if that session has exchanged a `hello`
+    pub fn allows(&self, session: Option<&str>, feature: &str) -> bool {
+        if let Some(session) = session {
+            if let Some(agreed) = self.agreed.lock().unwrap().touch(session) {
+                return agreed.features.contains(feature);
+            }
+        }
+        self.offered.features.contains(feature)
+    }
+
+    /// Forget every agreement, e.g. when the connection drops
+    pub fn reset(&self) {
+        self.agreed.lock().unwrap().agreed.clear();
+    }
+}
+
+impl Sessions {
+    fn insert(&mut self, session: String, agreed: Capabilities) {
+        if self.agreed.len() >= MAX_SESSIONS && !self.agreed.contains_key(&session) {
+            let oldest = self
+                .agreed
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(session, _)| session.clone());
+            if let Some(oldest) = oldest {
+                self.agreed.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.agreed.insert(session, (self.clock, agreed));
+    }
+
+    /// The agreement for `session`, marked as just used
+    fn touch(&mut self, session: &str) -> Option<&Capabilities> {
+        self.clock += 1;
+        let (used, agreed) = self.agreed.get_mut(session)?;
+        *used = self.clock;
+        Some(agreed)
+    }
+}
+
+/// The entries of `offered` the client also listed, in the client's order
+/// of preference
+fn common(offered: &[String], wanted: Option<&[String]>) -> Vec<String> {
+    match wanted {
+        Some(wanted) => wanted
+            .iter()
+            .filter(|name| offered.contains(name))
+            .cloned()
+            .collect(),
+        None => offered.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiation() -> Negotiation {
+        let offered = Capabilities::new("test", "1.0")
+            .with_features([feature::CANCEL, feature::TRANSFERS])
+            .with_codecs(["h264", "vp8"]);
+        Negotiation::new(offered)
+    }
+
+    fn hello(features: Option<&[&str]>) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            features: features.map(|names| names.iter().map(|name| name.to_string()).collect()),
+            codecs: Some(vec!["vp8".to_string(), "av1".to_string()]),
+            pixel_formats: None,
+        }
+    }
+
+    #[test]
+    fn agreement_is_what_both_sides_support() {
+        let negotiation = negotiation();
+        let agreed = negotiation
+            .hello(
+                Some("a"),
+                &hello(Some(&[feature::CANCEL, feature::PROGRESS])),
+            )
+            .unwrap();
+        assert_eq!(
+            agreed.features,
+            BTreeSet::from([feature::CANCEL.to_string()])
+        );
+        assert_eq!(agreed.codecs, ["vp8"]);
+
+        let everything = negotiation.hello(None, &hello(None)).unwrap();
+        assert_eq!(everything.features, negotiation.offered().features);
+    }
+
+    #[test]
+    fn hello_only_restricts_its_own_session() {
+        let negotiation = negotiation();
+        negotiation
+            .hello(Some("a"), &hello(Some(&[feature::CANCEL])))
+            .unwrap();
+
+        assert!(negotiation.allows(Some("a"), feature::CANCEL));
+        assert!(!negotiation.allows(Some("a"), feature::TRANSFERS));
+        assert!(negotiation.allows(Some("b"), feature::TRANSFERS));
+        assert!(negotiation.allows(None, feature::TRANSFERS));
+        // Never offered, so not available to anyone
+        assert!(!negotiation.allows(None, feature::PROGRESS));
+
+        negotiation.reset();
+        assert!(negotiation.allows(Some("a"), feature::TRANSFERS));
+    }
+
+    #[test]
+    fn sessionless_hello_restricts_nothing() {
+        let negotiation = negotiation();
+        negotiation.hello(None, &hello(Some(&[]))).unwrap();
+        assert!(negotiation.allows(None, feature::CANCEL));
+    }
+
+    #[test]
+    fn rejects_protocol_older_than_supported() {
+        let old = Hello {
+            protocol_version: MIN_PROTOCOL_VERSION - 1,
+            ..hello(None)
+        };
+        let error = negotiation().hello(Some("a"), &old).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Unsupported);
+    }
+
+    #[test]
+    fn forgets_the_longest_unused_session_first() {
+        let negotiation = negotiation();
+        let none: &[&str] = &[];
+        for session in 0..MAX_SESSIONS {
+            negotiation
+                .hello(Some(&session.to_string()), &hello(Some(none)))
+                .unwrap();
+        }
+        // Using session 0 makes session 1 the oldest
+        assert!(!negotiation.allows(Some("0"), feature::CANCEL));
+        negotiation.hello(Some("new"), &hello(Some(none))).unwrap();
+
+        assert!(!negotiation.allows(Some("0"), feature::CANCEL));
+        assert!(negotiation.allows(Some("1"), feature::CANCEL));
+        assert!(!negotiation.allows(Some("new"), feature::CANCEL));
+    }
+}
@@ -1,45 +1,61 @@
 use crate::{
     config::Config,
     error::{Error, Result},
-    network::{handlers::MessageHandler, proxy::ProxyConnector},
+    network::{
+        handlers::MessageHandler,
+        heartbeat::{self, Heartbeat},
+        outbound::{Outbound, OUTBOUND_CAPACITY},
+        reconnect::{Backoff, ConnectionState},
+        transport::{self, WsStream},
+    },
 };
-use futures_util::{SinkExt, StreamExt};
-use log::{error, info, warn};
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{Mutex, RwLock};
-use tokio::time::sleep;
-use tokio_tungstenite::{
-    connect_async_tls_with_config, tungstenite::Message, Connector, MaybeTlsStream, WebSocketStream,
-};
+use tokio::sync::{watch, Mutex, RwLock};
+use tokio_tungstenite::tungstenite::Message;
 use url::Url;
 
-type WebSocketWriter = Arc<
-    Mutex<
-        futures_util::stream::SplitSink<
-            WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-            Message,
-        >,
-    >,
->;
-
 #[derive(Clone)]
 pub struct WebSocketClient {
     config: Arc<Config>,
     message_handler: MessageHandler,
     should_reconnect: Arc<RwLock<bool>>,
+    backoff: Arc<Mutex<Backoff>>,
+    state: watch::Receiver<ConnectionState>,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    rtt: watch::Receiver<Option<Duration>>,
 }
 
 impl WebSocketClient {
     pub fn new(config: Arc<Config>) -> Self {
-        let message_handler = MessageHandler::new();
+        let message_handler = MessageHandler::new(&config);
+        let backoff = Backoff::new(config.reconnect.clone());
+        let state = backoff.subscribe();
+        let heartbeat = Heartbeat::new(config.heartbeat.clone());
+        let rtt = heartbeat.subscribe();
         Self {
             config,
             message_handler,
             should_reconnect: Arc::new(RwLock::new(true)),
+            backoff: Arc::new(Mutex::new(backoff)),
+            state,
+            heartbeat: Arc::new(Mutex::new(heartbeat)),
+            rtt,
         }
     }
 
+    /// Observe the relay connection state as it changes
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// Round-trip time measured by the most recent heartbeat
+    pub fn round_trip_time(&self) -> watch::Receiver<Option<Duration>> {
+        self.rtt.clone()
+    }
+
     pub async fn stop_reconnection(&self) {
         let mut should_reconnect = self.should_reconnect.write().await;
         *should_reconnect = false;
@@ -54,22 +70,36 @@ impl WebSocketClient {
                 let should_reconnect = self.should_reconnect.read().await;
                 if !*should_reconnect {
                     info!("Reconnection stopped by user request");
+                    self.backoff.lock().await.on_stopped();
                     break;
                 }
             }
 
-            match self.try_connect().await {
+            self.backoff.lock().await.on_connecting();
+            let result = self.try_connect().await;
+
+            let mut backoff = self.backoff.lock().await;
+            let delay = backoff.next_delay();
+            match result {
                 Ok(()) => {
-                    info!("WebSocket connection established successfully");
-                    // Connection was successful but closed, continue reconnecting
-                    warn!("Connection closed, attempting to reconnect...");
+                    warn!(
+                        "Connection closed, reconnecting in {:.1}s (attempt {})",
+                        delay.as_secs_f64(),
+                        backoff.attempt()
+                    );
                 }
                 Err(e) => {
-                    error!("Connection failed: {}. Retrying in 1 second...", e);
+                    error!(
+                        "Connection failed: {}. Retrying in {:.1}s (attempt {})",
+                        e,
+                        delay.as_secs_f64(),
+                        backoff.attempt()
+                    );
                 }
             }
+            drop(backoff);
 
-            sleep(Duration::from_secs(1)).await;
+            tokio::time::sleep(delay).await;
         }
 
         Ok(())
@@ -79,104 +109,45 @@ impl WebSocketClient {
         let url = Url::parse(&self.config.get_websocket_url())
             .map_err(|e| Error::Config(format!("Invalid WebSocket URL: {}", e)))?;
 
-        let ws_stream = if self.config.use_proxy {
-            self.connect_through_proxy(&url).await?
-        } else {
-            self.connect_direct(&url).await?
-        };
+        let ws_stream = transport::connect(&self.config, &url).await?;
+        self.backoff.lock().await.on_connected();
 
         self.handle_connection(ws_stream).await
     }
 
-    async fn connect_direct(
-        &self,
-        url: &Url,
-    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>> {
-        let tls_connector = if self.config.use_ssl {
-            let mut builder = native_tls::TlsConnector::builder();
-            builder.danger_accept_invalid_certs(true);
-            builder.danger_accept_invalid_hostnames(true);
-            Some(Connector::NativeTls(builder.build().unwrap()))
-        } else {
-            None
-        };
-
-        let (ws_stream, _) = connect_async_tls_with_config(url, None, false, tls_connector)
-            .await
-            .map_err(|e| Error::Network(format!("WebSocket connection failed: {}", e)))?;
-
-        Ok(ws_stream)
-    }
-
-    async fn connect_through_proxy(
-        &self,
-        url: &Url,
-    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>> {
-        let host = url.host_str().unwrap_or("localhost");
-        let port = url
-            .port()
-            .unwrap_or(if self.config.use_ssl { 443 } else { 80 });
-
-        // Parse proxy port
-        let proxy_port: u16 = self
-            .config
-            .proxy_port
-            .as_ref()
-            .ok_or_else(|| Error::Config("Proxy port not configured".to_string()))?
-            .parse()
-            .map_err(|e| Error::Config(format!("Invalid proxy port: {}", e)))?;
-
-        // Create proxy connector
-        let proxy_connector = ProxyConnector::new(
-            self.config.proxy_url.clone().unwrap_or_default(),
-            proxy_port,
-            host.to_string(),
-            port,
-            self.config.proxy_auth.clone(),
-            self.config.no_auth,
-        );
-
-        // Connect through proxy
-        let std_stream = proxy_connector.connect()?;
-
-        std_stream.set_nonblocking(true)?;
-        let tokio_stream = tokio::net::TcpStream::from_std(std_stream)?;
-
-        // Handle TLS if needed
-        let stream = if self.config.use_ssl {
-            let mut builder = native_tls::TlsConnector::builder();
-            builder.danger_accept_invalid_certs(true);
-            builder.danger_accept_invalid_hostnames(true);
-            let tls_connector = tokio_native_tls::TlsConnector::from(builder.build().unwrap());
-
-            let tls_stream = tls_connector
-                .connect(host, tokio_stream)
-                .await
-                .map_err(|e| Error::Network(format!("TLS handshake failed: {}", e)))?;
-
-            MaybeTlsStream::NativeTls(tls_stream)
-        } else {
-            MaybeTlsStream::Plain(tokio_stream)
-        };
-
-        // Perform WebSocket handshake
-        let (ws_stream, _) = tokio_tungstenite::client_async(url, stream)
-            .await
-            .map_err(|e| Error::Network(format!("WebSocket handshake failed: {}", e)))?;
-
-        Ok(ws_stream)
-    }
-
-    async fn handle_connection(
-        &self,
-        ws_stream: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-    ) -> Result<()> {
+    async fn handle_connection(&self, ws_stream: WsStream) -> Result<()> {
         info!("WebSocket connection established, starting message loop");
 
         let (writer, mut reader) = ws_stream.split();
-        let writer = Arc::new(Mutex::new(writer));
+        let (out, writer_task) = Outbound::spawn(writer, OUTBOUND_CAPACITY);
+
+        let mut heartbeat = self.heartbeat.lock().await;
+        heartbeat.reset();
+
+        loop {
+            let msg = tokio::select! {
+                msg = reader.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = heartbeat::sleep_until(heartbeat.deadline()) => {
+                    match heartbeat.on_deadline() {
+                        Ok(Some(ping)) => {
+                            if let Err(e) = out.send(ping).await {
+                                error!("Error sending heartbeat ping: {} - connection lost", e);
+                                break;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("{} - reconnecting", e);
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
 
-        while let Some(msg) = reader.next().await {
             // Check if we should continue processing
             {
                 let should_reconnect = self.should_reconnect.read().await;
@@ -190,7 +161,7 @@ impl WebSocketClient {
                 Ok(Message::Binary(data)) => {
                     if let Err(e) = self
                         .message_handler
-                        .handle_binary_message(&data, &writer)
+                        .handle_binary_message(&data, &out)
                         .await
                     {
                         error!("Error handling binary message: {}", e);
@@ -198,11 +169,7 @@ impl WebSocketClient {
                     }
                 }
                 Ok(Message::Text(text)) => {
-                    if let Err(e) = self
-                        .message_handler
-                        .handle_text_message(&text, &writer)
-                        .await
-                    {
+                    if let Err(e) = self.message_handler.handle_text_message(&text, &out).await {
                         error!("Error handling text message: {}", e);
                         // Continue processing other messages
                     }
@@ -212,14 +179,15 @@ impl WebSocketClient {
                     break;
                 }
                 Ok(Message::Ping(data)) => {
-                    let mut writer = writer.lock().await;
-                    if let Err(e) = writer.send(Message::Pong(data)).await {
+                    if let Err(e) = out.send(Message::Pong(data)).await {
                         error!("Error sending pong: {} - connection may be lost", e);
                         break;
                     }
                 }
-                Ok(Message::Pong(_)) => {
-                    // Ignore pong messages
+                Ok(Message::Pong(data)) => {
+                    if let Some(rtt) = heartbeat.on_pong(&data) {
+                        debug!("Heartbeat round trip {} ms", rtt.as_millis());
+                    }
                 }
                 Ok(Message::Frame(_)) => {
                     // Ignore frame messages
@@ -231,6 +199,10 @@ impl WebSocketClient {
             }
         }
 
+        // Replies to outstanding requests have nowhere to go now
+        self.message_handler.cancel_all();
+        writer_task.abort();
+
         info!("Connection loop ended, returning for reconnection");
         Ok(())
     }
@@ -1,606 +1,1442 @@
 use crate::{
+    config::{ArchiveConfig, Config, OperationsConfig, TransferConfig},
     error::{Error, Result},
-    filesystem::operations as fs_ops,
+    filesystem::{
+        listing, operations as fs_ops, search, Access, Job, PasteMode, Sandbox, SearchMatch,
+        SearchQuery,
+    },
+    network::capabilities::{feature, Capabilities, Negotiation},
+    network::outbound::Outbound,
+    network::payload,
+    network::protocol::{
+        ErrorBody, ErrorCode, PayloadEncoding, ProgressReport, Reply, Request, RequestEnvelope,
+        Response, SearchResults,
+    },
+    network::transfer::{self, Download, Frame, FrameKind, Transfers},
+    network::upload::{UploadSession, Uploads},
     system::info as system_info,
 };
-use futures_util::SinkExt;
-use log::{debug, error};
-use serde_json::{json, Value};
-use std::sync::Arc;
-use tokio::sync::Mutex;
-
-use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
-type WebSocketWriter = Arc<
-    Mutex<
-        futures_util::stream::SplitSink<
-            WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-            Message,
-        >,
-    >,
->;
+use base64::{engine::general_purpose, Engine as _};
+use log::{debug, error, info, warn};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
 
 /// Handles incoming WebSocket messages and routes them to appropriate handlers
+///
+/// Binary control frames are handled inline, in the order they arrive. Text
+/// requests run concurrently on a bounded pool and their replies are matched
+/// up by `request_id`, so they may complete in any order.
 #[derive(Clone)]
-pub struct MessageHandler;
+pub struct MessageHandler {
+    limits: OperationsConfig,
+    permits: Arc<Semaphore>,
+    /// Requests accepted but not yet replied to, running or waiting
+    pending: Arc<AtomicUsize>,
+    in_flight: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    /// Parent of every request token; replaced by [`Self::cancel_all`]
+    session: Arc<Mutex<CancellationToken>>,
+    transfer: TransferConfig,
+    transfers: Transfers,
+    uploads: Uploads,
+    negotiation: Negotiation,
+    sandbox: Arc<Sandbox>,
+    archive: ArchiveConfig,
+}
 
 impl MessageHandler {
-    pub fn new() -> Self {
-        Self
+    pub fn new(config: &Config) -> Self {
+        Self::with_capabilities(config, Capabilities::file_agent(&config.features))
+    }
+
+    /// A handler offering `capabilities` in its `hello_result`, for agents
+    /// that serve the file protocol alongside their own
+    pub fn with_capabilities(config: &Config, capabilities: Capabilities) -> Self {
+        let limits = config.operations.clone();
+        let permits = Arc::new(Semaphore::new(limits.max_concurrent.max(1)));
+        Self {
+            limits,
+            transfer: config.transfer.clone(),
+            transfers: Transfers::default(),
+            uploads: Uploads::default(),
+            permits,
+            pending: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            session: Arc::new(Mutex::new(CancellationToken::new())),
+            negotiation: Negotiation::new(capabilities),
+            sandbox: Arc::new(Sandbox::new(&config.sandbox)),
+            archive: config.archive.clone(),
+        }
+    }
+
+    /// The capabilities agreed with the client, shared with the handler
+    pub fn negotiation(&self) -> &Negotiation {
+        &self.negotiation
     }
 
     /// Handle incoming text messages
-    pub async fn handle_text_message(&self, text: &str, writer: &WebSocketWriter) -> Result<()> {
+    ///
+    /// Returns once the request is queued; the reply is sent through `out`
+    /// when it completes.
+    pub async fn handle_text_message(&self, text: &str, out: &Outbound) -> Result<()> {
         debug!("Received text message: {}", text);
+        self.handle_request_message(text, None, out).await
+    }
 
-        let msg: Value = serde_json::from_str(text).map_err(|e| Error::Json(e))?;
-
-        let msg_type = msg["type"].as_str().unwrap_or_default();
-
-        match msg_type {
-            "list_remote" => handle_list_remote(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "rename" => handle_rename(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "delete" => handle_delete(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "create_folder" => handle_folder_creation(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "upload_file" => handle_upload_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "download_file" => handle_download_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "paste_file" => handle_paste_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "edit_file" => handle_edit_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "save_file" => handle_save_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "zip_file" => handle_zip_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "unzip_file" => handle_unzip_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "open_file" => handle_open_file(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "get_agent_details" => handle_get_agent_details(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            "get_installed_software" => handle_get_installed_software(&msg, writer)
-                .await
-                .map_err(|e| Error::Network(e)),
-            _ => {
-                error!("Unknown message type: {}", msg_type);
-                Ok(())
-            }
-        }
-    }
-
-    pub async fn handle_binary_message(&self, data: &[u8], writer: &WebSocketWriter) -> Result<()> {
-        if data.len() >= 6 {
-            let msg_type = u16::from_be_bytes([data[0], data[1]]);
-            match msg_type {
-                73 => {
-                    if data.len() == 6 && data[4] == 0 && data[5] == 1 {
-                        debug!("Received unpause command");
-                    }
-                }
-                74 => {
-                    if data.len() == 6 && data[4] == 0 && data[5] == 1 {
-                        debug!("Received pause command");
-                    }
+    /// Handle a request given as JSON, with the file contents from the body
+    /// of a payload frame if it arrived in one
+    async fn handle_request_message(
+        &self,
+        text: &str,
+        body: Option<Vec<u8>>,
+        out: &Outbound,
+    ) -> Result<()> {
+        let RequestEnvelope {
+            request_id,
+            session,
+            progress,
+            payload,
+            request,
+        } = match RequestEnvelope::parse(text) {
+            Ok(envelope) => envelope,
+            Err(reply) => {
+                if let Some(error) = &reply.error {
+                    error!("Rejected message: {}", error.message);
                 }
-                _ => {
-                    debug!("Unknown binary message type: {}", msg_type);
+                // Without an id the relay could not route the reply anyway
+                if reply.request_id.is_some() {
+                    send_reply(&reply, out).await?;
                 }
+                return Ok(());
             }
+        };
+
+        if let Request::Hello(hello) = &request {
+            let reply = match self.negotiation.hello(session.as_deref(), hello) {
+                Ok(agreed) => {
+                    info!(
+                        "Negotiated protocol {} with features {:?}",
+                        agreed.protocol_version, agreed.features
+                    );
+                    Reply::success(request_id, Response::HelloResult(agreed))
+                }
+                Err(error) => Reply::failure_with("hello_result", request_id, error),
+            };
+            return send_reply(&reply, out).await;
         }
-        Ok(())
-    }
-}
 
-async fn handle_list_remote(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let path = msg["path"].as_str().unwrap_or("");
-    let request_id = msg["request_id"].as_str();
-
-    let mut response = if path.is_empty() {
-        // Return drives in the format expected by frontend
-        let drives = fs_ops::get_drives();
-        let entries: Vec<serde_json::Value> = drives
-            .into_iter()
-            .map(|drive| {
-                json!({
-                    "name": drive,
-                    "is_dir": true,
-                    "size": 0,
-                    "date": "Drive"
-                })
-            })
-            .collect();
+        let uses = [
+            (matches!(request, Request::Cancel { .. }), feature::CANCEL),
+            (progress, feature::PROGRESS),
+            (
+                matches!(
+                    request,
+                    Request::DownloadStart { .. }
+                        | Request::DownloadAck { .. }
+                        | Request::UploadBegin { .. }
+                        | Request::UploadCommit { .. }
+                        | Request::UploadAbort { .. }
+                ),
+                feature::TRANSFERS,
+            ),
+            (
+                body.is_some() || payload == PayloadEncoding::Binary,
+                feature::BINARY_PAYLOAD,
+            ),
+        ];
+        if let Some((_, missing)) = uses
+            .iter()
+            .find(|(used, name)| *used && !self.negotiation.allows(session.as_deref(), name))
+        {
+            warn!(
+                "Rejecting {}: '{}' was not negotiated",
+                request.name(),
+                missing
+            );
+            let reply = Reply::failure_with(
+                &format!("{}_result", request.name()),
+                request_id,
+                ErrorBody::new(
+                    ErrorCode::Unsupported,
+                    format!("Feature '{}' was not negotiated", missing),
+                ),
+            );
+            return send_reply(&reply, out).await;
+        }
 
-        json!({
-            "entries": entries,
-            "path": path
+        // Every path a request names is checked before anything runs;
+        // resolving them touches the disk, so do it on the blocking pool
+        let kind = format!("{}_result", request.name());
+        let sandbox = Arc::clone(&self.sandbox);
+        let checked = tokio::task::spawn_blocking(move || {
+            let allowed = authorize(&sandbox, &request);
+            (request, allowed)
         })
-    } else {
-        // List directory contents in the expected format
-        match std::fs::read_dir(path) {
-            Ok(entries) => {
-                let entries: Vec<serde_json::Value> = entries
-                    .filter_map(|res| res.ok())
-                    .filter_map(|entry| {
-                        let metadata = entry.metadata().ok()?;
-                        let modified = metadata.modified().ok().and_then(|time| {
-                            let datetime: chrono::DateTime<chrono::Local> = time.into();
-                            Some(datetime.format("%d/%m/%Y, %H:%M:%S").to_string())
-                        });
-
-                        Some(json!({
-                            "name": entry.file_name().to_string_lossy(),
-                            "is_dir": metadata.is_dir(),
-                            "size": metadata.len(),
-                            "date": modified.unwrap_or_else(|| "Unknown".to_string())
-                        }))
-                    })
-                    .collect();
-
-                json!({
-                    "entries": entries,
-                    "path": path
-                })
+        .await;
+        let (request, resolved) = match checked {
+            Ok((request, Ok(resolved))) => (request, resolved),
+            Ok((request, Err(e))) => {
+                warn!("Denied {}: {}", request.name(), e);
+                let reply = Reply::failure(&request, request_id, &e);
+                return send_reply(&reply, out).await;
             }
             Err(e) => {
-                json!({
-                    "entries": [],
-                    "path": path,
-                    "error": format!("Failed to list directory: {}", e)
-                })
+                error!("Path check panicked: {}", e);
+                let reply = Reply::failure_with(
+                    &kind,
+                    request_id,
+                    ErrorBody::new(ErrorCode::Internal, "Request handler panicked"),
+                );
+                return send_reply(&reply, out).await;
+            }
+        };
+
+        // Control messages bypass the queue so they work even when it is
+        // full, and downloads run outside it so they cannot starve it
+        match request {
+            Request::Cancel { target_request_id } => {
+                return self.handle_cancel(target_request_id, request_id, out).await;
+            }
+            Request::DownloadAck {
+                transfer_id,
+                offset,
+            } => {
+                return self
+                    .handle_download_ack(transfer_id, offset, request_id, out)
+                    .await
+            }
+            Request::DownloadStart {
+                path,
+                offset,
+                chunk_size,
+            } => {
+                let path = resolved.path(&path).to_string();
+                return self
+                    .start_download(path, offset, chunk_size, request_id, out)
+                    .await;
+            }
+            Request::UploadBegin {
+                path,
+                filename,
+                size,
+                sha256,
+                overwrite,
+                chunk_size,
+            } => {
+                let upload = PendingUpload {
+                    path: resolved.path(&path).to_string(),
+                    filename,
+                    size,
+                    sha256,
+                    overwrite,
+                    chunk_size: self.transfer.chunk_size_for(chunk_size),
+                };
+                return self.begin_upload(upload, request_id, out).await;
+            }
+            Request::UploadCommit { upload_id } => {
+                self.finish_upload(upload_id, true, request_id, out);
+                return Ok(());
             }
+            Request::UploadAbort { upload_id } => {
+                self.finish_upload(upload_id, false, request_id, out);
+                return Ok(());
+            }
+            _ => {}
         }
-    };
 
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+        let capacity = self.limits.max_concurrent + self.limits.max_queued;
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= capacity {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Rejecting {}: {} requests pending",
+                request.name(),
+                capacity
+            );
+            let reply = Reply::failure_with(
+                &format!("{}_result", request.name()),
+                request_id,
+                ErrorBody::new(ErrorCode::Busy, "Too many requests in progress"),
+            );
+            return send_reply(&reply, out).await;
+        }
+
+        let token = match self.register(request_id.as_deref()) {
+            Some(token) => token,
+            None => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                let reply = Reply::failure_with(
+                    &format!("{}_result", request.name()),
+                    request_id,
+                    ErrorBody::new(
+                        ErrorCode::InvalidRequest,
+                        "A request with this request_id is already in progress",
+                    ),
+                );
+                return send_reply(&reply, out).await;
+            }
+        };
+
+        let handler = self.clone();
+        let out = out.clone();
+        tokio::spawn(async move {
+            let mut job = Job::new(token.clone());
+            if let (true, Some(id), Some(interval)) = (
+                progress,
+                request_id.clone(),
+                handler.limits.progress_interval(),
+            ) {
+                let kind = request.name();
+                let updates = out.clone();
+                job = job.with_progress(interval, move |progress| {
+                    let report = ProgressReport {
+                        request_id: &id,
+                        job: kind,
+                        progress,
+                    };
+                    updates.try_send(Message::Text(report.to_json()));
+                });
+            }
+            let matches = match (&request, &request_id) {
+                (Request::SearchFiles { stream: true, .. }, Some(id)) => {
+                    let id = id.clone();
+                    let batches = out.clone();
+                    let sink: MatchSink = Box::new(move |matches| {
+                        let batch = SearchResults {
+                            request_id: &id,
+                            matches,
+                        };
+                        batches.blocking_send(Message::Text(batch.to_json()))
+                    });
+                    Some(sink)
+                }
+                _ => None,
+            };
+            let payload = Payload {
+                encoding: payload,
+                body,
+            };
+            let context = Context {
+                archive: handler.archive.clone(),
+                sandbox: Arc::clone(&handler.sandbox),
+                resolved,
+                matches,
+            };
+            let reply = handler
+                .run(request, payload, request_id.clone(), token, job, context)
+                .await;
+            if let Some(id) = &request_id {
+                handler.in_flight.lock().unwrap().remove(id);
+            }
+            handler.pending.fetch_sub(1, Ordering::SeqCst);
+            if let Some(reply) = reply {
+                if let Err(e) = send_reply(&reply, &out).await {
+                    error!("{}", e);
+                }
+            }
+        });
+        Ok(())
     }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("List remote successfully");
+    async fn handle_cancel(
+        &self,
+        target_request_id: String,
+        request_id: Option<String>,
+        out: &Outbound,
+    ) -> Result<()> {
+        let reply = if self.cancel(&target_request_id) {
+            debug!("Cancelling request {}", target_request_id);
+            Reply::success(request_id, Response::CancelResult { target_request_id })
+        } else {
+            Reply::failure_with(
+                "cancel_result",
+                request_id,
+                ErrorBody::new(
+                    ErrorCode::NotFound,
+                    format!("No request '{}' is in progress", target_request_id),
+                ),
+            )
+        };
+        send_reply(&reply, out).await
+    }
 
-    Ok(())
-}
-async fn handle_rename(msg: &Value, writer: &WebSocketWriter) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
-    let response = match fs_ops::handle_rename(msg) {
-        Ok(_) => {
-            let mut response_json = json!({
-                "type": "rename_result",
-                "status": "success",
-                "old_path": msg["old_path"],
-                "new_path": msg["new_path"]
-            });
-            if let Some(req_id) = request_id {
-                response_json["request_id"] = json!(req_id);
-            }
-            response_json
-        }
-        Err(e) => {
-            let mut error_json = json!({
-                "type": "error",
-                "message": format!("Rename failed: {}", e)
-            });
-            if let Some(req_id) = request_id {
-                error_json["request_id"] = json!(req_id);
-            }
-            error_json
+    /// Acks are only answered when they carry an id, as they do when sent
+    /// through the relay's request API
+    async fn handle_download_ack(
+        &self,
+        transfer_id: u32,
+        offset: u64,
+        request_id: Option<String>,
+        out: &Outbound,
+    ) -> Result<()> {
+        let known = self.transfers.ack(transfer_id, offset);
+        if !known {
+            debug!("Ack for unknown transfer {}", transfer_id);
         }
-    };
+        if request_id.is_none() {
+            return Ok(());
+        }
+        let reply = if known {
+            Reply::success(
+                request_id,
+                Response::DownloadAckResult {
+                    transfer_id,
+                    offset,
+                },
+            )
+        } else {
+            Reply::failure_with(
+                "download_ack_result",
+                request_id,
+                ErrorBody::new(
+                    ErrorCode::NotFound,
+                    format!("No transfer {} is in progress", transfer_id),
+                ),
+            )
+        };
+        send_reply(&reply, out).await
+    }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("Rename successfully {:?}", msg);
+    /// Hash the file and reply with the offer, then stream the chunks in
+    /// the background. The download can be cancelled by its request id.
+    async fn start_download(
+        &self,
+        path: String,
+        offset: u64,
+        chunk_size: Option<u32>,
+        request_id: Option<String>,
+        out: &Outbound,
+    ) -> Result<()> {
+        let Some(transfer_id) = self.transfers.register(self.transfer.max_transfers) else {
+            let reply = Reply::failure_with(
+                "download_start_result",
+                request_id,
+                ErrorBody::new(ErrorCode::Busy, "Too many transfers in progress"),
+            );
+            return send_reply(&reply, out).await;
+        };
+        let Some(token) = self.register(request_id.as_deref()) else {
+            self.transfers.remove(transfer_id);
+            let reply = Reply::failure_with(
+                "download_start_result",
+                request_id,
+                ErrorBody::new(
+                    ErrorCode::InvalidRequest,
+                    "A request with this request_id is already in progress",
+                ),
+            );
+            return send_reply(&reply, out).await;
+        };
+
+        let download = PendingDownload {
+            transfer_id,
+            path,
+            offset,
+            chunk_size: self.transfer.chunk_size_for(chunk_size),
+            request_id,
+        };
+        let handler = self.clone();
+        let out = out.clone();
+        tokio::spawn(async move {
+            let request_id = download.request_id.clone();
+            handler.download(download, token, &out).await;
+            if let Some(id) = &request_id {
+                handler.in_flight.lock().unwrap().remove(id);
+            }
+            handler.transfers.remove(transfer_id);
+        });
+        Ok(())
+    }
 
-    Ok(())
-}
+    async fn download(&self, pending: PendingDownload, token: CancellationToken, out: &Outbound) {
+        let PendingDownload {
+            transfer_id,
+            path,
+            offset,
+            chunk_size,
+            request_id,
+        } = pending;
+
+        let job = Job::new(token.clone());
+        let prepared = tokio::task::spawn_blocking(move || {
+            Download::prepare(transfer_id, &path, offset, chunk_size, &job)
+        })
+        .await;
+
+        let download = match prepared {
+            Ok(Ok(download)) => download,
+            Ok(Err(e)) => {
+                error!("download_start failed: {}", e);
+                let reply = Reply::failure_with("download_start_result", request_id, (&e).into());
+                if let Err(e) = send_reply(&reply, out).await {
+                    error!("{}", e);
+                }
+                return;
+            }
+            Err(e) => {
+                error!("download_start panicked: {}", e);
+                return;
+            }
+        };
 
-async fn handle_delete(msg: &Value, writer: &WebSocketWriter) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
-    let response = match fs_ops::handle_delete(msg) {
-        Ok(_) => {
-            let mut response_json = json!({
-                "type": "delete_result",
-                "status": "success"
-            });
-            if let Some(req_id) = request_id {
-                response_json["request_id"] = json!(req_id);
-            }
-            response_json
-        }
-        Err(e) => {
-            let mut error_json = json!({
-                "type": "error",
-                "message": format!("Delete failed: {}", e)
-            });
-            if let Some(req_id) = request_id {
-                error_json["request_id"] = json!(req_id);
-            }
-            error_json
+        let window = self.transfer.window_chunks;
+        let offer = Reply::success(request_id, download.offer(window));
+        if let Err(e) = send_reply(&offer, out).await {
+            error!("{}", e);
+            return;
         }
-    };
-
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File deleted successfully: {:?}", msg);
 
-    Ok(())
-}
+        let idle_timeout = self.transfer.idle_timeout();
+        if let Err(error) = download
+            .stream(&self.transfers, out, window, idle_timeout, &token)
+            .await
+        {
+            warn!("Transfer {} stopped: {}", transfer_id, error.message);
+            let _ = out.send(transfer::error_frame(transfer_id, &error)).await;
+        }
+    }
 
-async fn handle_folder_creation(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
-    let response = match fs_ops::handle_folder_creation(msg) {
-        Ok(_) => {
-            let mut response_json = json!({
-                "type": "create_folder_result",
-                "status": "success"
-            });
-            if let Some(req_id) = request_id {
-                response_json["request_id"] = json!(req_id);
-            }
-            response_json
-        }
-        Err(e) => {
-            let mut error_json = json!({
-                "type": "error",
-                "message": format!("Folder creation failed: {}", e)
-            });
-            if let Some(req_id) = request_id {
-                error_json["request_id"] = json!(req_id);
-            }
-            error_json
+    async fn begin_upload(
+        &self,
+        upload: PendingUpload,
+        request_id: Option<String>,
+        out: &Outbound,
+    ) -> Result<()> {
+        // Held from here, so a burst of begins cannot all pass the limit
+        // while the first ones are still hashing
+        let upload_id = self.transfers.next_id();
+        if !self.uploads.reserve(upload_id, self.transfer.max_transfers) {
+            let reply = Reply::failure_with(
+                "upload_begin_result",
+                request_id,
+                ErrorBody::new(ErrorCode::Busy, "Too many uploads in progress"),
+            );
+            return send_reply(&reply, out).await;
         }
-    };
+        let Some(token) = self.register(request_id.as_deref()) else {
+            self.uploads.release(upload_id);
+            let reply = Reply::failure_with(
+                "upload_begin_result",
+                request_id,
+                ErrorBody::new(
+                    ErrorCode::InvalidRequest,
+                    "A request with this request_id is already in progress",
+                ),
+            );
+            return send_reply(&reply, out).await;
+        };
+
+        let handler = self.clone();
+        let out = out.clone();
+        tokio::spawn(async move {
+            // Resuming re-hashes what was already received, which may take a while
+            let job = Job::new(token);
+            let chunk_size = upload.chunk_size;
+            let begun = tokio::task::spawn_blocking(move || {
+                UploadSession::begin(
+                    &upload.path,
+                    &upload.filename,
+                    upload.size,
+                    &upload.sha256,
+                    upload.overwrite,
+                    &job,
+                )
+            })
+            .await;
+            if let Some(id) = &request_id {
+                handler.in_flight.lock().unwrap().remove(id);
+            }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("Folder created successfully: {:?}", msg);
+            let reply = match begun {
+                Ok(Ok(session)) => {
+                    let response = Response::UploadBeginResult {
+                        upload_id,
+                        path: session.target().display().to_string(),
+                        offset: session.written(),
+                        chunk_size,
+                    };
+                    handler.uploads.insert(upload_id, session);
+                    Reply::success(request_id, response)
+                }
+                Ok(Err(e)) => {
+                    handler.uploads.release(upload_id);
+                    error!("upload_begin failed: {}", e);
+                    Reply::failure_with("upload_begin_result", request_id, (&e).into())
+                }
+                Err(e) => {
+                    handler.uploads.release(upload_id);
+                    error!("upload_begin panicked: {}", e);
+                    Reply::failure_with(
+                        "upload_begin_result",
+                        request_id,
+                        ErrorBody::new(ErrorCode::Internal, "Request handler panicked"),
+                    )
+                }
+            };
+            if let Err(e) = send_reply(&reply, &out).await {
+                error!("{}", e);
+            }
+        });
+        Ok(())
+    }
 
-    Ok(())
-}
+    /// Commit or abort an upload on the blocking pool
+    fn finish_upload(
+        &self,
+        upload_id: u32,
+        commit: bool,
+        request_id: Option<String>,
+        out: &Outbound,
+    ) {
+        let kind = if commit {
+            "upload_commit_result"
+        } else {
+            "upload_abort_result"
+        };
+        let session = self.uploads.remove(upload_id);
+        let out = out.clone();
+        tokio::spawn(async move {
+            let Some(session) = session else {
+                let reply = Reply::failure_with(
+                    kind,
+                    request_id,
+                    ErrorBody::new(
+                        ErrorCode::NotFound,
+                        format!("No upload {} is in progress", upload_id),
+                    ),
+                );
+                if let Err(e) = send_reply(&reply, &out).await {
+                    error!("{}", e);
+                }
+                return;
+            };
 
-async fn handle_upload_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let filename = msg["filename"].as_str().unwrap_or("");
-    let path = msg["path"].as_str().unwrap_or("");
-    let content_base64 = msg["content_base64"].as_str().unwrap_or("");
-    let request_id = msg["request_id"].as_str();
-
-    let response = match fs_ops::handle_upload_file(path, filename, content_base64) {
-        Ok(_) => {
-            let full_path = if path.is_empty() {
-                filename.to_string()
-            } else {
-                format!("{}/{}", path.trim_end_matches('/'), filename)
+            let finished = tokio::task::spawn_blocking(move || {
+                if commit {
+                    session
+                        .commit()
+                        .map(|(path, size, sha256)| Response::UploadCommitResult {
+                            path: path.display().to_string(),
+                            size,
+                            sha256,
+                        })
+                } else {
+                    session
+                        .abort()
+                        .map(|()| Response::UploadAbortResult { upload_id })
+                }
+            })
+            .await;
+            let reply = match finished {
+                Ok(Ok(response)) => Reply::success(request_id, response),
+                Ok(Err(e)) => {
+                    error!("Upload {} failed: {}", upload_id, e);
+                    Reply::failure_with(kind, request_id, (&e).into())
+                }
+                Err(e) => {
+                    error!("Upload {} panicked: {}", upload_id, e);
+                    Reply::failure_with(
+                        kind,
+                        request_id,
+                        ErrorBody::new(ErrorCode::Internal, "Request handler panicked"),
+                    )
+                }
             };
+            if let Err(e) = send_reply(&reply, &out).await {
+                error!("{}", e);
+            }
+        });
+    }
 
-            let mut response_json = json!({
-                "type": "upload_file_result",
-                "status": "success",
-                "path": full_path
-            });
-            if let Some(req_id) = request_id {
-                response_json["request_id"] = json!(req_id);
+    /// Store one upload chunk and acknowledge it. A failed write closes the
+    /// session; beginning the upload again resumes after the last good chunk.
+    async fn write_upload_chunk(&self, frame: Frame<'_>, out: &Outbound) -> Result<()> {
+        let id = frame.transfer_id;
+        // Chunks carry no session; `upload_begin` was already checked in its own
+        if !self.negotiation.allows(None, feature::TRANSFERS) {
+            let error = ErrorBody::new(
+                ErrorCode::Unsupported,
+                format!("Feature '{}' was not negotiated", feature::TRANSFERS),
+            );
+            return out.send(transfer::error_frame(id, &error)).await;
+        }
+        let Some(session) = self.uploads.get(id) else {
+            let error = ErrorBody::new(
+                ErrorCode::NotFound,
+                format!("No upload {} is in progress", id),
+            );
+            return out.send(transfer::error_frame(id, &error)).await;
+        };
+
+        let offset = frame.offset;
+        let data = frame.payload.to_vec();
+        let written = tokio::task::spawn_blocking(move || {
+            let mut session = session.lock().unwrap();
+            session.write(offset, &data)
+        })
+        .await
+        .unwrap_or_else(|e| Err(Error::System(format!("Upload write panicked: {}", e))));
+
+        match written {
+            Ok(written) => out.send(transfer::ack_frame(id, written)).await,
+            Err(e) => {
+                warn!("Upload {} chunk at {} failed: {}", id, offset, e);
+                let io_failure = matches!(e, Error::FileIo { ref source, .. }
+                    if source.kind() != std::io::ErrorKind::InvalidInput);
+                if io_failure {
+                    self.uploads.remove(id);
+                }
+                out.send(transfer::error_frame(id, &(&e).into())).await
             }
-            response_json
         }
-        Err(e) => {
-            let mut error_json = json!({
-                "type": "error",
-                "message": format!("Upload failed: {}", e)
-            });
-            if let Some(req_id) = request_id {
-                error_json["request_id"] = json!(req_id);
+    }
+
+    /// Cancel the in-flight request with this id. Returns `false` if no
+    /// such request is queued or running.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.in_flight.lock().unwrap().get(request_id) {
+            Some(token) => {
+                token.cancel();
+                true
             }
-            error_json
+            None => false,
         }
-    };
+    }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File uploaded successfully: {}", filename);
-    Ok(())
-}
-async fn handle_paste_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
+    /// Cancel every queued and running request, e.g. when the connection
+    /// their replies would be sent on has gone away. Open uploads are closed
+    /// and the next client has to say `hello` again.
+    pub fn cancel_all(&self) {
+        let mut session = self.session.lock().unwrap();
+        session.cancel();
+        *session = CancellationToken::new();
+        self.uploads.clear();
+        self.negotiation.reset();
+    }
 
-    let response = match fs_ops::handle_paste_multiple(msg) {
-        Ok(_) => {
-            let mut response = json!({
-                "paste_file_result": "success"
-            });
+    /// Number of requests accepted but not yet replied to
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
 
-            if let Some(id) = request_id {
-                response["request_id"] = json!(id);
+    /// Token for a new request, or `None` if its id is already in use
+    fn register(&self, request_id: Option<&str>) -> Option<CancellationToken> {
+        let token = self.session.lock().unwrap().child_token();
+        if let Some(id) = request_id {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight.contains_key(id) {
+                return None;
             }
-
-            response
+            in_flight.insert(id.to_string(), token.clone());
         }
-        Err(e) => {
-            let mut response = json!({
-                "error": format!("{}", e)
-            });
+        Some(token)
+    }
 
-            if let Some(id) = request_id {
-                response["request_id"] = json!(id);
+    /// Wait for a slot and run `request` on the blocking pool as `job`,
+    /// stopping it when `token` is cancelled or the request's timeout passes.
+    /// `None` means the pool is shut down.
+    async fn run(
+        &self,
+        request: Request,
+        payload: Payload,
+        request_id: Option<String>,
+        token: CancellationToken,
+        mut job: Job,
+        mut context: Context,
+    ) -> Option<Reply> {
+        let name = request.name();
+        let kind = format!("{}_result", name);
+        let permit = tokio::select! {
+            permit = Arc::clone(&self.permits).acquire_owned() => permit.ok()?,
+            _ = token.cancelled() => {
+                debug!("{} cancelled while queued", name);
+                return Some(Reply::failure_with(&kind, request_id, ErrorBody::cancelled()));
             }
+        };
 
-            response
-        }
-    };
+        let timeout = self.limits.timeout_for(name);
+        let mut task = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let result = execute(&request, payload, &mut context, &mut job);
+            (request, result)
+        });
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File pasted successfully: {:?}", msg);
+        // Operations notice the token between files and clean up after
+        // themselves, so wait for them to return before replying
+        let mut timed_out = None;
+        let joined = tokio::select! {
+            joined = &mut task => joined,
+            _ = sleep_for(timeout) => {
+                let limit = timeout.unwrap_or_default();
+                warn!("{} exceeded its {}s timeout, cancelling", name, limit.as_secs());
+                timed_out = Some(limit);
+                token.cancel();
+                task.await
+            }
+        };
 
-    Ok(())
-}
+        let (request, result) = match joined {
+            Ok(done) => done,
+            Err(e) => {
+                error!("{} panicked: {}", name, e);
+                return Some(Reply::failure_with(
+                    &kind,
+                    request_id,
+                    ErrorBody::new(ErrorCode::Internal, "Request handler panicked"),
+                ));
+            }
+        };
 
-async fn handle_download_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let path = msg["path"].as_str().unwrap_or("");
-    let request_id = msg["request_id"].as_str();
+        Some(match result {
+            Ok((response, None)) => Reply::success(request_id, response),
+            Ok((response, Some(body))) => {
+                Reply::success(request_id, response).with_attachment(body)
+            }
+            Err(Error::Cancelled) => match timed_out {
+                Some(limit) => Reply::failure_with(
+                    &kind,
+                    request_id,
+                    ErrorBody::new(
+                        ErrorCode::TimedOut,
+                        format!("Timed out after {}s", limit.as_secs()),
+                    ),
+                ),
+                None => {
+                    debug!("{} cancelled", name);
+                    Reply::failure_with(&kind, request_id, ErrorBody::cancelled())
+                }
+            },
+            Err(e) => {
+                error!("{} failed: {}", name, e);
+                Reply::failure(&request, request_id, &e)
+            }
+        })
+    }
 
-    let mut response = match fs_ops::handle_download_file(path) {
-        Ok(response) => {
-            // Convert to object to allow mutation
-            let mut obj = response.as_object().cloned().unwrap_or_default();
+    /// Handle incoming binary messages: requests carrying file contents,
+    /// upload chunks and control commands
+    pub async fn handle_binary_message(&self, data: &[u8], out: &Outbound) -> Result<()> {
+        if let Some((header, body)) = payload::decode(data) {
+            debug!(
+                "Received payload message: {} ({} bytes)",
+                header,
+                body.len()
+            );
+            return self
+                .handle_request_message(header, Some(body.to_vec()), out)
+                .await;
+        }
 
-            if let Some(data) = obj.remove("data") {
-                obj.insert("content".to_string(), data);
+        if let Some(frame) = Frame::decode(data) {
+            if frame.kind == FrameKind::Data {
+                return self.write_upload_chunk(frame, out).await;
             }
-
-            Value::Object(obj)
+            debug!("Ignoring {:?} frame from client", frame.kind);
+            return Ok(());
         }
-        Err(err_msg) => json!({
-            "type": "error",
-            "action": "download_file",
-            "message": format!("{}", err_msg)
-        }),
-    };
 
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+        if data.len() >= 6 {
+            let msg_type = u16::from_be_bytes([data[0], data[1]]);
+            match msg_type {
+                73 => {
+                    if data.len() == 6 && data[4] == 0 && data[5] == 1 {
+                        debug!("Received unpause command");
+                    }
+                }
+                74 => {
+                    if data.len() == 6 && data[4] == 0 && data[5] == 1 {
+                        debug!("Received pause command");
+                    }
+                }
+                _ => {
+                    debug!("Unknown binary message type: {}", msg_type);
+                }
+            }
+        }
+        Ok(())
     }
+}
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File downloaded successfully: {:?}", msg);
+/// A `download_start` that has been accepted but not yet hashed
+struct PendingDownload {
+    transfer_id: u32,
+    path: String,
+    offset: u64,
+    chunk_size: u32,
+    request_id: Option<String>,
+}
 
-    Ok(())
+/// How a request's file contents arrived and how the reply should carry them
+struct Payload {
+    encoding: PayloadEncoding,
+    body: Option<Vec<u8>>,
 }
 
-async fn handle_edit_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let path = msg["path"].as_str().unwrap_or("");
-    let request_id = msg["request_id"].as_str();
-
-    let mut response = match fs_ops::handle_edit_file(path) {
-        Ok(response) => response,
-        Err(e) => json!({
-            "type": "error",
-            "message": format!("Edit failed: {}", e)
-        }),
-    };
+/// Where `search_files` sends batches of matches as it finds them
+type MatchSink = Box<dyn FnMut(&[SearchMatch]) -> Result<()> + Send>;
+
+/// What a request needs besides itself while it runs on the blocking pool
+struct Context {
+    archive: ArchiveConfig,
+    sandbox: Arc<Sandbox>,
+    /// The request's paths as the sandbox checked them
+    resolved: Resolved,
+    /// Set when a `search_files` client asked for streamed matches
+    matches: Option<MatchSink>,
+}
 
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+/// An `upload_begin` waiting for its partial file to be opened
+struct PendingUpload {
+    path: String,
+    filename: String,
+    size: u64,
+    sha256: String,
+    overwrite: bool,
+    chunk_size: u32,
+}
+
+/// Sleep for `duration`, or forever if there is none
+async fn sleep_for(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
     }
+}
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
+async fn send_reply(reply: &Reply, out: &Outbound) -> Result<()> {
+    let message = match &reply.attachment {
+        Some(body) => Message::Binary(payload::encode(&reply.to_json(), body)),
+        None => Message::Text(reply.to_json()),
+    };
+    out.send(message)
         .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File edited successfully: {:?}", msg);
-
-    Ok(())
+        .map_err(|e| Error::Network(format!("Failed to send response: {}", e)))
 }
 
-async fn handle_save_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
-    let path = msg["path"].as_str().unwrap_or("");
-    let content = msg["content"].as_str().unwrap_or("");
-
-    let response = match std::fs::write(path, content) {
-        Ok(_) => {
-            let mut response_json = json!({
-                "type": "save_file_result",
-                "status": "success",
-                "path": path
-            });
-            if let Some(req_id) = request_id {
-                response_json["request_id"] = json!(req_id);
-            }
-            response_json
-        }
-        Err(e) => {
-            let mut error_json = json!({
-                "type": "error",
-                "message": format!("Save failed: {}", e)
-            });
-            if let Some(req_id) = request_id {
-                error_json["request_id"] = json!(req_id);
-            }
-            error_json
+/// Run `request`, taking file contents from the payload body and returning
+/// them as one where the client asked for that
+fn execute(
+    request: &Request,
+    payload: Payload,
+    context: &mut Context,
+    job: &mut Job,
+) -> Result<(Response, Option<Vec<u8>>)> {
+    let archive = &context.archive;
+    let resolved = &context.resolved;
+    let binary = payload.encoding == PayloadEncoding::Binary;
+    match (request, payload.body) {
+        (Request::UploadFile { path, filename, .. }, Some(body)) => {
+            fs_ops::write_file(resolved.path(path), filename, &body)?;
+            Ok((upload_result(path, filename), None))
         }
-    };
+        (Request::SaveFile { path, .. }, Some(body)) => {
+            std::fs::write(resolved.path(path), body)
+                .map_err(|e| Error::file_io("Save failed", e))?;
+            Ok((Response::SaveFileResult { path: path.clone() }, None))
+        }
+        (Request::DownloadFile { path }, _) if binary => {
+            let (filename, content) = fs_ops::read_file(resolved.path(path))?;
+            let response = Response::DownloadFileResult {
+                filename,
+                content: None,
+            };
+            Ok((response, Some(content)))
+        }
+        (
+            Request::ExtractEntries {
+                source,
+                entries,
+                target: None,
+                format,
+            },
+            _,
+        ) if binary => {
+            let entry = single_entry(entries)?;
+            let (filename, content) = fs_ops::handle_read_archive_entry(
+                resolved.path(source),
+                entry,
+                *format,
+                archive,
+                job,
+            )?;
+            let response = Response::ExtractEntriesResult {
+                target: None,
+                paths: Vec::new(),
+                filename: Some(filename),
+                content: None,
+            };
+            Ok((response, Some(content)))
+        }
+        (Request::EditFile { path }, _) if binary => {
+            let content = fs_ops::handle_edit_file(resolved.path(path))?.into_bytes();
+            let response = Response::EditFileResult {
+                path: path.clone(),
+                content: None,
+            };
+            Ok((response, Some(content)))
+        }
+        _ => handle_request(request, context, job).map(|response| (response, None)),
+    }
+}
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File saved successfully: {:?}", msg);
+/// Check every path `request` reads or writes against the sandbox,
+/// returning the checked form of each for the operation to use
+fn authorize(sandbox: &Sandbox, request: &Request) -> Result<Resolved> {
+    let mut checked = Checker {
+        sandbox,
+        resolved: Resolved::default(),
+    };
+    let c = &mut checked;
+
+    match request {
+        Request::ListRemote { path, .. } => match path.as_deref() {
+            // The drive list itself names no path
+            None | Some("") => {}
+            Some(path) => c.check(path, Access::Read)?,
+        },
+        Request::Rename { old_path, new_name } => {
+            c.check(old_path, Access::Write)?;
+            if let Some(parent) = Path::new(old_path).parent() {
+                c.check_path(&parent.join(new_name), Access::Write)?;
+            }
+        }
+        Request::Delete { path } | Request::SaveFile { path, .. } => {
+            c.check(path, Access::Write)?
+        }
+        Request::CreateFolder { path, folder_name } => {
+            c.write_in(path, folder_name.as_deref().unwrap_or_default())?
+        }
+        Request::UploadFile { path, filename, .. }
+        | Request::UploadBegin { path, filename, .. } => c.write_in(path, filename)?,
+        Request::DownloadFile { path }
+        | Request::EditFile { path }
+        | Request::OpenFile { path }
+        | Request::DownloadStart { path, .. } => c.check(path, Access::Read)?,
+        Request::PasteFile {
+            source_paths,
+            target_path,
+            operation,
+        } => {
+            for source in source_paths {
+                match operation {
+                    PasteMode::Copy => c.check(source, Access::ReadTree)?,
+                    PasteMode::Move => c.check(source, Access::Write)?,
+                }
+                let name = Path::new(source).file_name().unwrap_or_default();
+                c.check_path(&Path::new(target_path).join(name), Access::Write)?;
+            }
+            c.check(target_path, Access::WriteInside)?;
+        }
+        Request::ZipFile {
+            target_list,
+            zip_name,
+            destination,
+            ..
+        } => {
+            for path in target_list {
+                c.check(path, Access::ReadTree)?;
+            }
+            if !target_list.is_empty() {
+                c.check_path(
+                    &fs_ops::zip_destination(
+                        target_list,
+                        destination.as_deref(),
+                        zip_name.as_deref().unwrap_or("archive.zip"),
+                    ),
+                    Access::Write,
+                )?;
+                if let Some(destination) = destination {
+                    c.check(destination, Access::WriteInside)?;
+                }
+            }
+        }
+        Request::ArchiveCreate {
+            paths, destination, ..
+        } => {
+            for path in paths {
+                c.check(path, Access::ReadTree)?;
+            }
+            c.check(destination, Access::Write)?;
+        }
+        Request::UnzipFile { source, target } | Request::ArchiveExtract { source, target, .. } => {
+            c.check(source, Access::Read)?;
+            c.check(target, Access::WriteInside)?;
+        }
+        Request::ListArchive { path, .. } => c.check(path, Access::Read)?,
+        Request::SearchFiles { root, .. } => c.check(root, Access::Read)?,
+        Request::ExtractEntries { source, target, .. } => {
+            c.check(source, Access::Read)?;
+            if let Some(target) = target {
+                c.check(target, Access::WriteInside)?;
+            }
+        }
+        Request::GetAgentDetails
+        | Request::GetInstalledSoftware
+        | Request::Hello(_)
+        | Request::Cancel { .. }
+        | Request::DownloadAck { .. }
+        | Request::UploadCommit { .. }
+        | Request::UploadAbort { .. } => {}
+    }
+    Ok(checked.resolved)
+}
 
-    Ok(())
+/// Checks a request's paths, remembering the checked form of each
+struct Checker<'a> {
+    sandbox: &'a Sandbox,
+    resolved: Resolved,
 }
 
-async fn handle_zip_file(msg: &Value, writer: &WebSocketWriter) -> std::result::Result<(), String> {
-    let paths: Vec<String> = msg["target_list"]
-        .as_array()
-        .unwrap_or(&vec![])
-        .iter()
-        .filter_map(|p| p.as_str().map(|s| s.to_string()))
-        .collect();
-
-    let zip_name = msg["zip_name"]
-        .as_str()
-        .unwrap_or("archive.zip")
-        .to_string();
-
-    let request_id = msg["request_id"].as_str();
-
-    let mut response = if paths.is_empty() {
-        json!({
-            "type": "error",
-            "message": "No files selected for zip"
-        })
-    } else {
-        match fs_ops::handle_zip_files(&paths, &zip_name) {
-            Ok(result) => result,
-            Err(e) => json!({
-                "type": "error",
-                "message": format!("Zip failed: {}", e)
-            }),
+impl Checker<'_> {
+    /// Check a path the request names. Empty paths are left for the
+    /// operations themselves to reject.
+    fn check(&mut self, path: &str, access: Access) -> Result<()> {
+        if path.is_empty() {
+            return Ok(());
         }
-    };
-
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+        let checked = self.sandbox.check(Path::new(path), access)?;
+        self.resolved
+            .0
+            .insert(path.to_string(), checked.to_string_lossy().into_owned());
+        Ok(())
     }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File zipped successfully: {:?}", msg);
+    /// Check a path the operation derives from the request's own
+    fn check_path(&self, path: &Path, access: Access) -> Result<()> {
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.sandbox.check(path, access).map(drop)
+    }
 
-    Ok(())
+    /// Check adding `name`, or if it is empty `dir` itself, to `dir`
+    fn write_in(&mut self, dir: &str, name: &str) -> Result<()> {
+        if !name.is_empty() {
+            self.check_path(&Path::new(dir).join(name), Access::Write)?;
+        }
+        self.check(dir, Access::WriteInside)
+    }
 }
 
-async fn handle_unzip_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let source = msg["source"].as_str().unwrap_or("");
-    let target = msg["target"].as_str().unwrap_or("");
-    let request_id = msg["request_id"].as_str();
-
-    let mut response = if source.is_empty() || target.is_empty() {
-        json!({
-            "type": "error",
-            "message": "Source or target path missing for unzip."
-        })
-    } else {
-        match fs_ops::handle_unzip_file(source, target) {
-            Ok(result) => result,
-            Err(e) => json!({
-                "type": "error",
-                "message": format!("Unzip failed: {}", e)
-            }),
-        }
-    };
+/// The checked form of each path a request names, keyed by how the request
+/// spelled it, so operations use what was checked and replies can still
+/// echo the request
+#[derive(Debug, Default)]
+struct Resolved(HashMap<String, String>);
 
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+impl Resolved {
+    /// The checked form of `path`, or `path` itself if it was not checked
+    fn path<'a>(&'a self, path: &'a str) -> &'a str {
+        self.0.get(path).map_or(path, String::as_str)
     }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File unzipped successfully: {:?}", msg);
-
-    Ok(())
+    fn paths(&self, paths: &[String]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| self.path(path).to_string())
+            .collect()
+    }
 }
 
-async fn handle_open_file(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    // Extract request_id from the message
-    let request_id = msg["request_id"].as_str().unwrap_or("");
+fn handle_request(request: &Request, context: &mut Context, job: &mut Job) -> Result<Response> {
+    let archive = &context.archive;
+    let resolved = &context.resolved;
+    match request {
+        Request::ListRemote { path, options } => {
+            let path = path.clone().unwrap_or_default();
+            let listing = listing::list_directory(resolved.path(&path), options, job)?;
+            Ok(Response::ListRemoteResult {
+                path,
+                total: listing.total,
+                offset: options.offset,
+                entries: listing.entries,
+            })
+        }
+        Request::Rename { old_path, new_name } => {
+            let new_path = fs_ops::handle_rename(resolved.path(old_path), new_name)?;
+            Ok(Response::RenameResult {
+                old_path: old_path.clone(),
+                new_path: new_path.display().to_string(),
+            })
+        }
+        Request::Delete { path } => {
+            fs_ops::handle_delete(resolved.path(path), job)?;
+            Ok(Response::DeleteResult { path: path.clone() })
+        }
+        Request::CreateFolder { path, folder_name } => {
+            let path = fs_ops::handle_folder_creation(resolved.path(path), folder_name.as_deref())?;
+            Ok(Response::CreateFolderResult {
+                path: path.display().to_string(),
+            })
+        }
+        Request::UploadFile {
+            path,
+            filename,
+            content_base64,
+        } => {
+            let content_base64 = content_base64.as_deref().unwrap_or_default();
+            fs_ops::handle_upload_file(resolved.path(path), filename, content_base64)?;
+            Ok(upload_result(path, filename))
+        }
+        Request::DownloadFile { path } => {
+            let (filename, content) = fs_ops::handle_download_file(resolved.path(path))?;
+            Ok(Response::DownloadFileResult {
+                filename,
+                content: Some(content),
+            })
+        }
+        Request::PasteFile {
+            source_paths,
+            target_path,
+            operation,
+        } => {
+            fs_ops::handle_paste_multiple(
+                &resolved.paths(source_paths),
+                resolved.path(target_path),
+                *operation,
+                job,
+            )?;
+            Ok(Response::PasteFileResult {
+                target_path: target_path.clone(),
+                count: source_paths.len(),
+            })
+        }
+        Request::EditFile { path } => {
+            let content = fs_ops::handle_edit_file(resolved.path(path))?;
+            Ok(Response::EditFileResult {
+                path: path.clone(),
+                content: Some(content),
+            })
+        }
+        Request::SaveFile { path, content } => {
+            let content = content
+                .as_deref()
+                .ok_or_else(|| Error::FileSystem("Missing file content".to_string()))?;
+            std::fs::write(resolved.path(path), content)
+                .map_err(|e| Error::file_io("Save failed", e))?;
+            Ok(Response::SaveFileResult { path: path.clone() })
+        }
+        Request::ZipFile {
+            target_list,
+            zip_name,
+            destination,
+            options,
+        } => {
+            if target_list.is_empty() {
+                return Err(Error::FileSystem("No files selected for zip".to_string()));
+            }
+            let zip_name = zip_name.as_deref().unwrap_or("archive.zip");
+            let target_list = resolved.paths(target_list);
+            let destination = destination.as_deref().map(|path| resolved.path(path));
+            let destination = fs_ops::zip_destination(&target_list, destination, zip_name);
+            let path = fs_ops::handle_zip_files(&target_list, &destination, options, job)?;
+            Ok(Response::ZipFileResult {
+                path: path.display().to_string(),
+            })
+        }
+        Request::UnzipFile { source, target } => {
+            if source.is_empty() || target.is_empty() {
+                return Err(Error::FileSystem(
+                    "Source or target path missing for unzip.".to_string(),
+                ));
+            }
+            let path = fs_ops::handle_unzip_file(
+                resolved.path(source),
+                resolved.path(target),
+                archive,
+                job,
+            )?;
+            Ok(Response::UnzipFileResult {
+                path: path.display().to_string(),
+            })
+        }
+        Request::ArchiveCreate {
+            paths,
+            destination,
+            format,
+            options,
+        } => {
+            if destination.is_empty() {
+                return Err(Error::FileSystem(
+                    "Destination missing for archive.".to_string(),
+                ));
+            }
+            let (path, format) = fs_ops::handle_create_archive(
+                &resolved.paths(paths),
+                Path::new(resolved.path(destination)),
+                *format,
+                options,
+                job,
+            )?;
+            Ok(Response::ArchiveCreateResult {
+                path: path.display().to_string(),
+                format,
+            })
+        }
+        Request::ArchiveExtract {
+            source,
+            target,
+            format,
+        } => {
+            let (path, format) = fs_ops::handle_extract_archive(
+                resolved.path(source),
+                resolved.path(target),
+                *format,
+                archive,
+                job,
+            )?;
+            Ok(Response::ArchiveExtractResult {
+                path: path.display().to_string(),
+                format,
+            })
+        }
+        Request::ListArchive {
+            path,
+            format,
+            offset,
+            limit,
+        } => {
+            let (listing, format) =
+                fs_ops::handle_list_archive(resolved.path(path), *format, *offset, *limit, job)?;
+            Ok(Response::ListArchiveResult {
+                path: path.clone(),
+                format,
+                total: listing.total,
+                offset: *offset,
+                entries: listing.entries,
+            })
+        }
+        Request::ExtractEntries {
+            source,
+            entries,
+            target: Some(target),
+            format,
+        } => {
+            let (paths, _) = fs_ops::handle_extract_entries(
+                resolved.path(source),
+                entries,
+                resolved.path(target),
+                *format,
+                archive,
+                job,
+            )?;
+            Ok(Response::ExtractEntriesResult {
+                target: Some(target.clone()),
+                paths: paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect(),
+                filename: None,
+                content: None,
+            })
+        }
+        Request::ExtractEntries {
+            source,
+            entries,
+            target: None,
+            format,
+        } => {
+            let entry = single_entry(entries)?;
+            let (filename, content) = fs_ops::handle_read_archive_entry(
+                resolved.path(source),
+                entry,
+                *format,
+                archive,
+                job,
+            )?;
+            Ok(Response::ExtractEntriesResult {
+                target: None,
+                paths: Vec::new(),
+                filename: Some(filename),
+                content: Some(general_purpose::STANDARD.encode(content)),
+            })
+        }
+        Request::SearchFiles { root, query, .. } => search_files(root, query, context, job),
+        Request::OpenFile { path } => {
+            let path = open_file(resolved.path(path))?;
+            Ok(Response::OpenFileResult { path })
+        }
+        Request::GetAgentDetails => Ok(Response::GetAgentDetailsResult(payload(
+            system_info::get_agent_details(),
+        ))),
+        Request::GetInstalledSoftware => Ok(Response::GetInstalledSoftwareResult(payload(
+            system_info::get_installed_software(),
+        ))),
+        Request::Hello(_)
+        | Request::Cancel { .. }
+        | Request::DownloadStart { .. }
+        | Request::DownloadAck { .. }
+        | Request::UploadBegin { .. }
+        | Request::UploadCommit { .. }
+        | Request::UploadAbort { .. } => Err(Error::System(format!(
+            "{} is handled by the dispatcher",
+            request.name()
+        ))),
+    }
+}
 
-    let mut raw_path = msg["path"].as_str().unwrap_or("").to_string();
+/// Where an upload was written, spelled the way the client named its folder
+fn upload_result(path: &str, filename: &str) -> Response {
+    let full_path = if path.is_empty() {
+        filename.to_string()
+    } else {
+        format!("{}/{}", path.trim_end_matches('/'), filename)
+    };
+    Response::UploadFileResult { path: full_path }
+}
 
-    if raw_path.is_empty() {
-        debug!("Empty path received for open_file");
+/// Search below `root`, streaming the matches if the client asked for that
+/// and collecting them for the reply otherwise
+fn search_files(
+    root: &str,
+    query: &SearchQuery,
+    context: &mut Context,
+    job: &Job,
+) -> Result<Response> {
+    if root.is_empty() {
+        return Err(Error::FileSystem("Empty search root".to_string()));
+    }
+    let sandbox = &context.sandbox;
+    let allowed = |path: &Path| sandbox.allows_read(path);
+    let start = Path::new(context.resolved.path(root));
+    let mut collected = Vec::new();
+    let summary = match context.matches.as_mut() {
+        Some(sink) => search::search_files(start, query, allowed, job, |batch| sink(&batch))?,
+        None => search::search_files(start, query, allowed, job, |batch| {
+            collected.extend(batch);
+            Ok(())
+        })?,
+    };
+    Ok(Response::SearchFilesResult {
+        root: root.to_string(),
+        summary,
+        matches: collected,
+    })
+}
 
-        let error_response = json!({
-            "request_id": request_id,
-            "success": false,
-            "error": "Empty path received"
-        });
+/// The one entry `extract_entries` returns directly when it has no target
+fn single_entry(entries: &[String]) -> Result<&str> {
+    match entries {
+        [entry] => Ok(entry),
+        _ => Err(Error::FileSystem(
+            "Exactly one entry can be extracted without a target".to_string(),
+        )),
+    }
+}
 
-        let mut writer_guard = writer.lock().await;
-        writer_guard
-            .send(Message::Text(error_response.to_string()))
-            .await
-            .map_err(|e| format!("Failed to send error response: {}", e))?;
-        return Ok(());
+/// Open `path` with the desktop's file browser or default application
+fn open_file(path: &str) -> Result<String> {
+    if path.is_empty() {
+        return Err(Error::FileSystem("Empty path received".to_string()));
     }
 
     // Clean up double slashes and convert to Windows path format
+    let mut raw_path = path.to_string();
     while raw_path.contains("//") {
         raw_path = raw_path.replace("//", "/");
     }
@@ -608,92 +1444,189 @@ async fn handle_open_file(
 
     debug!("Opening path: {}", path);
 
-    let result = {
-        #[cfg(target_os = "windows")]
-        {
-            std::process::Command::new("explorer.exe")
-                .arg(&path)
-                .spawn()
-        }
-
-        #[cfg(not(target_os = "windows"))]
-        {
-            std::process::Command::new("xdg-open").arg(&path).spawn()
-        }
-    };
+    #[cfg(target_os = "windows")]
+    let result = std::process::Command::new("explorer.exe")
+        .arg(&path)
+        .spawn();
 
-    let response = match result {
-        Ok(_) => {
-            debug!("Explorer launched for: {}", path);
+    #[cfg(not(target_os = "windows"))]
+    let result = std::process::Command::new("xdg-open").arg(&path).spawn();
 
-            json!({
-                "request_id": request_id,
-                "success": true,
-                "message": "File opened successfully"
-            })
-        }
-        Err(e) => {
-            debug!("Failed to open path: {}", e);
+    result.map_err(|e| Error::file_io("Failed to open path", e))?;
+    debug!("Explorer launched for: {}", path);
+    Ok(path)
+}
 
-            json!({
-                "request_id": request_id,
-                "success": false,
-                "error": format!("Failed to open path: {}", e)
-            })
+/// System info reports carry their own `type`, which the envelope replaces
+fn payload(value: Value) -> Map<String, Value> {
+    let mut map = match value {
+        Value::Object(map) => map,
+        other => {
+            let mut map = Map::new();
+            map.insert("data".to_string(), other);
+            map
         }
     };
-
-    let mut writer_guard = writer.lock().await;
-    writer_guard
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("File opened successfully: {:?}", msg);
-
-    Ok(())
+    map.remove("type");
+    map
 }
 
-async fn handle_get_agent_details(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+    use serde_json::json;
+    use tokio::sync::mpsc;
 
-    let mut response = system_info::get_agent_details();
+    fn handler(config: &Config) -> (MessageHandler, Outbound, mpsc::Receiver<Message>) {
+        let (out, rx) = Outbound::channel(16);
+        (MessageHandler::new(config), out, rx)
+    }
 
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+    async fn send(handler: &MessageHandler, out: &Outbound, request: Value) {
+        handler
+            .handle_text_message(&request.to_string(), out)
+            .await
+            .unwrap();
     }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("Agent details fetched successfully");
-    debug!("Agent details: {:?}", response);
+    /// The next text message sent to the client, as JSON
+    async fn next_text(rx: &mut mpsc::Receiver<Message>) -> Value {
+        match rx.recv().await.unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn upload_and_download_carry_raw_bytes_in_payload_frames() {
+        let dir = TestDir::new("payload-frames");
+        let (handler, out, mut rx) = handler(&Config::default());
+        let contents = [0u8, 1, 2, 0xff, b'\n', 0x80];
+
+        let upload = json!({
+            "type": "upload_file",
+            "request_id": "up",
+            "path": dir.path(),
+            "filename": "raw.bin",
+        });
+        let frame = payload::encode(&upload.to_string(), &contents);
+        handler.handle_binary_message(&frame, &out).await.unwrap();
+        let uploaded = next_text(&mut rx).await;
+        assert_eq!(uploaded["status"], "success", "{}", uploaded);
+        assert_eq!(std::fs::read(dir.join("raw.bin")).unwrap(), contents);
+
+        let download = json!({
+            "type": "download_file",
+            "request_id": "down",
+            "path": dir.join("raw.bin"),
+            "payload": "binary",
+        });
+        send(&handler, &out, download).await;
+        let Message::Binary(reply) = rx.recv().await.unwrap() else {
+            panic!("expected a payload frame");
+        };
+        let (header, body) = payload::decode(&reply).unwrap();
+        let header: Value = serde_json::from_str(header).unwrap();
+        assert_eq!(header["type"], "download_file_result");
+        assert_eq!(header["filename"], "raw.bin");
+        assert!(header.get("content").is_none(), "{}", header);
+        assert_eq!(body, contents);
+    }
 
-async fn handle_get_installed_software(
-    msg: &Value,
-    writer: &WebSocketWriter,
-) -> std::result::Result<(), String> {
-    let request_id = msg["request_id"].as_str();
+    #[tokio::test]
+    async fn refuses_features_the_session_did_not_agree_to() {
+        let (handler, out, mut rx) = handler(&Config::default());
+        let hello = json!({
+            "type": "hello",
+            "request_id": "h",
+            "session": "tab-1",
+            "protocol_version": 1,
+            "features": ["progress"],
+        });
+        send(&handler, &out, hello).await;
+        let agreed = next_text(&mut rx).await;
+        assert_eq!(agreed["type"], "hello_result");
+        assert_eq!(agreed["features"], json!(["progress"]));
+
+        let cancel = json!({
+            "type": "cancel",
+            "request_id": "c1",
+            "session": "tab-1",
+            "target_request_id": "r1",
+        });
+        send(&handler, &out, cancel.clone()).await;
+        let refused = next_text(&mut rx).await;
+        assert_eq!(refused["type"], "cancel_result");
+        assert_eq!(refused["error"]["code"], "unsupported");
+
+        // Another session, or none, still gets every enabled feature
+        let mut other = cancel;
+        other["session"] = json!("tab-2");
+        send(&handler, &out, other).await;
+        assert_eq!(next_text(&mut rx).await["error"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn disabled_feature_is_refused_whatever_the_client_asks() {
+        let mut config = Config::default();
+        config.features.cancel = false;
+        let (handler, out, mut rx) = handler(&config);
+        let hello = json!({"type": "hello", "request_id": "h", "protocol_version": 1});
+        send(&handler, &out, hello).await;
+        let agreed = next_text(&mut rx).await;
+        assert!(!agreed["features"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("cancel")));
+
+        let cancel = json!({"type": "cancel", "request_id": "c1", "target_request_id": "r1"});
+        send(&handler, &out, cancel).await;
+        assert_eq!(next_text(&mut rx).await["error"]["code"], "unsupported");
+    }
 
-    let mut response = system_info::get_installed_software();
+    #[tokio::test]
+    async fn queued_request_is_cancelled_by_id() {
+        let dir = TestDir::new("cancel-queued");
+        dir.write("a.txt", "a");
+        let (handler, out, mut rx) = handler(&Config::default());
+        // Every slot taken, so the paste waits in the queue
+        let slots = handler.limits.max_concurrent as u32;
+        let held = Arc::clone(&handler.permits)
+            .acquire_many_owned(slots)
+            .await
+            .unwrap();
 
-    if let Some(req_id) = request_id {
-        response["request_id"] = json!(req_id);
+        let paste = json!({
+            "type": "paste_file",
+            "request_id": "r1",
+            "source_paths": [dir.join("a.txt")],
+            "target_path": dir.join("out"),
+        });
+        send(&handler, &out, paste).await;
+        assert_eq!(handler.pending(), 1);
+        let cancel = json!({"type": "cancel", "request_id": "r2", "target_request_id": "r1"});
+        send(&handler, &out, cancel).await;
+
+        let cancelled = next_text(&mut rx).await;
+        assert_eq!(cancelled["type"], "cancel_result");
+        assert_eq!(cancelled["status"], "success");
+        let pasted = next_text(&mut rx).await;
+        assert_eq!(pasted["type"], "paste_file_result");
+        assert_eq!(pasted["request_id"], "r1");
+        assert_eq!(pasted["error"]["code"], "cancelled");
+        drop(held);
+        assert!(!dir.join("out/a.txt").exists());
     }
 
-    let mut writer = writer.lock().await;
-    writer
-        .send(Message::Text(response.to_string()))
-        .await
-        .map_err(|e| format!("Failed to send response: {}", e))?;
-    println!("Installed software fetched successfully:");
+    #[tokio::test]
+    async fn cancel_of_unknown_request_is_not_found() {
+        let (handler, out, mut rx) = handler(&Config::default());
+        let cancel = json!({"type": "cancel", "request_id": "r2", "target_request_id": "r1"});
+        send(&handler, &out, cancel).await;
 
-    Ok(())
+        let reply = next_text(&mut rx).await;
+        assert_eq!(reply["type"], "cancel_result");
+        assert_eq!(reply["error"]["code"], "not_found");
+    }
 }
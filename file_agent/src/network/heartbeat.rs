@@ -0,0 +1,185 @@
+use crate::{
+    config::HeartbeatConfig,
+    error::{Error, Result},
+};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Tracks the ping in flight on one connection and the measured round trip
+///
+/// Drive it from the connection's read loop: sleep until [`Heartbeat::deadline`],
+/// then call [`Heartbeat::on_deadline`] to get the next ping to send, and
+/// pass every received pong to [`Heartbeat::on_pong`].
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    sequence: u64,
+    in_flight: Option<(u64, Instant)>,
+    next_ping_at: Instant,
+    rtt: watch::Sender<Option<Duration>>,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        let (rtt, _) = watch::channel(None);
+        Self {
+            next_ping_at: Instant::now() + config.interval(),
+            config,
+            sequence: 0,
+            in_flight: None,
+            rtt,
+        }
+    }
+
+    /// Forget any ping in flight and schedule the first ping of a new connection
+    pub fn reset(&mut self) {
+        self.in_flight = None;
+        self.next_ping_at = Instant::now() + self.config.interval();
+    }
+
+    /// Receiver holding the most recent round-trip time
+    pub fn subscribe(&self) -> watch::Receiver<Option<Duration>> {
+        self.rtt.subscribe()
+    }
+
+    /// When the read loop has to wake up next, or `None` if disabled
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.config.enabled() {
+            return None;
+        }
+        Some(match self.in_flight {
+            Some((_, sent_at)) => sent_at + self.config.timeout(),
+            None => self.next_ping_at,
+        })
+    }
+
+    /// Called once [`Heartbeat::deadline`] has passed. Returns the ping to
+    /// send, or an error if the previous ping went unanswered.
+    pub fn on_deadline(&mut self) -> Result<Option<Message>> {
+        let now = Instant::now();
+        if let Some((_, sent_at)) = self.in_flight {
+            if now.duration_since(sent_at) >= self.config.timeout() {
+                return Err(Error::Network(format!(
+                    "No pong received within {}s, connection considered dead",
+                    self.config.timeout_secs
+                )));
+            }
+            return Ok(None);
+        }
+        if now < self.next_ping_at {
+            return Ok(None);
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.in_flight = Some((self.sequence, now));
+        self.next_ping_at = now + self.config.interval();
+        Ok(Some(Message::Ping(self.sequence.to_be_bytes().to_vec())))
+    }
+
+    /// Match a pong against the ping in flight and record the round trip.
+    /// Pongs for other payloads (unsolicited or stale) are ignored.
+    pub fn on_pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        let (sequence, sent_at) = self.in_flight?;
+        if payload != sequence.to_be_bytes() {
+            return None;
+        }
+        self.in_flight = None;
+        let rtt = sent_at.elapsed();
+        self.rtt.send_replace(Some(rtt));
+        Some(rtt)
+    }
+}
+
+/// Sleep until `deadline`, or forever when the heartbeat is disabled
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat() -> Heartbeat {
+        Heartbeat::new(HeartbeatConfig {
+            interval_secs: 15,
+            timeout_secs: 10,
+        })
+    }
+
+    /// Sleep to the deadline and send the ping that is due, returning its payload
+    async fn ping(heartbeat: &mut Heartbeat) -> Vec<u8> {
+        sleep_until(heartbeat.deadline()).await;
+        match heartbeat.on_deadline().unwrap() {
+            Some(Message::Ping(payload)) => payload,
+            other => panic!("expected a ping, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn matching_pong_records_round_trip() {
+        let mut heartbeat = heartbeat();
+        let rtt = heartbeat.subscribe();
+        let payload = ping(&mut heartbeat).await;
+        let sent_at = Instant::now();
+
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(
+            heartbeat.on_pong(&payload),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(*rtt.borrow(), Some(Duration::from_millis(250)));
+        // The next ping waits for the interval again
+        assert_eq!(
+            heartbeat.deadline(),
+            Some(sent_at + Duration::from_secs(15))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_or_unknown_pongs_are_ignored() {
+        let mut heartbeat = heartbeat();
+        let rtt = heartbeat.subscribe();
+        assert_eq!(
+            heartbeat.on_pong(&1u64.to_be_bytes()),
+            None,
+            "nothing in flight"
+        );
+
+        let first = ping(&mut heartbeat).await;
+        heartbeat.on_pong(&first).unwrap();
+        let second = ping(&mut heartbeat).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert_eq!(heartbeat.on_pong(&first), None);
+        assert_eq!(heartbeat.on_pong(b"unsolicited"), None);
+        assert_eq!(*rtt.borrow(), Some(Duration::ZERO));
+        assert!(heartbeat.on_pong(&second).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_pong_reports_the_connection_dead() {
+        let mut heartbeat = heartbeat();
+        ping(&mut heartbeat).await;
+
+        // Woken early, e.g. by another message: still waiting
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert!(heartbeat.on_deadline().unwrap().is_none());
+
+        sleep_until(heartbeat.deadline()).await;
+        assert!(matches!(heartbeat.on_deadline(), Err(Error::Network(_))));
+    }
+
+    #[test]
+    fn zero_interval_disables_the_heartbeat() {
+        let heartbeat = Heartbeat::new(HeartbeatConfig {
+            interval_secs: 0,
+            timeout_secs: 10,
+        });
+        assert_eq!(heartbeat.deadline(), None);
+    }
+}
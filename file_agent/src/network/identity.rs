@@ -0,0 +1,129 @@
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    network::tls::{certificate_fingerprint, format_fingerprint},
+};
+use chrono::{Datelike, Utc};
+use log::info;
+use rcgen::{CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyPair};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Certificate written by `--generate-identity` when `tls.client_cert` is unset
+pub const DEFAULT_CLIENT_CERT_PATH: &str = "agent-cert.pem";
+/// Private key written by `--generate-identity` when `tls.client_key` is unset
+pub const DEFAULT_CLIENT_KEY_PATH: &str = "agent-key.pem";
+
+/// How long a generated client certificate stays valid
+const VALIDITY_YEARS: i32 = 2;
+
+/// Result of generating an agent identity
+#[derive(Debug, Clone)]
+pub struct GeneratedIdentity {
+    pub cert_path: String,
+    pub key_path: String,
+    /// SHA-256 of the certificate, to enrol the agent on the relay
+    pub fingerprint: String,
+    /// Where the previous certificate and key were moved, when rotating
+    pub backups: Vec<String>,
+}
+
+/// Create a new ECDSA P-256 keypair and self-signed client certificate for
+/// the agent. The subject carries the tenant as O and the agent uuid as CN.
+///
+/// Existing files are left alone unless `rotate` is set, in which case they
+/// are renamed to `<path>.<timestamp>.bak` before the new pair is written.
+pub fn generate_identity(config: &Config, rotate: bool) -> Result<GeneratedIdentity> {
+    let cert_path = config
+        .tls
+        .client_cert
+        .clone()
+        .unwrap_or_else(|| DEFAULT_CLIENT_CERT_PATH.to_string());
+    let key_path = config
+        .tls
+        .client_key
+        .clone()
+        .unwrap_or_else(|| DEFAULT_CLIENT_KEY_PATH.to_string());
+
+    let existing: Vec<&String> = [&cert_path, &key_path]
+        .into_iter()
+        .filter(|path| Path::new(path).exists())
+        .collect();
+    if !existing.is_empty() && !rotate {
+        return Err(Error::Config(format!(
+            "Identity already exists at {}; use --rotate-identity to replace it",
+            existing
+                .iter()
+                .map(|p| p.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+
+    let key_pair =
+        KeyPair::generate().map_err(|e| Error::Tls(format!("Failed to generate key: {}", e)))?;
+
+    let mut params = CertificateParams::new(Vec::<String>::new())
+        .map_err(|e| Error::Tls(format!("Invalid certificate parameters: {}", e)))?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, config.uuid.as_str());
+    params
+        .distinguished_name
+        .push(DnType::OrganizationName, config.tenant_id.as_str());
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let today = Utc::now().date_naive();
+    params.not_before = rcgen::date_time_ymd(today.year(), today.month() as u8, today.day() as u8);
+    params.not_after = rcgen::date_time_ymd(
+        today.year() + VALIDITY_YEARS,
+        today.month() as u8,
+        today.day().min(28) as u8,
+    );
+
+    let cert = params
+        .self_signed(&key_pair)
+        .map_err(|e| Error::Tls(format!("Failed to sign certificate: {}", e)))?;
+
+    let stamp = Utc::now().format("%Y%m%d%H%M%S");
+    let mut backups = Vec::new();
+    for path in existing {
+        let backup = format!("{}.{}.bak", path, stamp);
+        fs::rename(path, &backup)?;
+        backups.push(backup);
+    }
+
+    write_file(&key_path, key_pair.serialize_pem().as_bytes(), true)?;
+    write_file(&cert_path, cert.pem().as_bytes(), false)?;
+
+    let fingerprint = format_fingerprint(&certificate_fingerprint(cert.der()));
+    info!("Generated agent identity {} ({})", cert_path, fingerprint);
+
+    Ok(GeneratedIdentity {
+        cert_path,
+        key_path,
+        fingerprint,
+        backups,
+    })
+}
+
+/// Write through a temporary sibling so a crash never leaves half a key behind
+fn write_file(path: &str, contents: &[u8], private: bool) -> Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -1,6 +1,21 @@
+pub mod capabilities;
 pub mod client;
 pub mod handlers;
+pub mod heartbeat;
+pub mod identity;
+pub mod outbound;
+pub mod payload;
+pub mod protocol;
 pub mod proxy;
+pub mod reconnect;
+pub mod socks;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
+pub mod upload;
 
 pub use client::WebSocketClient;
 pub use handlers::MessageHandler;
+pub use heartbeat::Heartbeat;
+pub use outbound::Outbound;
+pub use reconnect::{Backoff, ConnectionState};
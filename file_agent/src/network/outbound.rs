@@ -0,0 +1,69 @@
+use crate::error::{Error, Result};
+use futures_util::{Sink, SinkExt};
+use log::error;
+use std::fmt::Display;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Frames queued for a connection before senders have to wait
+pub const OUTBOUND_CAPACITY: usize = 64;
+
+/// Handle for queueing frames to a connection's writer task
+///
+/// A single task owns the sink, so replies from concurrent requests never
+/// contend on a lock or interleave partial writes.
+#[derive(Clone)]
+pub struct Outbound {
+    tx: mpsc::Sender<Message>,
+}
+
+impl Outbound {
+    /// Spawn the writer task for `sink`. It ends when every [`Outbound`] is
+    /// dropped or the sink fails.
+    pub fn spawn<S>(mut sink: S, capacity: usize) -> (Self, JoinHandle<()>)
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: Display,
+    {
+        let (outbound, mut rx) = Self::channel(capacity);
+        let task = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Err(e) = sink.send(message).await {
+                    error!("Failed to write to WebSocket: {}", e);
+                    return;
+                }
+            }
+            let _ = sink.close().await;
+        });
+        (outbound, task)
+    }
+
+    /// A handle and the receiving end, for callers that drain the queue
+    /// into a sink shared with other writers
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self { tx }, rx)
+    }
+
+    /// Queue `message` without waiting, dropping it if the queue is full.
+    /// For updates that are superseded by the next one anyway.
+    pub fn try_send(&self, message: Message) -> bool {
+        self.tx.try_send(message).is_ok()
+    }
+
+    /// Queue `message` from a blocking thread, waiting for room. Must not
+    /// be called from async code.
+    pub fn blocking_send(&self, message: Message) -> Result<()> {
+        self.tx
+            .blocking_send(message)
+            .map_err(|_| Error::Network("Connection writer has stopped".to_string()))
+    }
+
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.tx
+            .send(message)
+            .await
+            .map_err(|_| Error::Network("Connection writer has stopped".to_string()))
+    }
+}
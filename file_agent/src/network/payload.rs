@@ -0,0 +1,85 @@
+//! File contents carried as raw bytes instead of base64 inside JSON
+//!
+//! A payload frame is a binary message laid out as:
+//!
+//! | bytes      | field                                           |
+//! |------------|-------------------------------------------------|
+//! | 0..2       | message type, always [`FILE_PAYLOAD`]           |
+//! | 2..6       | length `n` of the header                        |
+//! | 6..6+n     | JSON header, the usual envelope without content |
+//! | 6+n..      | body, the file contents                         |
+//!
+//! Integers are big-endian, like the screen agent's `[u16 type][u16 len]`
+//! packets. The body runs to the end of the message. Clients send
+//! `upload_file` and `save_file` this way instead of inlining the content,
+//! and ask for `download_file` and `edit_file` replies in this form with
+//! `"payload": "binary"`. Without it the reply stays base64 in a text
+//! frame, which is what HTTP callers of the relay receive.
+
+/// Binary message type of payload frames
+pub const FILE_PAYLOAD: u16 = 201;
+const PREFIX_LEN: usize = 6;
+
+pub fn encode(header: &str, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(PREFIX_LEN + header.len() + body.len());
+    frame.extend_from_slice(&FILE_PAYLOAD.to_be_bytes());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(header.as_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// The header and body of `data`, or `None` unless it is a well-formed
+/// payload frame
+pub fn decode(data: &[u8]) -> Option<(&str, &[u8])> {
+    if data.len() < PREFIX_LEN || data[..2] != FILE_PAYLOAD.to_be_bytes() {
+        return None;
+    }
+    let header_len = u32::from_be_bytes(data[2..6].try_into().ok()?) as usize;
+    let rest = &data[PREFIX_LEN..];
+    if header_len > rest.len() {
+        return None;
+    }
+    let (header, body) = rest.split_at(header_len);
+    Some((std::str::from_utf8(header).ok()?, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_header_and_body() {
+        let header = r#"{"type":"upload_file","path":"/tmp","filename":"a.bin"}"#;
+        let body = [0u8, 159, 146, 150, 255];
+        let frame = encode(header, &body);
+        assert_eq!(frame[..2], FILE_PAYLOAD.to_be_bytes());
+        assert_eq!(frame[2..6], (header.len() as u32).to_be_bytes());
+        assert_eq!(decode(&frame), Some((header, &body[..])));
+
+        // An empty body is still a payload
+        assert_eq!(decode(&encode("{}", &[])), Some(("{}", &[][..])));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let frame = encode("{}", b"body");
+        assert_eq!(decode(&frame[..PREFIX_LEN - 1]), None);
+
+        let mut other_type = frame.clone();
+        other_type[..2].copy_from_slice(&200u16.to_be_bytes());
+        assert_eq!(decode(&other_type), None);
+
+        let mut overlong = frame.clone();
+        overlong[2..6].copy_from_slice(&1_000u32.to_be_bytes());
+        assert_eq!(decode(&overlong), None);
+
+        let invalid_utf8 = [
+            &FILE_PAYLOAD.to_be_bytes()[..],
+            &2u32.to_be_bytes(),
+            &[0xff, 0xfe],
+        ]
+        .concat();
+        assert_eq!(decode(&invalid_utf8), None);
+    }
+}
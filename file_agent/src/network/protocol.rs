@@ -0,0 +1,741 @@
+use crate::{
+    error::Error,
+    filesystem::{
+        ArchiveEntry, ArchiveFormat, ArchiveOptions, DirEntry, JobProgress, ListOptions, PasteMode,
+        SearchMatch, SearchQuery, SearchSummary,
+    },
+    network::capabilities::{Capabilities, Hello},
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::io;
+
+/// A request from the relay, tagged by its `type` field
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    ListRemote {
+        /// Empty or missing lists the drives
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(flatten)]
+        options: ListOptions,
+    },
+    Rename {
+        old_path: String,
+        new_name: String,
+    },
+    Delete {
+        path: String,
+    },
+    CreateFolder {
+        path: String,
+        #[serde(default)]
+        folder_name: Option<String>,
+    },
+    UploadFile {
+        path: String,
+        filename: String,
+        /// Absent when the content is the body of a payload frame
+        #[serde(default)]
+        content_base64: Option<String>,
+    },
+    DownloadFile {
+        path: String,
+    },
+    PasteFile {
+        #[serde(alias = "from_list")]
+        source_paths: Vec<String>,
+        #[serde(alias = "to")]
+        target_path: String,
+        #[serde(alias = "mode", default, deserialize_with = "null_as_default")]
+        operation: PasteMode,
+    },
+    EditFile {
+        path: String,
+    },
+    SaveFile {
+        path: String,
+        /// Absent when the content is the body of a payload frame
+        #[serde(default)]
+        content: Option<String>,
+    },
+    ZipFile {
+        target_list: Vec<String>,
+        #[serde(default)]
+        zip_name: Option<String>,
+        /// Path of the archive, or a folder to put `zip_name` in; next to
+        /// the first entry if absent
+        #[serde(default)]
+        destination: Option<String>,
+        #[serde(flatten)]
+        options: ArchiveOptions,
+    },
+    UnzipFile {
+        source: String,
+        target: String,
+    },
+    /// Archive `paths` as `destination`, in the format its extension names
+    /// unless `format` is given
+    ArchiveCreate {
+        paths: Vec<String>,
+        destination: String,
+        #[serde(default)]
+        format: Option<ArchiveFormat>,
+        #[serde(flatten)]
+        options: ArchiveOptions,
+    },
+    /// Extract `source` into a new folder in `target`, detecting its format
+    /// unless `format` is given
+    ArchiveExtract {
+        source: String,
+        target: String,
+        #[serde(default)]
+        format: Option<ArchiveFormat>,
+    },
+    /// A page of the entries in the archive at `path`, detecting its format
+    /// unless `format` is given
+    ListArchive {
+        path: String,
+        #[serde(default)]
+        format: Option<ArchiveFormat>,
+        #[serde(default)]
+        offset: usize,
+        /// Entries per page; 1000 if absent, at most 10000
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Extract the named `entries` of `source` into `target`, each under its
+    /// own name. Without a target the one entry named, if at most 32 MiB,
+    /// is returned the way `download_file` returns a file.
+    ExtractEntries {
+        source: String,
+        entries: Vec<String>,
+        #[serde(default)]
+        target: Option<String>,
+        #[serde(default)]
+        format: Option<ArchiveFormat>,
+    },
+    /// Search below `root`. With `stream` set, matches are sent in
+    /// `search_results` messages as they are found and the reply only sums
+    /// up; like `job_progress`, only peers that expect them should opt in.
+    SearchFiles {
+        root: String,
+        #[serde(default)]
+        stream: bool,
+        #[serde(flatten)]
+        query: SearchQuery,
+    },
+    OpenFile {
+        path: String,
+    },
+    GetAgentDetails,
+    GetInstalledSoftware,
+    /// Agree on the protocol version and features for this connection
+    Hello(Hello),
+    /// Stream a file as binary chunks, starting at `offset` to resume an
+    /// interrupted download
+    DownloadStart {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        chunk_size: Option<u32>,
+    },
+    /// The client has received everything before `offset`
+    DownloadAck {
+        transfer_id: u32,
+        offset: u64,
+    },
+    /// Open or resume an upload of `size` bytes into `path/filename`
+    UploadBegin {
+        path: String,
+        filename: String,
+        size: u64,
+        sha256: String,
+        #[serde(default)]
+        overwrite: bool,
+        #[serde(default)]
+        chunk_size: Option<u32>,
+    },
+    /// Verify a fully received upload and move it into place
+    UploadCommit {
+        upload_id: u32,
+    },
+    UploadAbort {
+        upload_id: u32,
+    },
+    /// Stop the in-flight request with this id. The relay assigns every
+    /// message its own `request_id`, so the target travels separately.
+    Cancel {
+        #[serde(deserialize_with = "request_id")]
+        target_request_id: String,
+    },
+}
+
+/// `Request::name` and `Request::NAMES` from one list, so the match being
+/// exhaustive keeps every variant's name in `NAMES`
+macro_rules! request_names {
+    ($($variant:ident => $name:literal,)*) => {
+        impl Request {
+            /// Every request `type` the agent understands
+            pub const NAMES: &'static [&'static str] = &[$($name),*];
+
+            /// The request's `type` value
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Request::$variant { .. } => $name,)*
+                }
+            }
+        }
+    };
+}
+
+request_names! {
+    ListRemote => "list_remote",
+    Rename => "rename",
+    Delete => "delete",
+    CreateFolder => "create_folder",
+    UploadFile => "upload_file",
+    DownloadFile => "download_file",
+    PasteFile => "paste_file",
+    EditFile => "edit_file",
+    SaveFile => "save_file",
+    ZipFile => "zip_file",
+    UnzipFile => "unzip_file",
+    ArchiveCreate => "archive_create",
+    ArchiveExtract => "archive_extract",
+    ListArchive => "list_archive",
+    ExtractEntries => "extract_entries",
+    SearchFiles => "search_files",
+    OpenFile => "open_file",
+    GetAgentDetails => "get_agent_details",
+    GetInstalledSoftware => "get_installed_software",
+    Hello => "hello",
+    DownloadStart => "download_start",
+    DownloadAck => "download_ack",
+    UploadBegin => "upload_begin",
+    UploadCommit => "upload_commit",
+    UploadAbort => "upload_abort",
+    Cancel => "cancel",
+}
+
+/// A request together with the id the relay uses to route the reply
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestEnvelope {
+    #[serde(default, deserialize_with = "optional_request_id")]
+    pub request_id: Option<String>,
+    /// Chosen by the client to tie its requests to its `hello`; the relay
+    /// forwards every client's requests over the same connection
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Ask for `job_progress` updates while the request runs. Off by
+    /// default: the relay treats the first message carrying a request's id
+    /// as its reply, so only peers that expect updates should opt in.
+    #[serde(default)]
+    pub progress: bool,
+    /// How file contents in the reply are sent
+    #[serde(default)]
+    pub payload: PayloadEncoding,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// How file contents travel between the agent and a client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadEncoding {
+    /// Inline in the JSON reply, as base64 or text
+    #[default]
+    Base64,
+    /// As the body of a payload frame. The relay broadcasts binary messages
+    /// to browser clients only, so HTTP callers must not ask for this.
+    Binary,
+}
+
+impl RequestEnvelope {
+    /// Parse a text frame. On failure the error reply is returned instead,
+    /// addressed to the frame's `request_id` if one could be read.
+    pub fn parse(text: &str) -> Result<Self, Box<Reply>> {
+        let value: Value = serde_json::from_str(text).map_err(|e| {
+            Box::new(Reply::failure_with(
+                "error",
+                None,
+                ErrorBody::new(ErrorCode::InvalidRequest, format!("Invalid JSON: {}", e)),
+            ))
+        })?;
+
+        let request_id = match value.get("request_id") {
+            Some(Value::String(id)) => Some(id.clone()),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => None,
+        };
+        let kind = value
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+
+        serde_json::from_value(value).map_err(|e| {
+            Box::new(if Request::NAMES.contains(&kind.as_str()) {
+                Reply::failure_with(
+                    &format!("{}_result", kind),
+                    request_id,
+                    ErrorBody::new(
+                        ErrorCode::InvalidRequest,
+                        format!("Invalid '{}' request: {}", kind, e),
+                    ),
+                )
+            } else {
+                Reply::failure_with(
+                    "error",
+                    request_id,
+                    ErrorBody::new(
+                        ErrorCode::UnknownType,
+                        format!("Unknown request type '{}'", kind),
+                    ),
+                )
+            })
+        })
+    }
+}
+
+/// Successful result payloads, tagged `<request type>_result`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    ListRemoteResult {
+        path: String,
+        /// Entries in the whole directory, after filtering
+        total: usize,
+        offset: usize,
+        entries: Vec<DirEntry>,
+    },
+    RenameResult {
+        old_path: String,
+        new_path: String,
+    },
+    DeleteResult {
+        path: String,
+    },
+    CreateFolderResult {
+        path: String,
+    },
+    UploadFileResult {
+        path: String,
+    },
+    DownloadFileResult {
+        filename: String,
+        /// Base64; absent when the content is the body of a payload frame
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+    PasteFileResult {
+        target_path: String,
+        count: usize,
+    },
+    EditFileResult {
+        path: String,
+        /// Absent when the content is the body of a payload frame
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+    SaveFileResult {
+        path: String,
+    },
+    ZipFileResult {
+        path: String,
+    },
+    UnzipFileResult {
+        path: String,
+    },
+    ArchiveCreateResult {
+        path: String,
+        format: ArchiveFormat,
+    },
+    ArchiveExtractResult {
+        path: String,
+        format: ArchiveFormat,
+    },
+    ListArchiveResult {
+        path: String,
+        format: ArchiveFormat,
+        /// Entries in the whole archive
+        total: usize,
+        offset: usize,
+        entries: Vec<ArchiveEntry>,
+    },
+    /// Either the `paths` written in `target`, or the `filename` and
+    /// `content` of the one entry asked for without a target
+    ExtractEntriesResult {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        paths: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        filename: Option<String>,
+        /// Base64; absent when the content is the body of a payload frame
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+    SearchFilesResult {
+        root: String,
+        #[serde(flatten)]
+        summary: SearchSummary,
+        /// Empty when the matches were streamed
+        matches: Vec<SearchMatch>,
+    },
+    OpenFileResult {
+        path: String,
+    },
+    GetAgentDetailsResult(Map<String, Value>),
+    GetInstalledSoftwareResult(Map<String, Value>),
+    /// The capabilities both sides support
+    HelloResult(Capabilities),
+    /// The offer for a download; chunks follow as binary frames
+    DownloadStartResult {
+        transfer_id: u32,
+        filename: String,
+        size: u64,
+        /// Hex SHA-256 of the whole file, for checking a resumed download
+        sha256: String,
+        offset: u64,
+        chunk_size: u32,
+        /// Unacknowledged chunks the agent sends before waiting
+        window: u32,
+    },
+    DownloadAckResult {
+        transfer_id: u32,
+        offset: u64,
+    },
+    /// Chunks for the upload follow as binary frames, starting at `offset`
+    UploadBeginResult {
+        upload_id: u32,
+        path: String,
+        offset: u64,
+        chunk_size: u32,
+    },
+    UploadCommitResult {
+        path: String,
+        size: u64,
+        sha256: String,
+    },
+    UploadAbortResult {
+        upload_id: u32,
+    },
+    CancelResult {
+        target_request_id: String,
+    },
+}
+
+/// Periodic update for a running request that asked for progress
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "job_progress")]
+pub struct ProgressReport<'a> {
+    pub request_id: &'a str,
+    /// The request's `type`
+    pub job: &'static str,
+    #[serde(flatten)]
+    pub progress: &'a JobProgress,
+}
+
+impl ProgressReport<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// A batch of matches from a `search_files` request that streams them
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename = "search_results")]
+pub struct SearchResults<'a> {
+    pub request_id: &'a str,
+    pub matches: &'a [SearchMatch],
+}
+
+impl SearchResults<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success,
+    Error,
+}
+
+/// Machine readable failure reason
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    UnknownType,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    IoError,
+    ArchiveError,
+    OperationFailed,
+    /// Too many requests are already running or queued
+    Busy,
+    /// Stopped by a `cancel` request
+    Cancelled,
+    /// Stopped after exceeding the configured operation timeout
+    TimedOut,
+    /// Received data does not match the expected SHA-256
+    ChecksumMismatch,
+    /// The feature or protocol version was not negotiated or is disabled
+    Unsupported,
+    DiskFull,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn of(error: &Error) -> Self {
+        match error {
+            Error::Io(e) | Error::FileIo { source: e, .. } => Self::from_io(e.kind()),
+            Error::FileSystem(_) => ErrorCode::OperationFailed,
+            Error::Json(_) | Error::Base64(_) => ErrorCode::InvalidRequest,
+            Error::Zip(_) | Error::Archive(_) => ErrorCode::ArchiveError,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            Error::ChecksumMismatch { .. } => ErrorCode::ChecksumMismatch,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    fn from_io(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            io::ErrorKind::InvalidInput => ErrorCode::InvalidRequest,
+            io::ErrorKind::StorageFull => ErrorCode::DiskFull,
+            _ => ErrorCode::IoError,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn cancelled() -> Self {
+        Self::new(ErrorCode::Cancelled, "Cancelled")
+    }
+}
+
+impl From<&Error> for ErrorBody {
+    fn from(error: &Error) -> Self {
+        let message = match error {
+            Error::FileSystem(message) => message.clone(),
+            other => other.to_string(),
+        };
+        Self::new(ErrorCode::of(error), message)
+    }
+}
+
+/// The envelope every reply is sent in:
+/// `{type, request_id, status, error: {code, message}, ...payload}`
+#[derive(Debug, Clone, Serialize)]
+pub struct Reply {
+    #[serde(flatten)]
+    body: ReplyBody,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+    /// File contents sent after the JSON in a payload frame
+    #[serde(skip)]
+    pub attachment: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum ReplyBody {
+    Success(Response),
+    Failure {
+        #[serde(rename = "type")]
+        kind: String,
+    },
+}
+
+impl Reply {
+    pub fn success(request_id: Option<String>, response: Response) -> Self {
+        Self {
+            body: ReplyBody::Success(response),
+            request_id,
+            status: Status::Success,
+            error: None,
+            attachment: None,
+        }
+    }
+
+    /// Send `body` after the reply in a payload frame
+    pub fn with_attachment(mut self, body: Vec<u8>) -> Self {
+        self.attachment = Some(body);
+        self
+    }
+
+    /// Error reply for `request`, typed as its `<type>_result`
+    pub fn failure(request: &Request, request_id: Option<String>, error: &Error) -> Self {
+        Self::failure_with(
+            &format!("{}_result", request.name()),
+            request_id,
+            error.into(),
+        )
+    }
+
+    pub fn failure_with(kind: &str, request_id: Option<String>, error: ErrorBody) -> Self {
+        Self {
+            body: ReplyBody::Failure {
+                kind: kind.to_string(),
+            },
+            request_id,
+            status: Status::Error,
+            error: Some(error),
+            attachment: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| {
+            format!(
+                r#"{{"type":"error","status":"error","error":{{"code":"internal","message":"Failed to encode reply: {}"}}}}"#,
+                e
+            )
+        })
+    }
+}
+
+/// The relay sends string ids; accept numbers as well
+fn optional_request_id<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(id)) => Some(id),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        _ => None,
+    })
+}
+
+fn request_id<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    optional_request_id(deserializer)?
+        .ok_or_else(|| serde::de::Error::custom("expected a string or number request id"))
+}
+
+/// The relay forwards absent form fields as `null`
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{SortKey, SortOrder};
+
+    #[test]
+    fn parses_list_remote_as_the_relay_sends_it() {
+        // httpFileHandlers.go forwards the fields the caller left out as null
+        let text = r#"{"type":"list_remote","path":null,"show_hidden":null,"request_id":"7f3a"}"#;
+        let envelope = RequestEnvelope::parse(text).unwrap();
+        assert_eq!(envelope.request_id.as_deref(), Some("7f3a"));
+        let Request::ListRemote { path, options } = envelope.request else {
+            panic!("expected list_remote, got {:?}", envelope.request);
+        };
+        assert_eq!(path, None);
+        assert!(options.show_hidden);
+        assert_eq!(options.offset, 0);
+        assert_eq!(options.limit, None);
+        assert_eq!(options.sort, SortKey::Name);
+        assert_eq!(options.order, SortOrder::Asc);
+        assert!(!options.dirs_first);
+    }
+
+    #[test]
+    fn parses_list_remote_options() {
+        let text = r#"{"type":"list_remote","path":"C:/Users","show_hidden":false,
+            "offset":100,"limit":50,"sort":"size","order":"desc","dirs_first":null,
+            "request_id":"7f3b"}"#;
+        let envelope = RequestEnvelope::parse(text).unwrap();
+        let Request::ListRemote { path, options } = envelope.request else {
+            panic!("expected list_remote, got {:?}", envelope.request);
+        };
+        assert_eq!(path.as_deref(), Some("C:/Users"));
+        assert!(!options.show_hidden);
+        assert_eq!(options.offset, 100);
+        assert_eq!(options.limit, Some(50));
+        assert_eq!(options.sort, SortKey::Size);
+        assert_eq!(options.order, SortOrder::Desc);
+        assert!(!options.dirs_first);
+    }
+
+    /// The reply sent back for a frame that does not parse, as JSON
+    fn rejection(text: &str) -> Value {
+        let reply = RequestEnvelope::parse(text).unwrap_err();
+        serde_json::from_str(&reply.to_json()).unwrap()
+    }
+
+    #[test]
+    fn malformed_archive_requests_are_answered_as_their_own_type() {
+        let reply = rejection(r#"{"type":"archive_create","paths":"a.txt","request_id":"1"}"#);
+        assert_eq!(reply["type"], "archive_create_result");
+        assert_eq!(reply["request_id"], "1");
+        assert_eq!(reply["error"]["code"], "invalid_request");
+
+        let reply = rejection(r#"{"type":"archive_extract","source":"a.zip","request_id":"2"}"#);
+        assert_eq!(reply["type"], "archive_extract_result");
+        assert_eq!(reply["error"]["code"], "invalid_request");
+    }
+
+    #[test]
+    fn unknown_request_type_is_answered_as_error() {
+        let reply = rejection(r#"{"type":"format_disk","request_id":"3"}"#);
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["error"]["code"], "unknown_type");
+    }
+
+    #[test]
+    fn malformed_extract_entries_is_answered_as_extract_entries_result() {
+        let text = r#"{"type":"extract_entries","source":"logs.zip","entries":"app.log","request_id":"4"}"#;
+        let reply = rejection(text);
+        assert_eq!(reply["type"], "extract_entries_result");
+        assert_eq!(reply["request_id"], "4");
+        assert_eq!(reply["error"]["code"], "invalid_request");
+
+        let reply = rejection(r#"{"type":"list_archive","path":"logs.zip","offset":-1}"#);
+        assert_eq!(reply["type"], "list_archive_result");
+        assert_eq!(reply["error"]["code"], "invalid_request");
+    }
+
+    #[test]
+    fn malformed_search_is_answered_as_search_files_result() {
+        let text =
+            r#"{"type":"search_files","root":"/var/log","max_results":"all","request_id":"5"}"#;
+        let reply = rejection(text);
+        assert_eq!(reply["type"], "search_files_result");
+        assert_eq!(reply["request_id"], "5");
+        assert_eq!(reply["error"]["code"], "invalid_request");
+    }
+}
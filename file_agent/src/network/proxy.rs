@@ -1,9 +1,74 @@
-use crate::error::{Error, Result};
-use log::info;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use log::{info, warn};
+use std::io;
 use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
 
+/// Upper bound for connecting to the proxy and completing the CONNECT exchange
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest CONNECT response head we are willing to buffer
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Failure while opening a tunnel through an HTTP or SOCKS5 proxy
+#[derive(Error, Debug)]
+pub enum ProxyError {
+    #[error("failed to connect to proxy {address}: {source}")]
+    Connect { address: String, source: io::Error },
+
+    #[error("proxy I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("proxy did not answer within {}s", .0.as_secs())]
+    Timeout(Duration),
+
+    #[error("proxy closed the connection before completing the CONNECT response")]
+    ConnectionClosed,
+
+    #[error("malformed proxy response: {0}")]
+    MalformedResponse(String),
+
+    #[error("proxy response head exceeds {MAX_RESPONSE_HEAD} bytes")]
+    ResponseTooLarge,
+
+    #[error("proxy requires authentication ({challenge}) but no credentials are configured")]
+    AuthenticationRequired { challenge: String },
+
+    #[error("proxy rejected the configured credentials")]
+    AuthenticationFailed,
+
+    #[error("proxy only offers unsupported authentication schemes: {0}")]
+    UnsupportedAuthScheme(String),
+
+    #[error("proxy accepts none of the offered authentication methods")]
+    NoAcceptableAuthMethod,
+
+    #[error("invalid proxy credentials: {0}")]
+    InvalidCredentials(String),
+
+    #[error("invalid proxy target: {0}")]
+    InvalidTarget(String),
+
+    #[error("proxy refused CONNECT to {target}: {status} {reason}")]
+    Rejected {
+        target: String,
+        status: u16,
+        reason: String,
+    },
+}
+
+/// Parsed status line and the headers we act on
+#[derive(Debug)]
+struct ConnectResponse {
+    status: u16,
+    reason: String,
+    proxy_authenticate: Vec<String>,
+}
+
+/// Opens a TCP tunnel to `target_host:target_port` with an HTTP CONNECT
+/// request, answering a `407` Basic challenge when credentials are available
 pub struct ProxyConnector {
     proxy_host: String,
     proxy_port: u16,
@@ -14,6 +79,8 @@ pub struct ProxyConnector {
 }
 
 impl ProxyConnector {
+    /// `auth` is the base64 `user:password` pair. Unless `no_auth` is set it
+    /// is sent with the first request; otherwise only in reply to a `407`.
     pub fn new(
         proxy_host: String,
         proxy_port: u16,
@@ -32,64 +99,260 @@ impl ProxyConnector {
         }
     }
 
-    pub fn connect(&self) -> Result<TcpStream> {
-        info!(
-            "Connecting to proxy {}:{}",
-            self.proxy_host, self.proxy_port
-        );
-
-        let mut stream = TcpStream::connect(format!("{}:{}", self.proxy_host, self.proxy_port))
-            .map_err(|e| Error::Network(format!("Failed to connect to proxy: {}", e)))?;
-
-        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-        stream.set_write_timeout(Some(Duration::from_secs(30)))?;
-
-        // Send CONNECT request
-        let connect_request = if self.no_auth {
-            format!(
-                "CONNECT {}:{} HTTP/1.1\r\n\
-                 Host: {}:{}\r\n\
-                 Connection: keep-alive\r\n\r\n",
-                self.target_host, self.target_port, self.target_host, self.target_port
-            )
-        } else if let Some(ref auth) = self.auth {
-            format!(
-                "CONNECT {}:{} HTTP/1.1\r\n\
-                 Host: {}:{}\r\n\
-                 Proxy-Authorization: Basic {}\r\n\
-                 Connection: keep-alive\r\n\r\n",
-                self.target_host, self.target_port, self.target_host, self.target_port, auth
-            )
+    pub async fn connect(&self) -> Result<TcpStream, ProxyError> {
+        let preemptive = if self.no_auth {
+            None
         } else {
-            return Err(Error::Config(
-                "Proxy authentication required but not provided".to_string(),
-            ));
+            self.auth.as_deref()
         };
 
-        stream
-            .write_all(connect_request.as_bytes())
-            .map_err(|e| Error::Network(format!("Failed to send CONNECT request: {}", e)))?;
-
-        // Read response
-        let mut response = [0u8; 4096];
-        let bytes_read = stream
-            .read(&mut response)
-            .map_err(|e| Error::Network(format!("Failed to read proxy response: {}", e)))?;
-
-        let response_str = String::from_utf8_lossy(&response[..bytes_read]);
-        info!(
-            "Proxy response: {}",
-            response_str.lines().next().unwrap_or("")
-        );
-
-        if !response_str.contains("200") {
-            return Err(Error::Network(format!(
-                "Proxy CONNECT failed: {}",
-                response_str.lines().next().unwrap_or("Unknown error")
-            )));
+        let (stream, response) = self.attempt(preemptive).await?;
+        if response.status != 407 {
+            return self.finish(stream, response);
         }
+        drop(stream);
 
-        println!("Successfully connected through proxy");
-        Ok(stream)
+        if preemptive.is_some() {
+            return Err(ProxyError::AuthenticationFailed);
+        }
+        let challenge = response.proxy_authenticate.join(", ");
+        let Some(auth) = self.auth.as_deref() else {
+            return Err(ProxyError::AuthenticationRequired { challenge });
+        };
+        if !response.proxy_authenticate.is_empty()
+            && !response
+                .proxy_authenticate
+                .iter()
+                .any(|c| auth_scheme(c).eq_ignore_ascii_case("basic"))
+        {
+            return Err(ProxyError::UnsupportedAuthScheme(challenge));
+        }
+
+        info!("Proxy requested authentication, retrying with credentials");
+        // Proxies commonly close the connection after a 407, so retry on a
+        // fresh one instead of draining the challenge body
+        let (stream, response) = self.attempt(Some(auth)).await?;
+        if response.status == 407 {
+            return Err(ProxyError::AuthenticationFailed);
+        }
+        self.finish(stream, response)
+    }
+
+    async fn attempt(
+        &self,
+        auth: Option<&str>,
+    ) -> Result<(TcpStream, ConnectResponse), ProxyError> {
+        timeout(HANDSHAKE_TIMEOUT, self.exchange(auth))
+            .await
+            .map_err(|_| ProxyError::Timeout(HANDSHAKE_TIMEOUT))?
+    }
+
+    async fn exchange(
+        &self,
+        auth: Option<&str>,
+    ) -> Result<(TcpStream, ConnectResponse), ProxyError> {
+        let address = format!("{}:{}", self.proxy_host, self.proxy_port);
+        info!("Connecting to proxy {}", address);
+
+        let mut stream = TcpStream::connect((self.proxy_host.as_str(), self.proxy_port))
+            .await
+            .map_err(|source| ProxyError::Connect {
+                address: address.clone(),
+                source,
+            })?;
+        stream.set_nodelay(true)?;
+
+        let target = self.target();
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(auth) = auth {
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
+        }
+        request.push_str("Proxy-Connection: keep-alive\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let response = read_response(&mut stream).await?;
+        info!("Proxy response: {} {}", response.status, response.reason);
+        Ok((stream, response))
+    }
+
+    fn finish(
+        &self,
+        stream: TcpStream,
+        response: ConnectResponse,
+    ) -> Result<TcpStream, ProxyError> {
+        if (200..300).contains(&response.status) {
+            info!("Tunnel to {} established through proxy", self.target());
+            Ok(stream)
+        } else {
+            Err(ProxyError::Rejected {
+                target: self.target(),
+                status: response.status,
+                reason: response.reason,
+            })
+        }
+    }
+
+    fn target(&self) -> String {
+        if self.target_host.contains(':') {
+            format!("[{}]:{}", self.target_host, self.target_port)
+        } else {
+            format!("{}:{}", self.target_host, self.target_port)
+        }
+    }
+}
+
+/// Read until the end of the response head, however the proxy splits it
+/// across TCP segments. Error bodies are ignored; the connection is dropped.
+async fn read_response(stream: &mut TcpStream) -> Result<ConnectResponse, ProxyError> {
+    let mut head = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(ProxyError::ConnectionClosed);
+        }
+        head.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Response::new(&mut headers);
+        match parsed.parse(&head) {
+            Ok(httparse::Status::Complete(len)) => {
+                let status = parsed
+                    .code
+                    .ok_or_else(|| ProxyError::MalformedResponse("missing status".to_string()))?;
+                if (200..300).contains(&status) && len < head.len() {
+                    // The client speaks first on both WebSocket and TLS, so
+                    // anything here means the proxy is not acting as a tunnel
+                    warn!("Proxy sent {} unexpected bytes", head.len() - len);
+                    return Err(ProxyError::MalformedResponse(
+                        "unexpected data after CONNECT response".to_string(),
+                    ));
+                }
+                let reason = parsed.reason.unwrap_or("").to_string();
+                let proxy_authenticate = parsed
+                    .headers
+                    .iter()
+                    .filter(|h| h.name.eq_ignore_ascii_case("proxy-authenticate"))
+                    .map(|h| String::from_utf8_lossy(h.value).into_owned())
+                    .collect();
+                return Ok(ConnectResponse {
+                    status,
+                    reason,
+                    proxy_authenticate,
+                });
+            }
+            Ok(httparse::Status::Partial) => {
+                if head.len() > MAX_RESPONSE_HEAD {
+                    return Err(ProxyError::ResponseTooLarge);
+                }
+            }
+            Err(e) => return Err(ProxyError::MalformedResponse(e.to_string())),
+        }
+    }
+}
+
+/// Scheme token of a `Proxy-Authenticate` challenge, e.g. `Basic`
+fn auth_scheme(challenge: &str) -> &str {
+    challenge.split_whitespace().next().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Read one CONNECT request head from a client of the stub proxy
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// A stub proxy answering each connection with the next reply, written
+    /// in the given pieces; yields the requests it received
+    async fn stub_proxy(replies: Vec<Vec<&'static str>>) -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for pieces in replies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                requests.push(read_request(&mut stream).await);
+                for piece in pieces {
+                    stream.write_all(piece.as_bytes()).await.unwrap();
+                    stream.flush().await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+            requests
+        });
+        (port, handle)
+    }
+
+    fn connector(port: u16, auth: Option<&str>, no_auth: bool) -> ProxyConnector {
+        ProxyConnector::new(
+            "127.0.0.1".to_string(),
+            port,
+            "relay.example".to_string(),
+            443,
+            auth.map(str::to_string),
+            no_auth,
+        )
+    }
+
+    #[tokio::test]
+    async fn reads_connect_response_split_across_reads() {
+        let (port, proxy) = stub_proxy(vec![vec![
+            "HTTP/1.1 200 Conn",
+            "ection established\r\nVia: stub\r",
+            "\n\r\n",
+        ]])
+        .await;
+
+        connector(port, None, false).connect().await.unwrap();
+        let requests = proxy.await.unwrap();
+        assert!(requests[0].starts_with("CONNECT relay.example:443 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn retries_with_credentials_after_407() {
+        let (port, proxy) = stub_proxy(vec![
+            vec!["HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"stub\"\r\n\r\n"],
+            vec!["HTTP/1.1 200 OK\r\n\r\n"],
+        ])
+        .await;
+
+        connector(port, Some("dXNlcjpwYXNz"), true)
+            .connect()
+            .await
+            .unwrap();
+        let requests = proxy.await.unwrap();
+        assert!(!requests[0].contains("Proxy-Authorization"));
+        assert!(requests[1].contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn maps_non_2xx_status_to_rejected() {
+        let (port, _proxy) = stub_proxy(vec![vec!["HTTP/1.1 403 Forbidden\r\n\r\n"]]).await;
+
+        let result = connector(port, None, false).connect().await;
+        match result {
+            Err(ProxyError::Rejected {
+                target,
+                status,
+                reason,
+            }) => {
+                assert_eq!(target, "relay.example:443");
+                assert_eq!(status, 403);
+                assert_eq!(reason, "Forbidden");
+            }
+            other => panic!("expected Rejected, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,184 @@
+use crate::config::ReconnectConfig;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Lifecycle of the relay connection, published for other tasks to observe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Dialling the relay; `attempt` counts consecutive failures so far
+    Connecting {
+        attempt: u32,
+    },
+    Connected {
+        since: Instant,
+    },
+    /// Waiting before the next dial
+    Backoff {
+        attempt: u32,
+        delay: Duration,
+        next_attempt_at: Instant,
+    },
+    /// Reconnection was stopped and no further attempts will be made
+    Stopped,
+}
+
+/// Exponential backoff with full jitter and a reset after a stable connection
+#[derive(Debug)]
+pub struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+    connected_at: Option<Instant>,
+    state: watch::Sender<ConnectionState>,
+    rng: StdRng,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Self::with_rng(config, StdRng::from_entropy())
+    }
+
+    fn with_rng(config: ReconnectConfig, rng: StdRng) -> Self {
+        let (state, _) = watch::channel(ConnectionState::Connecting { attempt: 0 });
+        Self {
+            config,
+            attempt: 0,
+            connected_at: None,
+            state,
+            rng,
+        }
+    }
+
+    /// Receiver that always holds the latest [`ConnectionState`]
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Consecutive failed attempts since the last stable connection
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn on_connecting(&self) {
+        self.state.send_replace(ConnectionState::Connecting {
+            attempt: self.attempt,
+        });
+    }
+
+    pub fn on_connected(&mut self) {
+        let now = Instant::now();
+        self.connected_at = Some(now);
+        self.state
+            .send_replace(ConnectionState::Connected { since: now });
+    }
+
+    pub fn on_stopped(&self) {
+        self.state.send_replace(ConnectionState::Stopped);
+    }
+
+    /// Record a failed attempt or a dropped connection and return how long
+    /// to wait before dialling again
+    pub fn next_delay(&mut self) -> Duration {
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= self.config.stable_after() {
+                self.attempt = 0;
+            }
+        }
+
+        self.attempt = self.attempt.saturating_add(1);
+        let delay = self.jittered_delay();
+        self.state.send_replace(ConnectionState::Backoff {
+            attempt: self.attempt,
+            delay,
+            next_attempt_at: Instant::now() + delay,
+        });
+        delay
+    }
+
+    fn ceiling(&self) -> Duration {
+        let exponent = self.attempt.saturating_sub(1).min(63) as i32;
+        let millis = self.config.initial_delay_ms as f64 * self.config.multiplier.powi(exponent);
+        let max = self.config.max_delay();
+        if millis.is_finite() && millis < max.as_millis() as f64 {
+            Duration::from_millis(millis as u64)
+        } else {
+            max
+        }
+    }
+
+    fn jittered_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling().as_millis() as u64;
+        Duration::from_millis(self.rng.gen_range(0..=ceiling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        let config = ReconnectConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 5_000,
+            multiplier: 2.0,
+            stable_after_secs: 60,
+        };
+        Backoff::with_rng(config, StdRng::seed_from_u64(7))
+    }
+
+    #[test]
+    fn delays_stay_below_the_growing_ceiling() {
+        let mut backoff = backoff();
+        let mut total = Duration::ZERO;
+        for n in 1..=16u32 {
+            let ceiling = Duration::from_millis((100u64 << (n - 1).min(20)).min(5_000));
+            let delay = backoff.next_delay();
+            assert_eq!(backoff.attempt(), n);
+            assert!(
+                delay <= ceiling,
+                "attempt {}: {:?} > {:?}",
+                n,
+                delay,
+                ceiling
+            );
+            total += delay;
+        }
+        // Jitter spreads the delays rather than pinning them to zero
+        assert!(total > Duration::from_secs(5), "{:?}", total);
+    }
+
+    #[test]
+    fn same_seed_gives_same_delays() {
+        let (mut a, mut b) = (backoff(), backoff());
+        for _ in 0..8 {
+            assert_eq!(a.next_delay(), b.next_delay());
+        }
+    }
+
+    #[test]
+    fn resets_after_a_stable_connection_only() {
+        let mut backoff = backoff();
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+
+        backoff.on_connected();
+        backoff.next_delay();
+        assert_eq!(
+            backoff.attempt(),
+            6,
+            "a short connection continues the sequence"
+        );
+
+        backoff.on_connected();
+        backoff.connected_at = Instant::now().checked_sub(Duration::from_secs(61));
+        let delay = backoff.next_delay();
+        assert_eq!(backoff.attempt(), 1);
+        assert!(delay <= Duration::from_millis(100), "{:?}", delay);
+        assert!(matches!(
+            *backoff.subscribe().borrow(),
+            ConnectionState::Backoff { attempt: 1, .. }
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::network::proxy::{ProxyError, HANDSHAKE_TIMEOUT};
+use log::info;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const USER_PASS_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Opens a TCP tunnel through a SOCKS5 proxy (RFC 1928), authenticating with
+/// username/password (RFC 1929) when credentials are given. Host names are
+/// passed to the proxy unresolved so DNS happens on the proxy side.
+pub struct Socks5Connector {
+    proxy_host: String,
+    proxy_port: u16,
+    target_host: String,
+    target_port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Connector {
+    pub fn new(
+        proxy_host: String,
+        proxy_port: u16,
+        target_host: String,
+        target_port: u16,
+        credentials: Option<(String, String)>,
+    ) -> Self {
+        Self {
+            proxy_host,
+            proxy_port,
+            target_host,
+            target_port,
+            credentials,
+        }
+    }
+
+    pub async fn connect(&self) -> Result<TcpStream, ProxyError> {
+        timeout(HANDSHAKE_TIMEOUT, self.handshake())
+            .await
+            .map_err(|_| ProxyError::Timeout(HANDSHAKE_TIMEOUT))?
+    }
+
+    async fn handshake(&self) -> Result<TcpStream, ProxyError> {
+        let address = format!("{}:{}", self.proxy_host, self.proxy_port);
+        info!("Connecting to SOCKS5 proxy {}", address);
+
+        let mut stream = TcpStream::connect((self.proxy_host.as_str(), self.proxy_port))
+            .await
+            .map_err(|source| ProxyError::Connect { address, source })?;
+        stream.set_nodelay(true)?;
+
+        self.negotiate_method(&mut stream).await?;
+        self.request_connect(&mut stream).await?;
+
+        info!(
+            "Tunnel to {}:{} established through SOCKS5 proxy",
+            self.target_host, self.target_port
+        );
+        Ok(stream)
+    }
+
+    async fn negotiate_method(&self, stream: &mut TcpStream) -> Result<(), ProxyError> {
+        let greeting: &[u8] = if self.credentials.is_some() {
+            &[VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS]
+        } else {
+            &[VERSION, 1, METHOD_NO_AUTH]
+        };
+        stream.write_all(greeting).await?;
+
+        let mut reply = [0u8; 2];
+        read_exact(stream, &mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(ProxyError::MalformedResponse(format!(
+                "unexpected SOCKS version {}",
+                reply[0]
+            )));
+        }
+
+        match (reply[1], &self.credentials) {
+            (METHOD_NO_AUTH, _) => Ok(()),
+            (METHOD_USER_PASS, Some((user, pass))) => authenticate(stream, user, pass).await,
+            (METHOD_NONE_ACCEPTABLE, None) => Err(ProxyError::AuthenticationRequired {
+                challenge: "SOCKS5 username/password".to_string(),
+            }),
+            (METHOD_NONE_ACCEPTABLE, Some(_)) => Err(ProxyError::NoAcceptableAuthMethod),
+            (method, _) => Err(ProxyError::MalformedResponse(format!(
+                "proxy selected a method that was not offered ({:#04x})",
+                method
+            ))),
+        }
+    }
+
+    async fn request_connect(&self, stream: &mut TcpStream) -> Result<(), ProxyError> {
+        let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+        let host = self
+            .target_host
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let len = u8::try_from(host.len()).map_err(|_| {
+                    ProxyError::InvalidTarget(format!("host name '{}' is too long", host))
+                })?;
+                request.push(ATYP_DOMAIN);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&self.target_port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        read_exact(stream, &mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(ProxyError::MalformedResponse(format!(
+                "unexpected SOCKS version {}",
+                reply[0]
+            )));
+        }
+        if reply[1] != 0x00 {
+            return Err(ProxyError::Rejected {
+                target: format!("{}:{}", self.target_host, self.target_port),
+                status: reply[1] as u16,
+                reason: reply_reason(reply[1]).to_string(),
+            });
+        }
+
+        // Discard the bound address so the stream starts at tunnel data
+        let address_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                read_exact(stream, &mut len).await?;
+                len[0] as usize
+            }
+            other => {
+                return Err(ProxyError::MalformedResponse(format!(
+                    "unknown address type {:#04x}",
+                    other
+                )))
+            }
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        read_exact(stream, &mut bound).await?;
+        Ok(())
+    }
+}
+
+async fn authenticate(stream: &mut TcpStream, user: &str, pass: &str) -> Result<(), ProxyError> {
+    let (Ok(user_len), Ok(pass_len)) = (u8::try_from(user.len()), u8::try_from(pass.len())) else {
+        return Err(ProxyError::InvalidCredentials(
+            "SOCKS5 username and password are limited to 255 bytes".to_string(),
+        ));
+    };
+    let mut request = vec![USER_PASS_VERSION, user_len];
+    request.extend_from_slice(user.as_bytes());
+    request.push(pass_len);
+    request.extend_from_slice(pass.as_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 2];
+    read_exact(stream, &mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(ProxyError::AuthenticationFailed);
+    }
+    Ok(())
+}
+
+/// `read_exact` that reports an early close as [`ProxyError::ConnectionClosed`]
+async fn read_exact(stream: &mut TcpStream, buf: &mut [u8]) -> Result<(), ProxyError> {
+    match stream.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(ProxyError::ConnectionClosed)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn reply_reason(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
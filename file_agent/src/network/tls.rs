@@ -0,0 +1,258 @@
+use crate::{
+    config::TlsConfig,
+    error::{Error, Result},
+};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::fs;
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
+
+/// TLS client built from [`TlsConfig`], shared by the direct and proxy paths
+#[derive(Clone)]
+pub struct TlsSettings {
+    connector: TlsConnector,
+    pin: Option<[u8; 32]>,
+}
+
+impl TlsSettings {
+    pub fn from_config(config: &TlsConfig) -> Result<Self> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(path) = &config.ca_bundle {
+            let pem = fs::read(path)
+                .map_err(|e| Error::Tls(format!("Failed to read CA bundle '{}': {}", path, e)))?;
+            let certs = split_pem_certificates(&pem);
+            if certs.is_empty() {
+                return Err(Error::Tls(format!("No certificates found in '{}'", path)));
+            }
+            for cert in certs {
+                let cert = native_tls::Certificate::from_pem(&cert).map_err(|e| {
+                    Error::Tls(format!("Invalid CA certificate in '{}': {}", path, e))
+                })?;
+                builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(identity) = load_identity(config)? {
+            builder.identity(identity);
+        }
+
+        let pin = config
+            .pin()
+            .transpose()
+            .map_err(|e| Error::Tls(format!("Invalid certificate pin: {}", e)))?;
+
+        if config.insecure {
+            warn!("TLS certificate verification is disabled");
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        } else if pin.is_some() {
+            // The pin identifies the exact leaf certificate, so chain and
+            // hostname checks are replaced by the fingerprint comparison
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+
+        let connector = builder
+            .build()
+            .map_err(|e| Error::Tls(format!("Failed to build TLS connector: {}", e)))?;
+
+        Ok(Self {
+            connector: TlsConnector::from(connector),
+            pin,
+        })
+    }
+
+    /// Perform the TLS handshake and enforce the certificate pin
+    pub async fn connect(&self, host: &str, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let tls_stream = self
+            .connector
+            .connect(host, stream)
+            .await
+            .map_err(|e| Error::Tls(format!("TLS handshake with {} failed: {}", host, e)))?;
+
+        if let Some(expected) = &self.pin {
+            let cert = tls_stream
+                .get_ref()
+                .peer_certificate()
+                .map_err(|e| Error::Tls(format!("Failed to read server certificate: {}", e)))?
+                .ok_or_else(|| Error::Tls("Server presented no certificate".to_string()))?;
+            let der = cert
+                .to_der()
+                .map_err(|e| Error::Tls(format!("Failed to encode server certificate: {}", e)))?;
+            let actual = certificate_fingerprint(&der);
+            if &actual != expected {
+                return Err(Error::Tls(format!(
+                    "Server certificate fingerprint {} does not match the configured pin",
+                    format_fingerprint(&actual)
+                )));
+            }
+        }
+
+        Ok(tls_stream)
+    }
+}
+
+/// Client certificate for mutual TLS, from PKCS#12 or a PEM cert/key pair
+fn load_identity(config: &TlsConfig) -> Result<Option<native_tls::Identity>> {
+    if let Some(path) = &config.client_pkcs12 {
+        let der = fs::read(path)
+            .map_err(|e| Error::Tls(format!("Failed to read client identity '{}': {}", path, e)))?;
+        let password = config.client_pkcs12_password.as_deref().unwrap_or("");
+        let identity = native_tls::Identity::from_pkcs12(&der, password)
+            .map_err(|e| Error::Tls(format!("Invalid PKCS#12 identity '{}': {}", path, e)))?;
+        return Ok(Some(identity));
+    }
+
+    let (Some(cert_path), Some(key_path)) = (&config.client_cert, &config.client_key) else {
+        return Ok(None);
+    };
+    let cert = fs::read(cert_path).map_err(|e| {
+        Error::Tls(format!(
+            "Failed to read client certificate '{}': {}",
+            cert_path, e
+        ))
+    })?;
+    let key = fs::read(key_path)
+        .map_err(|e| Error::Tls(format!("Failed to read client key '{}': {}", key_path, e)))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|e| {
+        Error::Tls(format!(
+            "Invalid client certificate/key '{}'/'{}': {}",
+            cert_path, key_path, e
+        ))
+    })?;
+    Ok(Some(identity))
+}
+
+/// SHA-256 over a DER encoded certificate
+pub fn certificate_fingerprint(der: &[u8]) -> [u8; 32] {
+    Sha256::digest(der).into()
+}
+
+/// Colon separated upper-case hex, as printed by `openssl x509 -fingerprint`
+pub fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// native-tls only parses a single certificate per PEM blob
+fn split_pem_certificates(pem: &[u8]) -> Vec<Vec<u8>> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = String::from_utf8_lossy(pem);
+    let mut certs = Vec::new();
+    let mut rest = text.as_ref();
+    while let Some(start) = rest.find("-----BEGIN CERTIFICATE-----") {
+        let Some(end) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + end + END.len();
+        certs.push(rest.as_bytes()[start..end].to_vec());
+        rest = &rest[end..];
+    }
+    certs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+
+    /// A self-signed certificate for `localhost`, standing in for a relay
+    /// deployed with its own CA
+    struct SelfSigned {
+        pem: String,
+        key_pem: String,
+        der: Vec<u8>,
+    }
+
+    fn self_signed() -> SelfSigned {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        SelfSigned {
+            pem: cert.pem(),
+            key_pem: key.serialize_pem(),
+            der: cert.der().to_vec(),
+        }
+    }
+
+    /// Accept one TLS connection on a local port, returning the port
+    async fn serve(cert: &SelfSigned) -> u16 {
+        let identity =
+            native_tls::Identity::from_pkcs8(cert.pem.as_bytes(), cert.key_pem.as_bytes()).unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // The client may abort the handshake; that is the point of some tests
+            let _ = acceptor.accept(stream).await;
+        });
+        port
+    }
+
+    async fn handshake(config: &TlsConfig, port: u16) -> Result<()> {
+        let settings = TlsSettings::from_config(config)?;
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        settings.connect("localhost", stream).await.map(drop)
+    }
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn rejects_self_signed_certificate_by_default() {
+        let cert = self_signed();
+        let port = serve(&cert).await;
+        let result = handshake(&TlsConfig::default(), port).await;
+        assert!(matches!(result, Err(Error::Tls(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn accepts_certificate_from_ca_bundle() {
+        let cert = self_signed();
+        let port = serve(&cert).await;
+        let bundle = write_temp("relay-ca.pem", &cert.pem);
+        let config = TlsConfig {
+            ca_bundle: Some(bundle.to_string_lossy().into_owned()),
+            ..TlsConfig::default()
+        };
+        let result = handshake(&config, port).await;
+        fs::remove_file(&bundle).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepts_pinned_certificate() {
+        let cert = self_signed();
+        let port = serve(&cert).await;
+        let config = TlsConfig {
+            pin_sha256: Some(format_fingerprint(&certificate_fingerprint(&cert.der))),
+            ..TlsConfig::default()
+        };
+        handshake(&config, port).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_certificate_not_matching_pin() {
+        let cert = self_signed();
+        let port = serve(&cert).await;
+        let config = TlsConfig {
+            pin_sha256: Some(format_fingerprint(&[0xAB; 32])),
+            ..TlsConfig::default()
+        };
+        let result = handshake(&config, port).await;
+        assert!(matches!(result, Err(Error::Tls(_))), "{:?}", result);
+    }
+}
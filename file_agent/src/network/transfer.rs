@@ -0,0 +1,515 @@
+//! Chunked file transfers carried in binary frames
+//!
+//! Every frame starts with a 16 byte big-endian header:
+//!
+//! | bytes | field                                         |
+//! |-------|-----------------------------------------------|
+//! | 0..2  | message type, always [`FILE_TRANSFER`]        |
+//! | 2     | [`FrameKind`]                                 |
+//! | 3     | reserved, zero                                |
+//! | 4..8  | transfer id from the `download_start` offer   |
+//! | 8..16 | byte offset of the payload within the file    |
+//!
+//! `Data` frames carry file bytes, downloaded or uploaded. The final `End`
+//! frame of a download has the total size as its offset and the SHA-256 of
+//! the whole file as its payload. The agent answers each upload chunk with
+//! an empty `Ack` frame whose offset is the number of bytes stored. An
+//! `Error` frame (offset zero) carries a JSON `{code, message}` body.
+//! Binary frames are used throughout because the relay forwards them to
+//! browser clients as is.
+
+use crate::{
+    error::{Error, Result},
+    filesystem::Job,
+    network::{
+        outbound::Outbound,
+        protocol::{ErrorBody, ErrorCode, Response},
+    },
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+/// Binary message type of transfer frames
+pub const FILE_TRANSFER: u16 = 200;
+pub const HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Data = 1,
+    End = 2,
+    Error = 3,
+    Ack = 4,
+}
+
+impl FrameKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FrameKind::Data),
+            2 => Some(FrameKind::End),
+            3 => Some(FrameKind::Error),
+            4 => Some(FrameKind::Ack),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded transfer frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub kind: FrameKind,
+    pub transfer_id: u32,
+    pub offset: u64,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        frame.extend_from_slice(&FILE_TRANSFER.to_be_bytes());
+        frame.push(self.kind as u8);
+        frame.push(0);
+        frame.extend_from_slice(&self.transfer_id.to_be_bytes());
+        frame.extend_from_slice(&self.offset.to_be_bytes());
+        frame.extend_from_slice(self.payload);
+        frame
+    }
+
+    /// `None` unless `data` is a well-formed transfer frame
+    pub fn decode(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[..2] != FILE_TRANSFER.to_be_bytes() {
+            return None;
+        }
+        Some(Self {
+            kind: FrameKind::from_u8(data[2])?,
+            transfer_id: u32::from_be_bytes(data[4..8].try_into().ok()?),
+            offset: u64::from_be_bytes(data[8..16].try_into().ok()?),
+            payload: &data[HEADER_LEN..],
+        })
+    }
+}
+
+/// Acknowledged offsets of the transfers in progress, by transfer id
+#[derive(Clone, Default)]
+pub(crate) struct Transfers {
+    next_id: Arc<AtomicU32>,
+    acked: Arc<Mutex<HashMap<u32, watch::Sender<u64>>>>,
+}
+
+impl Transfers {
+    /// A new transfer id, or `None` if `limit` transfers are already running
+    pub(crate) fn register(&self, limit: usize) -> Option<u32> {
+        let mut acked = self.acked.lock().unwrap();
+        if acked.len() >= limit {
+            return None;
+        }
+        let id = self.next_id();
+        acked.insert(id, watch::channel(0).0);
+        Some(id)
+    }
+
+    /// An id no other download or upload of this agent is using
+    pub(crate) fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    fn subscribe(&self, id: u32, offset: u64) -> Option<watch::Receiver<u64>> {
+        let acked = self.acked.lock().unwrap();
+        let sender = acked.get(&id)?;
+        sender.send_replace(offset);
+        Some(sender.subscribe())
+    }
+
+    /// Record that the client has everything before `offset`. Returns
+    /// `false` for an unknown transfer.
+    pub(crate) fn ack(&self, id: u32, offset: u64) -> bool {
+        match self.acked.lock().unwrap().get(&id) {
+            Some(sender) => {
+                sender.send_if_modified(|acked| {
+                    let advanced = offset > *acked;
+                    if advanced {
+                        *acked = offset;
+                    }
+                    advanced
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn remove(&self, id: u32) {
+        self.acked.lock().unwrap().remove(&id);
+    }
+}
+
+/// A file being sent to the client, from `offset` to its end
+pub(crate) struct Download {
+    pub transfer_id: u32,
+    path: PathBuf,
+    filename: String,
+    size: u64,
+    sha256: String,
+    offset: u64,
+    chunk_size: u32,
+    /// Hash state covering the bytes before `offset`
+    prefix: Sha256,
+}
+
+impl Download {
+    /// Hash the file, noting the hash state at `offset` so the checksum sent
+    /// at the end covers the whole file. Blocking; checks `job` between reads.
+    pub(crate) fn prepare(
+        transfer_id: u32,
+        path: &str,
+        offset: u64,
+        chunk_size: u32,
+        job: &Job,
+    ) -> Result<Self> {
+        if path.is_empty() {
+            return Err(Error::FileSystem("Empty path provided".to_string()));
+        }
+        let path = PathBuf::from(path);
+        let mut file = File::open(&path).map_err(|e| Error::file_io("Failed to open file", e))?;
+        let size = file
+            .metadata()
+            .map_err(|e| Error::file_io("Failed to read file metadata", e))?
+            .len();
+        if offset > size {
+            return Err(Error::file_io(
+                format!(
+                    "Offset {} is beyond the end of the file ({} bytes)",
+                    offset, size
+                ),
+                io::Error::from(io::ErrorKind::InvalidInput),
+            ));
+        }
+
+        let mut hasher = Sha256::new();
+        let mut prefix = (offset == 0).then(Sha256::new);
+        let mut read_total = 0u64;
+        let mut buffer = vec![0u8; 1024 * 1024];
+        loop {
+            job.check_cancelled()?;
+            let read = file
+                .read(&mut buffer)
+                .map_err(|e| Error::file_io("Failed to read file", e))?;
+            if read == 0 {
+                break;
+            }
+            let chunk = &buffer[..read];
+            if prefix.is_none() && read_total + read as u64 >= offset {
+                let split = (offset - read_total) as usize;
+                hasher.update(&chunk[..split]);
+                prefix = Some(hasher.clone());
+                hasher.update(&chunk[split..]);
+            } else {
+                hasher.update(chunk);
+            }
+            read_total += read as u64;
+        }
+
+        let filename = Path::new(&path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+        Ok(Self {
+            transfer_id,
+            path,
+            filename,
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+            offset,
+            chunk_size,
+            // The file shrank below `offset` while hashing; streaming will
+            // send nothing and the final checksum will not match
+            prefix: prefix.unwrap_or_default(),
+        })
+    }
+
+    pub(crate) fn offer(&self, window: u32) -> Response {
+        Response::DownloadStartResult {
+            transfer_id: self.transfer_id,
+            filename: self.filename.clone(),
+            size: self.size,
+            sha256: self.sha256.clone(),
+            offset: self.offset,
+            chunk_size: self.chunk_size,
+            window,
+        }
+    }
+
+    /// Send the file from `offset` as `Data` frames followed by an `End`
+    /// frame, keeping at most `window` chunks unacknowledged
+    pub(crate) async fn stream(
+        self,
+        transfers: &Transfers,
+        out: &Outbound,
+        window: u32,
+        idle_timeout: Duration,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<(), ErrorBody> {
+        let id = self.transfer_id;
+        let mut acked = transfers
+            .subscribe(id, self.offset)
+            .ok_or_else(|| ErrorBody::new(ErrorCode::Internal, "Transfer is not registered"))?;
+        let window_bytes = u64::from(window) * u64::from(self.chunk_size);
+
+        let mut file = tokio::fs::File::open(&self.path)
+            .await
+            .map_err(|e| io_error("Failed to open file", e))?;
+        file.seek(SeekFrom::Start(self.offset))
+            .await
+            .map_err(|e| io_error("Failed to seek", e))?;
+
+        let mut hasher = self.prefix;
+        let mut sent = self.offset;
+        let mut buffer = vec![0u8; self.chunk_size as usize];
+        loop {
+            while sent.saturating_sub(*acked.borrow()) >= window_bytes {
+                tokio::select! {
+                    changed = acked.changed() => if changed.is_err() {
+                        return Err(ErrorBody::new(ErrorCode::Cancelled, "Transfer was removed"));
+                    },
+                    _ = cancel.cancelled() => return Err(ErrorBody::cancelled()),
+                    _ = tokio::time::sleep(idle_timeout) => {
+                        return Err(ErrorBody::new(
+                            ErrorCode::TimedOut,
+                            format!("No acknowledgement for {}s", idle_timeout.as_secs()),
+                        ));
+                    }
+                }
+            }
+            if cancel.is_cancelled() {
+                return Err(ErrorBody::cancelled());
+            }
+
+            let read = read_chunk(&mut file, &mut buffer)
+                .await
+                .map_err(|e| io_error("Failed to read file", e))?;
+            if read == 0 {
+                break;
+            }
+            let payload = &buffer[..read];
+            hasher.update(payload);
+            let frame = Frame {
+                kind: FrameKind::Data,
+                transfer_id: id,
+                offset: sent,
+                payload,
+            };
+            out.send(Message::Binary(frame.encode()))
+                .await
+                .map_err(|e| ErrorBody::from(&e))?;
+            sent += read as u64;
+        }
+
+        let digest = hasher.finalize();
+        let end = Frame {
+            kind: FrameKind::End,
+            transfer_id: id,
+            offset: sent,
+            payload: &digest,
+        };
+        out.send(Message::Binary(end.encode()))
+            .await
+            .map_err(|e| ErrorBody::from(&e))
+    }
+}
+
+/// The `Ack` frame confirming an upload has `offset` bytes stored
+pub(crate) fn ack_frame(transfer_id: u32, offset: u64) -> Message {
+    Message::Binary(
+        Frame {
+            kind: FrameKind::Ack,
+            transfer_id,
+            offset,
+            payload: &[],
+        }
+        .encode(),
+    )
+}
+
+/// The `Error` frame ending a failed transfer
+pub(crate) fn error_frame(transfer_id: u32, error: &ErrorBody) -> Message {
+    let body = serde_json::to_vec(error).unwrap_or_default();
+    Message::Binary(
+        Frame {
+            kind: FrameKind::Error,
+            transfer_id,
+            offset: 0,
+            payload: &body,
+        }
+        .encode(),
+    )
+}
+
+/// Fill `buffer` unless the file ends first
+async fn read_chunk(file: &mut tokio::fs::File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = file.read(&mut buffer[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+fn io_error(context: &str, e: io::Error) -> ErrorBody {
+    ErrorBody::from(&Error::file_io(context, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+    use tokio::sync::mpsc;
+
+    const CONTENTS: &[u8] = b"0123456789";
+
+    fn prepare(dir: &TestDir, transfers: &Transfers, offset: u64) -> Download {
+        let path = dir.write("file.bin", CONTENTS);
+        let id = transfers.register(1).unwrap();
+        Download::prepare(id, path.to_str().unwrap(), offset, 4, &Job::detached()).unwrap()
+    }
+
+    async fn next_frame(rx: &mut mpsc::Receiver<Message>) -> (FrameKind, u64, Vec<u8>) {
+        match rx.recv().await.unwrap() {
+            Message::Binary(data) => {
+                let frame = Frame::decode(&data).unwrap();
+                (frame.kind, frame.offset, frame.payload.to_vec())
+            }
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = Frame {
+            kind: FrameKind::Data,
+            transfer_id: 7,
+            offset: 1 << 40,
+            payload: b"chunk",
+        };
+        let encoded = frame.encode();
+        assert_eq!(encoded.len(), HEADER_LEN + 5);
+        assert_eq!(Frame::decode(&encoded), Some(frame));
+
+        assert_eq!(Frame::decode(&encoded[..HEADER_LEN - 1]), None);
+        let mut unknown_kind = encoded.clone();
+        unknown_kind[2] = 9;
+        assert_eq!(Frame::decode(&unknown_kind), None);
+    }
+
+    #[test]
+    fn acks_only_move_forward_and_need_a_registered_transfer() {
+        let transfers = Transfers::default();
+        let id = transfers.register(1).unwrap();
+        assert_eq!(transfers.register(1), None);
+
+        let acked = transfers.subscribe(id, 0).unwrap();
+        assert!(transfers.ack(id, 8));
+        assert!(transfers.ack(id, 4));
+        assert_eq!(*acked.borrow(), 8);
+
+        transfers.remove(id);
+        assert!(!transfers.ack(id, 12));
+        assert!(transfers.register(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn stream_waits_for_acks_beyond_the_window() {
+        let dir = TestDir::new("download-window");
+        let transfers = Transfers::default();
+        let download = prepare(&dir, &transfers, 0);
+        let id = download.transfer_id;
+        let (out, mut rx) = Outbound::channel(16);
+
+        let streaming = {
+            let transfers = transfers.clone();
+            tokio::spawn(async move {
+                let cancel = CancellationToken::new();
+                download
+                    .stream(&transfers, &out, 1, Duration::from_secs(30), &cancel)
+                    .await
+            })
+        };
+
+        assert_eq!(
+            next_frame(&mut rx).await,
+            (FrameKind::Data, 0, b"0123".to_vec())
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err(), "sent past an unacknowledged window");
+
+        transfers.ack(id, 4);
+        assert_eq!(
+            next_frame(&mut rx).await,
+            (FrameKind::Data, 4, b"4567".to_vec())
+        );
+        transfers.ack(id, 8);
+        assert_eq!(
+            next_frame(&mut rx).await,
+            (FrameKind::Data, 8, b"89".to_vec())
+        );
+        let (kind, size, digest) = next_frame(&mut rx).await;
+        assert_eq!((kind, size), (FrameKind::End, 10));
+        assert_eq!(digest, Sha256::digest(CONTENTS).to_vec());
+        assert!(streaming.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn resumed_stream_ends_with_the_whole_file_checksum() {
+        let dir = TestDir::new("download-resume");
+        let transfers = Transfers::default();
+        let download = prepare(&dir, &transfers, 6);
+        let Response::DownloadStartResult { sha256, offset, .. } = download.offer(4) else {
+            panic!("expected a download_start_result");
+        };
+        assert_eq!(offset, 6);
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(CONTENTS)));
+
+        let (out, mut rx) = Outbound::channel(16);
+        let cancel = CancellationToken::new();
+        download
+            .stream(&transfers, &out, 4, Duration::from_secs(30), &cancel)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            next_frame(&mut rx).await,
+            (FrameKind::Data, 6, b"6789".to_vec())
+        );
+        let (kind, size, digest) = next_frame(&mut rx).await;
+        assert_eq!((kind, size), (FrameKind::End, 10));
+        assert_eq!(digest, Sha256::digest(CONTENTS).to_vec());
+    }
+
+    #[tokio::test]
+    async fn stream_gives_up_without_acks() {
+        let dir = TestDir::new("download-idle");
+        let transfers = Transfers::default();
+        let download = prepare(&dir, &transfers, 0);
+        let (out, _rx) = Outbound::channel(16);
+
+        let cancel = CancellationToken::new();
+        let error = download
+            .stream(&transfers, &out, 1, Duration::from_millis(20), &cancel)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::TimedOut);
+    }
+}
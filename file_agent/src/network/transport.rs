@@ -0,0 +1,80 @@
+use crate::{
+    config::{Config, EnvProxy, ProxyKind, ProxySettings},
+    error::{Error, Result},
+    network::{proxy::ProxyConnector, socks::Socks5Connector, tls::TlsSettings},
+};
+use log::info;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+/// WebSocket connection to the relay, plain or over TLS
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Open the relay WebSocket described by `url`, directly or through the
+/// configured proxy, verifying the server certificate per `config.tls`
+pub async fn connect(config: &Config, url: &Url) -> Result<WsStream> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::Config(format!("WebSocket URL '{}' has no host", url)))?
+        .to_string();
+    let port = url
+        .port_or_known_default()
+        .unwrap_or(if config.use_ssl { 443 } else { 80 });
+
+    let tcp_stream = match config.relay_proxy(&EnvProxy::from_env())? {
+        Some(proxy) => connect_through_proxy(&proxy, &host, port).await?,
+        None => connect_direct(&host, port).await?,
+    };
+
+    let stream = if config.use_ssl {
+        let tls = TlsSettings::from_config(&config.tls)?;
+        MaybeTlsStream::NativeTls(tls.connect(&host, tcp_stream).await?)
+    } else {
+        MaybeTlsStream::Plain(tcp_stream)
+    };
+
+    let (ws_stream, response) = tokio_tungstenite::client_async(url, stream)
+        .await
+        .map_err(|e| Error::Network(format!("WebSocket handshake failed: {}", e)))?;
+    info!("WebSocket handshake successful: {}", response.status());
+
+    Ok(ws_stream)
+}
+
+async fn connect_direct(host: &str, port: u16) -> Result<TcpStream> {
+    TcpStream::connect((host, port))
+        .await
+        .map_err(|e| Error::Network(format!("Failed to connect to {}:{}: {}", host, port, e)))
+}
+
+async fn connect_through_proxy(proxy: &ProxySettings, host: &str, port: u16) -> Result<TcpStream> {
+    let stream = match proxy.kind {
+        ProxyKind::Http => {
+            ProxyConnector::new(
+                proxy.host.clone(),
+                proxy.port,
+                host.to_string(),
+                port,
+                proxy.auth.clone(),
+                proxy.no_auth,
+            )
+            .connect()
+            .await?
+        }
+        ProxyKind::Socks5 => {
+            // Offering both methods lets the proxy decide, the SOCKS
+            // equivalent of answering a 407 challenge
+            Socks5Connector::new(
+                proxy.host.clone(),
+                proxy.port,
+                host.to_string(),
+                port,
+                proxy.credentials(),
+            )
+            .connect()
+            .await?
+        }
+    };
+    Ok(stream)
+}
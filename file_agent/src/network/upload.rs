@@ -0,0 +1,343 @@
+use crate::{
+    error::{Error, Result},
+    filesystem::Job,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// An upload being written to a hidden sibling of its target
+///
+/// The partial file is named after the target and the expected hash, so
+/// beginning the same upload again after a disconnect picks up where the
+/// last session stopped. It only replaces the target once the full size
+/// has arrived and the SHA-256 matches.
+pub(crate) struct UploadSession {
+    target: PathBuf,
+    part: PathBuf,
+    file: File,
+    size: u64,
+    sha256: String,
+    overwrite: bool,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl UploadSession {
+    /// Open or resume the partial file for `filename` in `dir`. Blocking;
+    /// hashing the already received part checks `job` between reads.
+    pub(crate) fn begin(
+        dir: &str,
+        filename: &str,
+        size: u64,
+        sha256: &str,
+        overwrite: bool,
+        job: &Job,
+    ) -> Result<Self> {
+        if dir.is_empty() || filename.is_empty() {
+            return Err(Error::FileSystem("Missing required parameters".to_string()));
+        }
+        if filename.contains(['/', '\\']) || filename == "." || filename == ".." {
+            return Err(invalid(format!("Invalid file name '{}'", filename)));
+        }
+        let sha256 = sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid("sha256 must be 64 hex digits".to_string()));
+        }
+
+        let target = Path::new(dir).join(filename);
+        if !overwrite && target.exists() {
+            return Err(already_exists(&target));
+        }
+        let part = Path::new(dir).join(format!(".{}.{}.part", filename, &sha256[..16]));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part)
+            .map_err(|e| Error::file_io("Failed to create upload file", e))?;
+        let mut existing = file
+            .metadata()
+            .map_err(|e| Error::file_io("Failed to read upload file", e))?
+            .len();
+        if existing > size {
+            file.set_len(0)
+                .map_err(|e| Error::file_io("Failed to reset upload file", e))?;
+            existing = 0;
+        }
+
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut hashed = 0u64;
+        while hashed < existing {
+            job.check_cancelled()?;
+            let want = buffer.len().min((existing - hashed) as usize);
+            let read = file
+                .read(&mut buffer[..want])
+                .map_err(|e| Error::file_io("Failed to read upload file", e))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            hashed += read as u64;
+        }
+        file.seek(SeekFrom::Start(hashed))
+            .map_err(|e| Error::file_io("Failed to read upload file", e))?;
+
+        Ok(Self {
+            target,
+            part,
+            file,
+            size,
+            sha256,
+            overwrite,
+            hasher,
+            written: hashed,
+        })
+    }
+
+    pub(crate) fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// Bytes received so far; the next chunk must start here
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Append a chunk. A failed write is rolled back to the previous chunk
+    /// boundary so the upload can be resumed from `written()`.
+    pub(crate) fn write(&mut self, offset: u64, data: &[u8]) -> Result<u64> {
+        if offset != self.written {
+            return Err(invalid(format!(
+                "Chunk at offset {} but expected {}",
+                offset, self.written
+            )));
+        }
+        if self.written + data.len() as u64 > self.size {
+            return Err(invalid(format!(
+                "Chunk exceeds the declared size of {} bytes",
+                self.size
+            )));
+        }
+
+        if let Err(e) = self.file.write_all(data) {
+            let _ = self.file.set_len(self.written);
+            let _ = self.file.seek(SeekFrom::Start(self.written));
+            return Err(Error::file_io("Failed to write upload", e));
+        }
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+        Ok(self.written)
+    }
+
+    /// Verify the upload and move it into place. A checksum mismatch
+    /// discards the partial file; other failures keep it for a retry.
+    pub(crate) fn commit(self) -> Result<(PathBuf, u64, String)> {
+        if self.written != self.size {
+            return Err(invalid(format!(
+                "Upload incomplete: {} of {} bytes received",
+                self.written, self.size
+            )));
+        }
+        let actual = format!("{:x}", self.hasher.finalize());
+        if actual != self.sha256 {
+            drop(self.file);
+            let _ = fs::remove_file(&self.part);
+            return Err(Error::ChecksumMismatch {
+                expected: self.sha256,
+                actual,
+            });
+        }
+
+        self.file
+            .sync_all()
+            .map_err(|e| Error::file_io("Failed to flush upload", e))?;
+        drop(self.file);
+        if !self.overwrite && self.target.exists() {
+            return Err(already_exists(&self.target));
+        }
+        fs::rename(&self.part, &self.target)
+            .map_err(|e| Error::file_io("Failed to move upload into place", e))?;
+        Ok((self.target, self.size, actual))
+    }
+
+    /// Give up on the upload and delete what was received
+    pub(crate) fn abort(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.part).map_err(|e| Error::file_io("Failed to remove upload", e))
+    }
+}
+
+/// Open upload sessions by upload id, with `None` holding the slot of one
+/// that is still beginning
+#[derive(Clone, Default)]
+pub(crate) struct Uploads {
+    sessions: Arc<Mutex<HashMap<u32, Option<SharedSession>>>>,
+}
+
+type SharedSession = Arc<Mutex<UploadSession>>;
+
+impl Uploads {
+    /// Hold a slot for upload `id` while its session begins. Returns
+    /// `false` if `limit` uploads are already open or beginning.
+    pub(crate) fn reserve(&self, id: u32, limit: usize) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= limit {
+            return false;
+        }
+        sessions.insert(id, None);
+        true
+    }
+
+    /// Fill the slot reserved for `id`. The session is dropped if the slot
+    /// is gone, as when the connection closed while it began.
+    pub(crate) fn insert(&self, id: u32, session: UploadSession) {
+        if let Some(slot) = self.sessions.lock().unwrap().get_mut(&id) {
+            *slot = Some(Arc::new(Mutex::new(session)));
+        }
+    }
+
+    /// Give back the slot of an upload that failed to begin
+    pub(crate) fn release(&self, id: u32) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if matches!(sessions.get(&id), Some(None)) {
+            sessions.remove(&id);
+        }
+    }
+
+    pub(crate) fn get(&self, id: u32) -> Option<SharedSession> {
+        self.sessions.lock().unwrap().get(&id).cloned().flatten()
+    }
+
+    /// Take the session out. Chunk writes finish before the next message
+    /// is read, so nothing else holds it by the time a commit or abort runs.
+    pub(crate) fn remove(&self, id: u32) -> Option<UploadSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !matches!(sessions.get(&id), Some(Some(_))) {
+            return None;
+        }
+        let session = sessions.remove(&id).flatten()?;
+        Arc::try_unwrap(session)
+            .ok()
+            .map(|session| session.into_inner().unwrap())
+    }
+
+    /// Close every session. Partial files stay behind for resuming.
+    pub(crate) fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+fn already_exists(path: &Path) -> Error {
+    Error::file_io(
+        format!("Cannot upload to {}", path.display()),
+        io::Error::from(io::ErrorKind::AlreadyExists),
+    )
+}
+
+fn invalid(message: String) -> Error {
+    Error::file_io(
+        "Invalid upload",
+        io::Error::new(io::ErrorKind::InvalidInput, message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir::TestDir;
+
+    const CONTENTS: &[u8] = b"hello, upload";
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    fn begin(dir: &TestDir, sha256: &str) -> UploadSession {
+        let path = dir.path().to_str().unwrap();
+        let size = CONTENTS.len() as u64;
+        UploadSession::begin(path, "file.txt", size, sha256, false, &Job::detached()).unwrap()
+    }
+
+    /// The partial files in `dir`
+    fn parts(dir: &TestDir) -> Vec<String> {
+        fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".part"))
+            .collect()
+    }
+
+    #[test]
+    fn begin_again_resumes_after_what_was_written() {
+        let dir = TestDir::new("upload-resume");
+        let hash = sha256(CONTENTS);
+        let mut first = begin(&dir, &hash);
+        assert_eq!(first.write(0, &CONTENTS[..5]).unwrap(), 5);
+        drop(first);
+
+        let mut resumed = begin(&dir, &hash);
+        assert_eq!(resumed.written(), 5);
+        assert!(resumed.write(0, CONTENTS).is_err());
+        resumed.write(5, &CONTENTS[5..]).unwrap();
+
+        let (path, size, actual) = resumed.commit().unwrap();
+        assert_eq!(path, dir.join("file.txt"));
+        assert_eq!(size, CONTENTS.len() as u64);
+        assert_eq!(actual, hash);
+        assert_eq!(fs::read(&path).unwrap(), CONTENTS);
+        assert!(parts(&dir).is_empty());
+    }
+
+    #[test]
+    fn checksum_mismatch_discards_the_partial_file() {
+        let dir = TestDir::new("upload-mismatch");
+        let wrong = sha256(b"something else");
+        let mut session = begin(&dir, &wrong);
+        session.write(0, CONTENTS).unwrap();
+        assert_eq!(parts(&dir).len(), 1);
+
+        let error = session.commit().unwrap_err();
+        assert!(
+            matches!(&error, Error::ChecksumMismatch { expected, actual }
+                if *expected == wrong && *actual == sha256(CONTENTS)),
+            "{}",
+            error
+        );
+        assert!(parts(&dir).is_empty());
+        assert!(!dir.join("file.txt").exists());
+
+        // Nothing is left to resume from
+        assert_eq!(begin(&dir, &wrong).written(), 0);
+    }
+
+    #[test]
+    fn incomplete_upload_is_kept_for_a_retry() {
+        let dir = TestDir::new("upload-incomplete");
+        let mut session = begin(&dir, &sha256(CONTENTS));
+        session.write(0, &CONTENTS[..3]).unwrap();
+
+        let error = session.commit().unwrap_err();
+        assert!(matches!(error, Error::FileIo { .. }), "{}", error);
+        assert_eq!(parts(&dir).len(), 1);
+        assert!(!dir.join("file.txt").exists());
+    }
+
+    #[test]
+    fn reserved_slots_count_against_the_limit_until_released() {
+        let uploads = Uploads::default();
+        assert!(uploads.reserve(1, 2));
+        assert!(uploads.reserve(2, 2));
+        assert!(!uploads.reserve(3, 2));
+        assert!(uploads.get(1).is_none());
+
+        uploads.release(1);
+        assert!(uploads.reserve(3, 2));
+    }
+}
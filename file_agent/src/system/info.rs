@@ -1,4 +1,5 @@
 use chrono::DateTime;
+#[cfg(windows)]
 use log::debug;
 use serde_json::{json, Value};
 use std::process::Command;
@@ -136,7 +137,7 @@ pub fn get_agent_details() -> Value {
     // CPU information
     let cpu = sys
         .cpus()
-        .get(0)
+        .first()
         .map(|c| c.brand().to_string())
         .unwrap_or_else(|| "Unknown CPU".to_string());
 
@@ -240,25 +241,16 @@ pub fn get_agent_details() -> Value {
 }
 
 pub fn get_installed_software() -> Value {
-    let mut system_software = Vec::new();
-    let mut user_software = Vec::new();
-
     // For Windows, use PowerShell to get installed software
     #[cfg(windows)]
-    {
-        let (sys_soft, usr_soft) = get_windows_installed_software();
-        system_software = sys_soft;
-        user_software = usr_soft;
-    }
+    let (system_software, user_software) = get_windows_installed_software();
 
     // For Unix-like systems, try different package managers
+    // You would need to implement similar categorization for Unix systems;
+    // for now, treat all Unix software as system-wide
     #[cfg(not(windows))]
-    {
-        // You would need to implement similar categorization for Unix systems
-        let all_software = get_unix_installed_software();
-        // For now, treat all Unix software as system-wide
-        system_software = all_software;
-    }
+    let (system_software, user_software): (Vec<Value>, Vec<Value>) =
+        (get_unix_installed_software(), Vec::new());
 
     json!({
         "type": "installed_software",
@@ -1,3 +1,6 @@
+#[cfg(test)]
+pub(crate) mod test_dir;
+
 pub fn clean_text_for_transmission(text: &str) -> String {
     text.chars()
         .filter(|c| c.is_ascii() && (*c as u8) >= 32 || *c == '\n' || *c == '\r' || *c == '\t')
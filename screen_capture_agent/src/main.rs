@@ -6,8 +6,55 @@ mod codec;
 mod platform;
 
 mod video_encoder;
+use rust_c1rmm_agent::config::{CliArgs, Config as AgentConfig};
+use rust_c1rmm_agent::network::identity::generate_identity;
+
 #[tokio::main]
 async fn main() {
+    let args = match CliArgs::from_env_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, CliArgs::usage());
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", CliArgs::usage());
+        return;
+    }
+
+    let config = match AgentConfig::load_layered(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.print_config {
+        match config.to_redacted_toml() {
+            Ok(dump) => print!("{}", dump),
+            Err(e) => eprintln!("{}", e),
+        }
+        return;
+    }
+    if args.generate_identity || args.rotate_identity {
+        match generate_identity(&config, args.rotate_identity) {
+            Ok(identity) => {
+                for backup in &identity.backups {
+                    println!("Previous identity saved as {}", backup);
+                }
+                println!("Certificate: {}", identity.cert_path);
+                println!("Private key: {}", identity.key_path);
+                println!("SHA-256 fingerprint: {}", identity.fingerprint);
+            }
+            Err(e) => {
+                eprintln!("Failed to generate identity: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Initialize logger - control with RUST_LOG environment variable
     // Examples:
     //   RUST_LOG=off          - No logging (maximum performance)
@@ -41,7 +88,7 @@ async fn main() {
         );
     }
 
-    if let Err(e) = connect_and_stream_default().await {
+    if let Err(e) = connect_and_stream_default(config).await {
         log::error!("Application Error: {:?}", e);
     }
 }
@@ -4,6 +4,7 @@ use anyhow::Result;
 use flume::Sender;
 use futures_util::stream::SplitSink;
 use futures_util::SinkExt;
+use rust_c1rmm_agent::network::capabilities::Negotiation;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
@@ -14,10 +15,15 @@ static FRAME_RATE_TIMER_TX: once_cell::sync::OnceCell<tokio::sync::mpsc::Sender<
     once_cell::sync::OnceCell::new();
 pub struct MessageHandler {
     pub client_state: Arc<ClientState>,
+    /// Features agreed in the file agent's `hello` exchange
+    negotiation: Negotiation,
 }
 impl MessageHandler {
-    pub fn new(client_state: Arc<ClientState>) -> Self {
-        Self { client_state }
+    pub fn new(client_state: Arc<ClientState>, negotiation: Negotiation) -> Self {
+        Self {
+            client_state,
+            negotiation,
+        }
     }
     pub async fn handle_message(
         &self,
@@ -156,14 +162,8 @@ impl MessageHandler {
             })?;
         println!(" Sent refresh packet (cmd=6) to client");
         println!(" Touch injection not supported - reporting failure to client");
-        let mut touch_response = Vec::new();
-        touch_response.push(MNG_KVM_INIT_TOUCH);
-        touch_response.push(0);
-        touch_response.push(0);
-        touch_response.push(4);
-        touch_response.push(2); // 2 = failure
         sender
-            .send(Message::Binary(touch_response))
+            .send(Message::Binary(create_touch_failure_packet()))
             .await
             .map_err(|e| {
                 println!(" Failed to send auto touch init response: {}", e);
@@ -188,6 +188,25 @@ impl MessageHandler {
         ws_sender: &Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>,
     ) -> Result<()> {
         let input_type = data[1];
+        if let Some(feature) = required_feature(input_type) {
+            // Input frames carry no session, so only what is offered applies
+            if !self.negotiation.allows(None, feature) {
+                println!(
+                    " Ignoring message type {}: '{}' was not negotiated",
+                    input_type, feature
+                );
+                if input_type == MNG_KVM_INIT_TOUCH {
+                    let mut sender = ws_sender.lock().await;
+                    if let Err(e) = sender
+                        .send(Message::Binary(create_touch_failure_packet()))
+                        .await
+                    {
+                        println!(" Failed to send touch init response: {}", e);
+                    }
+                }
+                return Ok(());
+            }
+        }
         match input_type {
             2 => {
                 if data.len() >= 10 {
@@ -381,29 +400,6 @@ impl MessageHandler {
                     self.client_state.set_cursor_visible(cursor_enabled);
                 }
             }
-            MNG_KVM_INIT_TOUCH => {
-                println!(" Received touch init command (type 14) - feature disabled");
-                let mut response = Vec::new();
-                response.push(MNG_KVM_INIT_TOUCH);
-                response.push(0);
-                response.push(0);
-                response.push(4);
-                response.push(2); // 2 = failure / unsupported
-                let mut sender = ws_sender.lock().await;
-                if let Err(e) = sender.send(Message::Binary(response)).await {
-                    println!(" Failed to send touch init response: {}", e);
-                } else {
-                    println!(" Touch init response sent with failure status");
-                }
-            }
-            MNG_UPDATE_TEMP_WALLPAPER => {
-                println!("Received temp wallpaper command (type 73) - wallpaper feature disabled");
-            }
-            MNG_RESTORE_ORIGINAL_WALLPAPER => {
-                println!(
-                    "Received restore wallpaper command (type 74) - wallpaper feature disabled"
-                );
-            }
             144 => {
                 if data.len() >= 5 {
                     let cursor_enabled = data[4] != 0;
@@ -424,6 +420,18 @@ impl MessageHandler {
     }
 }
 
+/// The feature a client message needs to have been negotiated
+fn required_feature(input_type: u8) -> Option<&'static str> {
+    match input_type {
+        1 | 2 | 10 | 85 => Some(FEATURE_INPUT),
+        MNG_KVM_INIT_TOUCH => Some(FEATURE_TOUCH),
+        MNG_UPDATE_TEMP_WALLPAPER | MNG_RESTORE_ORIGINAL_WALLPAPER => Some(FEATURE_WALLPAPER),
+        _ => None,
+    }
+}
+fn create_touch_failure_packet() -> Vec<u8> {
+    vec![MNG_KVM_INIT_TOUCH, 0, 0, 4, 2] // 2 = failure / unsupported
+}
 pub fn create_keystate_packet() -> Vec<u8> {
     let mut packet = Vec::new();
     packet.extend_from_slice(&(MNG_KVM_KEYSTATE as u16).to_be_bytes());
@@ -1,6 +1,5 @@
 pub mod blocking_capture_thread;
 pub mod client_state;
-pub mod config;
 pub mod input_processor;
 pub mod keyboard;
 pub mod message_handler;
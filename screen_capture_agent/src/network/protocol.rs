@@ -15,6 +15,13 @@ pub const MNG_KVM_MOUSE_CURSOR: u8 = 88;
 pub const MNG_KVM_CURSOR_CONTROL: u8 = 144;
 pub const MNG_UPDATE_TEMP_WALLPAPER: u8 = 73;
 pub const MNG_RESTORE_ORIGINAL_WALLPAPER: u8 = 74;
+/// Screen features offered in `hello_result`, next to the file protocol's
+pub const FEATURE_SCREEN: &str = "screen";
+pub const FEATURE_INPUT: &str = "input";
+/// Not implemented, so never offered
+pub const FEATURE_TOUCH: &str = "touch";
+/// Not implemented, so never offered
+pub const FEATURE_WALLPAPER: &str = "wallpaper";
 pub fn create_resolution_packet_with_scaling(client_state: &ClientState) -> Vec<u8> {
     let mut packet = Vec::new();
     let (raw_width, raw_height) = get_raw_screen_resolution();
@@ -2,35 +2,35 @@ use super::client_state::ClientState;
 use super::message_handler::MessageHandler;
 use super::protocol::{
     create_agent_connected_packet, create_display_list_packet_with_scaling,
-    create_resolution_packet_with_scaling, create_video_frame_packet,
+    create_resolution_packet_with_scaling, create_video_frame_packet, FEATURE_INPUT,
+    FEATURE_SCREEN,
 };
 use crate::network::blocking_capture_thread::CaptureThreadHandle;
 use crate::network::input_processor::InputProcessor;
 use crate::network::protocol::get_codec_type_id;
 use crate::qos::VideoQoS;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use futures_util::{SinkExt, StreamExt};
-use native_tls::TlsConnector as NativeTlsConnector;
 use rust_c1rmm_agent::config::Config as AgentConfig;
+use rust_c1rmm_agent::network::capabilities::Capabilities;
 use rust_c1rmm_agent::network::handlers::MessageHandler as FileMessageHandler;
-use rust_c1rmm_agent::network::proxy::ProxyConnector;
+use rust_c1rmm_agent::network::heartbeat::{self, Heartbeat};
+use rust_c1rmm_agent::network::outbound::{Outbound, OUTBOUND_CAPACITY};
+use rust_c1rmm_agent::network::reconnect::{Backoff, ConnectionState};
+use rust_c1rmm_agent::network::transport::{self, WsStream};
 #[cfg(target_os = "windows")]
 use scrap::dxgi::gdi;
 use scrap::CodecFormat;
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
-use tokio_native_tls::TlsConnector as TokioTlsConnector;
+use tokio::sync::{watch, Mutex};
 use tokio_tungstenite::tungstenite::protocol::Message;
-use tokio_tungstenite::{
-    connect_async, connect_async_tls_with_config, tungstenite::client::IntoClientRequest,
-    Connector, MaybeTlsStream, WebSocketStream,
-};
 use url::Url;
 
 const AGENT_TYPE: &str = "c_agent";
+/// QoS user slot fed with the relay link round trip measured by the heartbeat
+const RELAY_QOS_USER: i32 = 0;
 
 pub struct WebSocketClient {
     config: AgentConfig,
@@ -41,10 +41,17 @@ pub struct WebSocketClient {
     connection_stable: Arc<AtomicBool>,
     consecutive_errors: Arc<AtomicU32>,
     last_keyframe_time: Arc<Mutex<Instant>>,
+    backoff: Backoff,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    /// Taken when the heartbeat is built, since the connection holds its lock
+    rtt: watch::Receiver<Option<Duration>>,
 }
 
 impl WebSocketClient {
     pub fn new(config: AgentConfig) -> Self {
+        let backoff = Backoff::new(config.reconnect.clone());
+        let heartbeat = Heartbeat::new(config.heartbeat.clone());
+        let rtt = heartbeat.subscribe();
         Self {
             config,
             running: false,
@@ -55,18 +62,45 @@ impl WebSocketClient {
             connection_stable: Arc::new(AtomicBool::new(false)),
             consecutive_errors: Arc::new(AtomicU32::new(0)),
             last_keyframe_time: Arc::new(Mutex::new(Instant::now())),
+            backoff,
+            heartbeat: Arc::new(Mutex::new(heartbeat)),
+            rtt,
         }
     }
 
+    /// Observe the relay connection state as it changes
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.backoff.subscribe()
+    }
+
+    /// Round-trip time to the relay measured by the most recent heartbeat
+    pub fn round_trip_time(&self) -> watch::Receiver<Option<Duration>> {
+        self.rtt.clone()
+    }
+
     pub fn with_codec(mut self, codec: CodecFormat) -> Self {
         self.video_codec = codec;
         self
     }
 
-    pub async fn connect_and_stream(&mut self) -> Result<()> {
-        let mut retry_count: usize = 0;
+    /// What this agent offers in `hello_result`: the file protocol plus
+    /// screen streaming and input, with the configured codec preferred
+    fn capabilities(&self) -> Capabilities {
+        let codecs = match self.video_codec {
+            CodecFormat::VP9 => ["vp9", "vp8"],
+            _ => ["vp8", "vp9"],
+        };
+        let file = Capabilities::file_agent(&self.config.features);
+        Capabilities::new("screen_capture_agent", env!("CARGO_PKG_VERSION"))
+            .with_features(file.features.iter().map(String::as_str))
+            .with_features([FEATURE_SCREEN, FEATURE_INPUT])
+            .with_codecs(codecs)
+            .with_pixel_formats(["i420"])
+    }
 
+    pub async fn connect_and_stream(&mut self) -> Result<()> {
         loop {
+            self.backoff.on_connecting();
             match self.try_connect_and_stream().await {
                 Ok(_) => {
                     self.connection_stable.store(true, Ordering::Relaxed);
@@ -74,26 +108,29 @@ impl WebSocketClient {
 
                     if should_shutdown() {
                         println!("Shutdown requested, stopping agent loop");
+                        self.backoff.on_stopped();
                         return Ok(());
                     }
 
-                    retry_count = 0;
-                    println!("Connection ended, attempting to reconnect...");
+                    let delay = self.backoff.next_delay();
+                    println!(
+                        "Connection ended, reconnecting in {:.1} seconds...",
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
                 }
                 Err(e) => {
-                    retry_count += 1;
                     self.connection_stable.store(false, Ordering::Relaxed);
                     self.consecutive_errors.fetch_add(1, Ordering::Relaxed);
 
-                    let backoff_duration =
-                        Duration::from_secs(std::cmp::min(retry_count.max(1) as u64 * 2, 30));
+                    let delay = self.backoff.next_delay();
                     println!(
-                        "Connection error (attempt {}): {}. Retrying in {} seconds...",
-                        retry_count,
+                        "Connection error (attempt {}): {}. Retrying in {:.1} seconds...",
+                        self.backoff.attempt(),
                         e,
-                        backoff_duration.as_secs()
+                        delay.as_secs_f64()
                     );
-                    sleep(backoff_duration).await;
+                    tokio::time::sleep(delay).await;
                 }
             }
         }
@@ -105,126 +142,19 @@ impl WebSocketClient {
 
         let url = Url::parse(&url).context("Invalid WebSocket URL")?;
         let ws_stream = self.establish_connection(&url).await?;
+        self.backoff.on_connected();
         self.handle_connected_stream(ws_stream).await
     }
 
-    async fn establish_connection(
-        &self,
-        url: &Url,
-    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>> {
-        if self.config.use_proxy {
-            self.connect_through_proxy(url).await
-        } else {
-            self.connect_direct(url).await
-        }
-    }
-
-    async fn connect_direct(
-        &self,
-        url: &Url,
-    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>> {
-        if self.config.use_ssl {
-            let req = url.clone().into_client_request()?;
-            let tls_connector = NativeTlsConnector::builder()
-                .danger_accept_invalid_certs(true)
-                .danger_accept_invalid_hostnames(true)
-                .build()
-                .context("failed to build TLS connector")?;
-            let connector = Connector::NativeTls(tls_connector);
-            let (ws_stream, response) =
-                connect_async_tls_with_config(req, None, false, Some(connector))
-                    .await
-                    .context("TLS WebSocket handshake failed")?;
-            println!(
-                "Secure WebSocket handshake successful! Response: {:?}",
-                response.status()
-            );
-            Ok(ws_stream)
-        } else {
-            let (ws_stream, response) = connect_async(url.clone())
-                .await
-                .context("WebSocket handshake failed")?;
-            println!(
-                "WebSocket handshake successful! Response: {:?}",
-                response.status()
-            );
-            Ok(ws_stream)
-        }
-    }
-
-    async fn connect_through_proxy(
-        &self,
-        url: &Url,
-    ) -> Result<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>> {
-        let host = url.host_str().unwrap_or("localhost").to_string();
-        let port =
-            url.port_or_known_default()
-                .unwrap_or_else(|| if self.config.use_ssl { 443 } else { 80 });
-
-        let proxy_port: u16 = self
-            .config
-            .proxy_port
-            .as_ref()
-            .ok_or_else(|| anyhow!("Proxy port not configured"))?
-            .parse()
-            .context("Invalid proxy port")?;
-
-        let proxy_host = self
-            .config
-            .proxy_url
-            .clone()
-            .ok_or_else(|| anyhow!("Proxy host not configured"))?;
-
-        let proxy_connector = ProxyConnector::new(
-            proxy_host,
-            proxy_port,
-            host.clone(),
-            port,
-            self.config.proxy_auth.clone(),
-            self.config.no_auth,
-        );
-
-        let std_stream = proxy_connector
-            .connect()
-            .map_err(|e| anyhow!(e.to_string()))
-            .context("Failed to establish proxy tunnel")?;
-
-        std_stream
-            .set_nonblocking(true)
-            .context("Failed to configure proxy stream")?;
-
-        let tokio_stream =
-            tokio::net::TcpStream::from_std(std_stream).context("Failed to wrap proxy stream")?;
-
-        let stream = if self.config.use_ssl {
-            let mut builder = native_tls::TlsConnector::builder();
-            builder.danger_accept_invalid_certs(true);
-            builder.danger_accept_invalid_hostnames(true);
-            let tls_connector = builder
-                .build()
-                .context("Failed to build native TLS for proxy")?;
-            let tls_connector = TokioTlsConnector::from(tls_connector);
-            let tls_stream = tls_connector
-                .connect(&host, tokio_stream)
-                .await
-                .context("TLS handshake over proxy failed")?;
-            MaybeTlsStream::NativeTls(tls_stream)
-        } else {
-            MaybeTlsStream::Plain(tokio_stream)
-        };
-
-        let (ws_stream, _) = tokio_tungstenite::client_async(url, stream)
+    async fn establish_connection(&self, url: &Url) -> Result<WsStream> {
+        let ws_stream = transport::connect(&self.config, url)
             .await
-            .context("WebSocket handshake through proxy failed")?;
-
-        println!("WebSocket handshake through proxy successful!");
+            .context("Failed to establish relay connection")?;
+        println!("WebSocket connection to relay established");
         Ok(ws_stream)
     }
 
-    async fn handle_connected_stream(
-        &mut self,
-        ws_stream: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-    ) -> Result<()> {
+    async fn handle_connected_stream(&mut self, ws_stream: WsStream) -> Result<()> {
         let (mut write, read) = ws_stream.split();
         println!("WebSocket connection established - waiting for client connections");
         let client_state = Arc::new(ClientState::new());
@@ -233,8 +163,12 @@ impl WebSocketClient {
             gdi::set_client_state_provider(client_state.clone());
         }
 
-        let screen_message_handler = MessageHandler::new(client_state.clone());
-        let file_message_handler = FileMessageHandler::new();
+        let file_message_handler =
+            FileMessageHandler::with_capabilities(&self.config, self.capabilities());
+        let screen_message_handler = MessageHandler::new(
+            client_state.clone(),
+            file_message_handler.negotiation().clone(),
+        );
         let (handshake_tx, _handshake_rx) = flume::unbounded::<bool>();
 
         println!(
@@ -263,9 +197,24 @@ impl WebSocketClient {
         let write_half_for_messages = Arc::clone(&write_half);
         let write_half_for_video = Arc::clone(&write_half);
 
+        // File agent replies are queued and written one at a time, so a slow
+        // request never holds the socket while video frames wait
+        let (file_replies, mut file_reply_rx) = Outbound::channel(OUTBOUND_CAPACITY);
+        let write_half_for_replies = Arc::clone(&write_half);
+        let reply_task = tokio::spawn(async move {
+            while let Some(reply) = file_reply_rx.recv().await {
+                let mut writer = write_half_for_replies.lock().await;
+                if let Err(e) = writer.send(reply).await {
+                    println!("Error sending file-agent reply: {}", e);
+                    break;
+                }
+            }
+        });
+
         let (video_tx, mut video_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(30);
 
         let video_qos = Arc::new(std::sync::Mutex::new(VideoQoS::new()));
+        video_qos.lock().unwrap().on_connection_open(RELAY_QOS_USER);
         let (capture_thread, frame_receiver) =
             CaptureThreadHandle::spawn(self.video_codec, video_qos.clone());
 
@@ -275,14 +224,41 @@ impl WebSocketClient {
         let file_handler_for_messages = file_message_handler.clone();
         let screen_handler = screen_message_handler;
         let handshake_sender = handshake_tx.clone();
+        let heartbeat = Arc::clone(&self.heartbeat);
+        let video_qos_for_messages = video_qos.clone();
 
         let message_task = tokio::spawn(async move {
             let mut read_stream = read;
-            while let Some(msg) = read_stream.next().await {
+            let mut heartbeat = heartbeat.lock().await;
+            heartbeat.reset();
+            loop {
+                let msg = tokio::select! {
+                    msg = read_stream.next() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = heartbeat::sleep_until(heartbeat.deadline()) => {
+                        match heartbeat.on_deadline() {
+                            Ok(Some(ping)) => {
+                                let mut writer = writer_for_messages.lock().await;
+                                if let Err(e) = writer.send(ping).await {
+                                    println!("Error sending heartbeat ping: {}", e);
+                                    break;
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                println!("{} - reconnecting", e);
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                };
                 match msg {
                     Ok(Message::Binary(data)) => {
                         if let Err(e) = file_handler_for_messages
-                            .handle_binary_message(&data, &writer_for_messages)
+                            .handle_binary_message(&data, &file_replies)
                             .await
                         {
                             println!("Error handling file-agent binary message: {}", e);
@@ -297,7 +273,7 @@ impl WebSocketClient {
                     }
                     Ok(Message::Text(text)) => {
                         if let Err(e) = file_handler_for_messages
-                            .handle_text_message(&text, &writer_for_messages)
+                            .handle_text_message(&text, &file_replies)
                             .await
                         {
                             println!("Error handling file-agent text message: {}", e);
@@ -310,7 +286,14 @@ impl WebSocketClient {
                             break;
                         }
                     }
-                    Ok(Message::Pong(_)) => {}
+                    Ok(Message::Pong(data)) => {
+                        if let Some(rtt) = heartbeat.on_pong(&data) {
+                            video_qos_for_messages
+                                .lock()
+                                .unwrap()
+                                .user_network_delay(RELAY_QOS_USER, rtt.as_millis() as u32);
+                        }
+                    }
                     Ok(Message::Close(_)) => {
                         println!("Connection closed by remote");
                         break;
@@ -467,6 +450,11 @@ impl WebSocketClient {
                 break;
             }
 
+            if message_task.is_finished() {
+                println!("Relay connection lost");
+                break;
+            }
+
             if last_check.elapsed().as_secs() >= 10 {
                 println!("Connection alive, {} active clients", clients);
                 last_check = Instant::now();
@@ -488,24 +476,26 @@ impl WebSocketClient {
         );
         message_task.abort();
         video_send_task.abort();
+        file_message_handler.cancel_all();
+        reply_task.abort();
         self.running = false;
         Ok(())
     }
 }
 
-pub async fn connect_and_stream_with_encoder(codec: CodecFormat) -> Result<()> {
-    let config = AgentConfig::load_from_file("config.txt")
-        .map_err(|e| anyhow!(e.to_string()))
-        .context("Failed to load configuration")?;
+pub async fn connect_and_stream_with_encoder(
+    config: AgentConfig,
+    codec: CodecFormat,
+) -> Result<()> {
     let mut client = WebSocketClient::new(config).with_codec(codec);
     client.connect_and_stream().await
 }
 
-pub async fn connect_and_stream_default() -> Result<()> {
+pub async fn connect_and_stream_default(config: AgentConfig) -> Result<()> {
     // CRITICAL FIX: Use VP8 instead of VP9 for 3-5x better performance
     // VP8 with cpuused=12 achieves 30fps on 4-core systems
     // VP9 with cpuused=7 only achieves 10-15fps on same hardware
-    connect_and_stream_with_encoder(CodecFormat::VP8).await
+    connect_and_stream_with_encoder(config, CodecFormat::VP8).await
 }
 
 pub static SHUTDOWN_FLAG: AtomicBool = AtomicBool::new(false);